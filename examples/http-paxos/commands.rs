@@ -1,7 +1,11 @@
-use bincode::{deserialize, serialize};
+use crate::service::Entry;
 use bytes::Bytes;
-use hyper::{client::HttpConnector, Body, Client, Request};
+use hyper::{client::HttpConnector, header::HeaderValue, Body, Client, Request};
 use paxos::{Command, CommandMetas, NodeId, NodeMetadata, Receiver, Transport};
+use std::error::Error;
+
+/// Header carrying the `CommandMetas` of a command as JSON
+pub const METAS_HEADER: &str = "X-Paxos-Metas";
 
 pub struct HttpTransport {
     client: Client<HttpConnector, Body>,
@@ -13,9 +17,16 @@ impl Default for HttpTransport {
     }
 }
 
-impl Transport for HttpTransport {
-    fn send(&mut self, _node: NodeId, meta: &NodeMetadata, cmd: Command, _cmd_metas: CommandMetas) {
-        let bytes = match serialize(&cmd) {
+impl Transport<Entry> for HttpTransport {
+    fn send(
+        &mut self,
+        _node: NodeId,
+        meta: &NodeMetadata,
+        cmd: Command<Entry>,
+        cmd_metas: CommandMetas,
+    ) {
+        // `Command` is internally tagged, which bincode cannot deserialize
+        let bytes = match serde_json::to_vec(&cmd) {
             Ok(bytes) => bytes,
             Err(e) => {
                 error!("Error serializing command: {:?}", e);
@@ -23,16 +34,27 @@ impl Transport for HttpTransport {
             }
         };
 
-        let request =
-            Request::builder().method("POST").uri(meta.0.as_ref()).body(bytes.into()).unwrap();
+        let request = Request::builder()
+            .method("POST")
+            .uri(meta.0.as_ref())
+            .header(METAS_HEADER, serde_json::to_string(&cmd_metas).unwrap())
+            .body(bytes.into())
+            .unwrap();
         tokio::spawn(self.client.request(request));
     }
 }
 
-pub fn invoke<C: Receiver>(replica: &mut C, command: Bytes) {
-    let cmd = match deserialize(&command) {
-        Ok(cmd) => cmd,
-        Err(_) => return,
-    };
-    replica.receive(cmd, CommandMetas("".into()));
+/// Hands a command sent by a peer to the replica. Commands that cannot be
+/// parsed fail like those the replica refuses.
+pub fn invoke<C: Receiver<Entry>>(
+    replica: &mut C,
+    command: Bytes,
+    metas: Option<&HeaderValue>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let cmd = serde_json::from_slice(&command)?;
+    let cmd_metas = metas
+        .and_then(|metas| serde_json::from_slice(metas.as_bytes()).ok())
+        .unwrap_or_default();
+    replica.receive(cmd, cmd_metas)?;
+    Ok(())
 }
@@ -0,0 +1,417 @@
+//! Linearizability checking of client histories against the key-value store.
+//!
+//! Clients record the invocation and response of each GET and POST in a
+//! shared `History`. Each key of the store behaves as a register, and
+//! linearizability is compositional, so the history of every key is checked
+//! on its own with the Wing & Gong search: repeatedly pick an operation that
+//! was invoked before every remaining operation returned, apply it to the
+//! register and backtrack when its result disagrees with the register. As in
+//! Lowe's refinement of the search, combinations of linearized operations
+//! and register values that have already been reached are not explored again.
+//!
+//! Operations that never returned may or may not have taken effect. A write
+//! without a response can be linearized at any point after its invocation,
+//! or not at all, and a read without a response is ignored.
+use crate::service::{Entry, Handler, LEADER_HEADER};
+use bytes::Bytes;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use paxos::{Command, CommandMetas, Configuration, NodeId, NodeMetadata, Transport};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::timeout;
+
+/// Request made by a client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    Get,
+    Set(Bytes),
+}
+
+/// Response received by a client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Return {
+    Get(Option<Bytes>),
+    Set,
+}
+
+/// Request against a single key, along with its response if one arrived
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub client: usize,
+    pub key: Bytes,
+    pub call: Call,
+    pub ret: Option<Return>,
+    /// Logical time of the invocation
+    pub invoked_at: u64,
+    /// Logical time of the response
+    pub returned_at: Option<u64>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "client {} ", self.client)?;
+        match (&self.call, &self.ret) {
+            (Call::Get, Some(Return::Get(val))) => write!(fmt, "get {:?} -> {:?}", self.key, val)?,
+            (Call::Set(val), _) => write!(fmt, "set {:?} = {:?}", self.key, val)?,
+            (Call::Get, _) => write!(fmt, "get {:?}", self.key)?,
+        }
+        match self.returned_at {
+            Some(returned_at) => write!(fmt, " [{}, {}]", self.invoked_at, returned_at),
+            None => write!(fmt, " [{}, ?]", self.invoked_at),
+        }
+    }
+}
+
+/// Concurrent history shared by the clients. Invocations and responses are
+/// timestamped from a single counter, so the order of the timestamps is the
+/// real-time order of the events.
+#[derive(Clone, Default)]
+pub struct History {
+    inner: Arc<Mutex<(u64, Vec<Operation>)>>,
+}
+
+impl History {
+    /// Records the invocation of a request, returning its index
+    pub fn invoke(&self, client: usize, key: Bytes, call: Call) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let (clock, ops) = &mut *inner;
+        *clock += 1;
+        ops.push(Operation { client, key, call, ret: None, invoked_at: *clock, returned_at: None });
+        ops.len() - 1
+    }
+
+    /// Records the response of a request
+    pub fn complete(&self, op: usize, ret: Return) {
+        let mut inner = self.inner.lock().unwrap();
+        let (clock, ops) = &mut *inner;
+        *clock += 1;
+        ops[op].ret = Some(ret);
+        ops[op].returned_at = Some(*clock);
+    }
+
+    pub fn operations(&self) -> Vec<Operation> {
+        self.inner.lock().unwrap().1.clone()
+    }
+}
+
+/// History of a key that has no linearization
+#[derive(Debug)]
+pub struct Violation {
+    pub key: Bytes,
+    /// Longest sequence of operations that could be linearized
+    pub linearized: Vec<Operation>,
+    /// Operations that could have been linearized next, none of which agree
+    /// with the register after `linearized`
+    pub candidates: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "history of key {:?} is not linearizable", self.key)?;
+        writeln!(fmt, "longest linearization:")?;
+        for op in &self.linearized {
+            writeln!(fmt, "    {}", op)?;
+        }
+        writeln!(fmt, "cannot be followed by any of:")?;
+        for op in &self.candidates {
+            writeln!(fmt, "    {}", op)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the history of every key is linearizable
+pub fn check(ops: &[Operation]) -> Result<(), Violation> {
+    let mut keys: BTreeMap<Bytes, Vec<Operation>> = BTreeMap::new();
+    for op in ops {
+        // a read that never returned observed nothing
+        if op.call == Call::Get && op.ret.is_none() {
+            continue;
+        }
+        keys.entry(op.key.clone()).or_default().push(op.clone());
+    }
+
+    for (key, ops) in keys {
+        Search::new(&ops).run().map_err(|(linearized, candidates)| Violation {
+            key,
+            linearized: linearized.into_iter().map(|i| ops[i].clone()).collect(),
+            candidates: candidates.into_iter().map(|i| ops[i].clone()).collect(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Search for a linearization of the operations on a single key
+struct Search<'a> {
+    ops: &'a [Operation],
+    linearized: Vec<bool>,
+    order: Vec<usize>,
+    seen: HashSet<(Vec<bool>, Option<Bytes>)>,
+    longest: Vec<usize>,
+    longest_candidates: Vec<usize>,
+}
+
+impl<'a> Search<'a> {
+    fn new(ops: &'a [Operation]) -> Search<'a> {
+        Search {
+            ops,
+            linearized: vec![false; ops.len()],
+            order: Vec::new(),
+            seen: HashSet::new(),
+            longest: Vec::new(),
+            longest_candidates: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<(), (Vec<usize>, Vec<usize>)> {
+        if self.explore(None) {
+            Ok(())
+        } else {
+            Err((self.longest, self.longest_candidates))
+        }
+    }
+
+    fn explore(&mut self, register: Option<Bytes>) -> bool {
+        let remaining = (0..self.ops.len()).filter(|i| !self.linearized[*i]);
+
+        // every operation that returned must take effect before the
+        // earliest remaining response, so only those invoked earlier
+        // can be linearized next
+        let deadline = remaining.clone().filter_map(|i| self.ops[i].returned_at).min();
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            // writes that never returned need not take effect
+            None => return true,
+        };
+        let candidates =
+            remaining.filter(|i| self.ops[*i].invoked_at < deadline).collect::<Vec<_>>();
+
+        if self.order.len() >= self.longest.len() {
+            self.longest = self.order.clone();
+            self.longest_candidates = candidates.clone();
+        }
+
+        for i in candidates {
+            let next = match (&self.ops[i].call, &self.ops[i].ret) {
+                (Call::Set(val), _) => Some(val.clone()),
+                (Call::Get, Some(Return::Get(val))) if *val == register => register.clone(),
+                _ => continue,
+            };
+
+            self.linearized[i] = true;
+            if self.seen.insert((self.linearized.clone(), next.clone())) {
+                self.order.push(i);
+                if self.explore(next) {
+                    return true;
+                }
+                self.order.pop();
+            }
+            self.linearized[i] = false;
+        }
+        false
+    }
+}
+
+/// Transport that delivers commands to handlers within the same process
+/// through the same `/paxos` endpoint used by the HTTP transport.
+#[derive(Clone, Default)]
+struct LocalTransport {
+    peers: Arc<Mutex<HashMap<NodeId, Handler<LocalTransport>>>>,
+}
+
+impl Transport<Entry> for LocalTransport {
+    fn send(
+        &mut self,
+        node: NodeId,
+        _meta: &NodeMetadata,
+        cmd: Command<Entry>,
+        _cmd_metas: CommandMetas,
+    ) {
+        let handler = match self.peers.lock().unwrap().get(&node) {
+            Some(handler) => handler.clone(),
+            None => return,
+        };
+        let bytes = serde_json::to_vec(&cmd).unwrap();
+        tokio::spawn(async move { handler.handle(paxos_request(bytes.into())).await });
+    }
+}
+
+fn paxos_request(body: Bytes) -> Request<Body> {
+    Request::builder().method(Method::POST).uri("/paxos").body(body.into()).unwrap()
+}
+
+fn cluster(nodes: u32) -> Vec<Handler<LocalTransport>> {
+    let transport = LocalTransport::default();
+    let handlers = (0..nodes)
+        .map(|node| {
+            let config = Configuration::new(
+                node,
+                (0..nodes).filter(|n| *n != node).map(|n| (n, NodeMetadata::default())),
+            );
+            let handler = Handler::with_transport(transport.clone(), config);
+            tokio::spawn(handler.clone().run_ticks());
+            handler
+        })
+        .collect::<Vec<_>>();
+    transport.peers.lock().unwrap().extend((0..nodes).zip(handlers.iter().cloned()));
+    handlers
+}
+
+/// Time after which a client gives up on a request. The test runs on a paused
+/// clock, which only advances once every node is idle, so a request only times
+/// out when it will never be answered.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Reads a key, following the redirects of followers to the leader
+async fn get(handlers: &[Handler<LocalTransport>], mut node: usize, uri: &str) -> Response<Body> {
+    let mut res = Response::default();
+    for _ in 0..handlers.len() {
+        let req = Request::builder().method(Method::GET).uri(uri).body(Body::empty()).unwrap();
+        res = handlers[node].handle(req).await.unwrap();
+        if res.status() != StatusCode::TEMPORARY_REDIRECT {
+            break;
+        }
+        node = res.headers()[LEADER_HEADER].to_str().unwrap().parse().unwrap();
+    }
+    res
+}
+
+/// Issues random requests to random nodes, one at a time
+async fn client(
+    client: usize,
+    seed: u64,
+    requests: usize,
+    handlers: Vec<Handler<LocalTransport>>,
+    history: History,
+) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for i in 0..requests {
+        let node = rng.gen_range(0..handlers.len());
+        let key = Bytes::from(if rng.gen() { "x" } else { "y" });
+        let uri = format!("/{}", String::from_utf8_lossy(&key));
+
+        if rng.gen_bool(0.5) {
+            let op = history.invoke(client, key, Call::Get);
+            // reads are answered by the leader, or refused while it changes
+            if let Ok(res) = timeout(REQUEST_TIMEOUT, get(&handlers, node, &uri)).await {
+                let ret = match res.status() {
+                    StatusCode::OK => Some(hyper::body::to_bytes(res.into_body()).await.unwrap()),
+                    StatusCode::NOT_FOUND => None,
+                    StatusCode::SERVICE_UNAVAILABLE | StatusCode::TEMPORARY_REDIRECT => continue,
+                    status => panic!("unexpected response {}", status),
+                };
+                history.complete(op, Return::Get(ret));
+            }
+        } else {
+            let value = Bytes::from(format!("{}-{}", client, i));
+            let op = history.invoke(client, key, Call::Set(value.clone()));
+            let req = Request::builder().method(Method::POST).uri(uri).body(value.into()).unwrap();
+            // proposals preempted by another leader are never answered, and
+            // those refused with 503 may still be decided
+            if let Ok(res) = timeout(REQUEST_TIMEOUT, handlers[node].handle(req)).await {
+                match res.unwrap().status() {
+                    StatusCode::NO_CONTENT => history.complete(op, Return::Set),
+                    StatusCode::SERVICE_UNAVAILABLE => {}
+                    status => panic!("unexpected response {}", status),
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn cluster_is_linearizable() {
+    let handlers = cluster(3);
+    let history = History::default();
+
+    let clients = (0..5)
+        .map(|i| tokio::spawn(client(i, i as u64, 20, handlers.clone(), history.clone())))
+        .collect::<Vec<_>>();
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    let ops = history.operations();
+    assert!(ops.iter().any(|op| op.ret.is_some()));
+    if let Err(violation) = check(&ops) {
+        panic!("{}", violation);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn followers_redirect_reads() {
+    let handlers = cluster(3);
+    let req = Request::builder().method(Method::POST).uri("/x").body("a".into()).unwrap();
+    let res = timeout(REQUEST_TIMEOUT, handlers[0].handle(req)).await.unwrap().unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, res.status());
+    let slot: u64 = res.headers()["X-Paxos-Slot"].to_str().unwrap().parse().unwrap();
+    tokio::time::sleep(REQUEST_TIMEOUT).await;
+
+    let req = Request::builder().method(Method::GET).uri("/x").body(Body::empty()).unwrap();
+    let res = timeout(REQUEST_TIMEOUT, handlers[1].handle(req)).await.unwrap().unwrap();
+    assert_eq!(StatusCode::TEMPORARY_REDIRECT, res.status());
+    assert_eq!("0", res.headers()[LEADER_HEADER]);
+
+    let res = timeout(REQUEST_TIMEOUT, get(&handlers, 1, "/x")).await.unwrap();
+    assert_eq!(StatusCode::OK, res.status());
+    // the read sees the write without deciding a slot of its own
+    assert_eq!((slot + 1).to_string(), res.headers()["X-Paxos-Slot"]);
+    assert_eq!("a", hyper::body::to_bytes(res.into_body()).await.unwrap());
+}
+
+#[tokio::test]
+async fn malformed_commands_are_refused() {
+    let handlers = cluster(3);
+    let res = handlers[0].handle(paxos_request("garbage".into())).await.unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, res.status());
+}
+
+fn op(call: Call, ret: Option<Return>, invoked_at: u64, returned_at: Option<u64>) -> Operation {
+    Operation { client: 0, key: "x".into(), call, ret, invoked_at, returned_at }
+}
+
+#[test]
+fn check_concurrent_writes() {
+    // either write may take effect first since they overlap
+    let ops = vec![
+        op(Call::Set("a".into()), Some(Return::Set), 1, Some(4)),
+        op(Call::Set("b".into()), Some(Return::Set), 2, Some(5)),
+        op(Call::Get, Some(Return::Get(Some("a".into()))), 6, Some(7)),
+    ];
+    assert!(check(&ops).is_ok());
+}
+
+#[test]
+fn check_stale_read() {
+    let ops = vec![
+        op(Call::Set("a".into()), Some(Return::Set), 1, Some(2)),
+        op(Call::Set("b".into()), Some(Return::Set), 3, Some(4)),
+        op(Call::Get, Some(Return::Get(Some("a".into()))), 5, Some(6)),
+    ];
+    let violation = check(&ops).unwrap_err();
+    assert_eq!(2, violation.linearized.len());
+    assert_eq!(1, violation.candidates.len());
+    assert_eq!(Call::Get, violation.candidates[0].call);
+}
+
+#[test]
+fn check_unanswered_write() {
+    // a write without a response may take effect at any later point
+    let ops = vec![
+        op(Call::Set("a".into()), None, 1, None),
+        op(Call::Get, Some(Return::Get(None)), 2, Some(3)),
+        op(Call::Get, Some(Return::Get(Some("a".into()))), 4, Some(5)),
+        op(Call::Get, None, 6, None),
+    ];
+    assert!(check(&ops).is_ok());
+
+    // but cannot be undone once observed
+    let mut ops = ops;
+    ops.push(op(Call::Get, Some(Return::Get(None)), 7, Some(8)));
+    assert!(check(&ops).is_err());
+}
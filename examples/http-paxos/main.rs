@@ -1,9 +1,10 @@
-extern crate bincode;
+#![cfg_attr(test, allow(dead_code))]
 extern crate bytes;
 extern crate env_logger;
 extern crate paxos;
 extern crate rand;
 extern crate serde;
+extern crate serde_json;
 #[macro_use]
 extern crate log;
 extern crate futures_util;
@@ -13,6 +14,8 @@ extern crate tokio;
 
 mod commands;
 mod kvstore;
+#[cfg(test)]
+mod linearizability;
 mod service;
 
 use hyper::{
@@ -23,7 +26,7 @@ use paxos::{Configuration, NodeId, NodeMetadata};
 use std::{env::args, net::SocketAddr, process::exit};
 
 fn config() -> paxos::Configuration {
-    let node_id_str = match args().skip(1).next() {
+    let node_id_str = match args().nth(1) {
         Some(node_id_str) => node_id_str,
         None => {
             error!("Must supply node ID as the first argument (0, 1, 2)");
@@ -31,7 +34,7 @@ fn config() -> paxos::Configuration {
         }
     };
 
-    let node_id = match u32::from_str_radix(&node_id_str, 10) {
+    let node_id = match node_id_str.parse::<u32>() {
         Ok(node_id) => node_id,
         Err(_) => {
             error!("Must supply integer node ID as the first argument (0, 1, 2)");
@@ -59,7 +62,7 @@ async fn main() {
     let conf = config();
     let addr: SocketAddr = format!("127.0.0.1:808{}", conf.current()).parse().unwrap();
     let handler = service::Handler::new(conf);
-    let timers = handler.spawn_timers();
+    tokio::spawn(handler.clone().run_ticks());
 
     let service = make_service_fn(move |_| {
         let handler = handler.clone();
@@ -73,7 +76,6 @@ async fn main() {
 
     let server = Server::bind(&addr).serve(service);
     if let Err(e) = server.await {
-        drop(timers);
         eprintln!("server error: {}", e);
     }
 }
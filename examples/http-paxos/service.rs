@@ -6,93 +6,167 @@ use crate::{
 use bytes::Bytes;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use paxos::{
-    statemachine::StateMachineReplica, Command, CommandMetas, Configuration, Node, Receiver,
-    Replica,
+    statemachine::{Consistency, PendingResponse, StateMachineReplica},
+    CommandMetas, Configuration, Node, NodeId, PaxosError, Proposed, Replica, Transport,
 };
 use rand::random;
 use std::{sync::Arc, time::Duration};
-use tokio::{self, sync::Mutex, task::JoinHandle, time::interval};
+use tokio::{self, sync::Mutex, time::interval};
 
-type PaxosReplica = StateMachineReplica<Node<HttpTransport>, KeyValueStore>;
+/// Period between heartbeats of the leader
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
 
-#[derive(Clone)]
-pub struct Handler {
-    replica: Arc<Mutex<PaxosReplica>>,
-    store: KeyValueStore,
+/// Header naming the leader that a read is redirected to
+pub const LEADER_HEADER: &str = "X-Paxos-Leader";
+
+/// Command decided by the replicas, along with the identity of its proposal
+pub type Entry = Proposed<KvCommand>;
+
+type PaxosReplica<T> = StateMachineReplica<Node<T, Entry>, KeyValueStore, Entry>;
+
+pub struct Handler<T: Transport<Entry> = HttpTransport> {
+    replica: Arc<Mutex<PaxosReplica<T>>>,
+}
+
+impl<T: Transport<Entry>> Clone for Handler<T> {
+    fn clone(&self) -> Handler<T> {
+        Handler { replica: self.replica.clone() }
+    }
 }
 
 impl Handler {
     pub fn new(config: Configuration) -> Handler {
-        let store = KeyValueStore::default();
-        let replica = Node::new(HttpTransport::default(), config).state_machine(store.clone());
-        Handler { replica: Arc::new(Mutex::new(replica)), store }
+        Handler::with_transport(HttpTransport::default(), config)
     }
+}
 
-    pub fn spawn_timers(&self) -> JoinHandle<()> {
-        let store = self.store.clone();
-        let listener_cleanup = tokio::spawn(async move {
-            let mut ticks = interval(Duration::new(30, 0));
-            loop {
-                ticks.tick().await;
-                store.prune_listeners();
+impl<T: Transport<Entry>> Handler<T> {
+    /// Handler sending Paxos commands to peers through a custom transport
+    pub fn with_transport(transport: T, config: Configuration) -> Handler<T> {
+        let replica = Node::new(transport, config).state_machine(KeyValueStore::default());
+        Handler { replica: Arc::new(Mutex::new(replica)) }
+    }
+
+    /// Sends heartbeats while the node leads, and runs for leadership while
+    /// proposals wait for a leader. Runs until the task is dropped.
+    pub async fn run_ticks(self) {
+        let mut ticks = interval(TICK_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let mut replica = self.replica.lock().await;
+            if replica.is_leader() || replica.queued_proposals() > 0 {
+                replica.propose_leadership(CommandMetas::default());
             }
-        });
+        }
+    }
 
-        listener_cleanup
+    async fn propose(
+        &self,
+        command: KvCommand,
+        cmd_metas: CommandMetas,
+    ) -> Result<PendingResponse<Option<Bytes>>, PaxosError> {
+        let res = self.replica.lock().await.propose(command, cmd_metas);
+        if let Err(e) = &res {
+            warn!("Refused proposal: {}", e);
+        }
+        res
+    }
+
+    /// Reads a key without deciding a slot. Replicas that do not lead refuse
+    /// the read, naming the leader when they know it.
+    async fn get(
+        &self,
+        key: Bytes,
+        cmd_metas: CommandMetas,
+    ) -> Result<PendingResponse<Option<Bytes>>, PaxosError> {
+        let query = KvCommand::Get { key };
+        self.replica.lock().await.query(query, Consistency::Linearizable, cmd_metas)
+    }
+
+    /// Redirects a read to the leader. Peers are addressed by the URI of
+    /// their `/paxos` endpoint, which shares its host with the client API.
+    async fn redirect(&self, leader: NodeId, key: &Bytes) -> Result<Response<Body>, hyper::Error> {
+        let location = {
+            let replica = self.replica.lock().await;
+            match replica.inner().configuration().peer(leader) {
+                Some(meta) => {
+                    let uri = String::from_utf8_lossy(&meta.0);
+                    let base = uri.trim_end_matches("/paxos");
+                    format!("{}/{}", base, String::from_utf8_lossy(key))
+                }
+                None => return respond(StatusCode::SERVICE_UNAVAILABLE),
+            }
+        };
+        Ok(Response::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header("Location", location)
+            .header(LEADER_HEADER, leader)
+            .body(Body::empty())
+            .unwrap())
     }
 
     pub async fn handle(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
         let path = Bytes::from(req.uri().path()[1..].to_string());
         match (req.method(), path) {
             (&Method::POST, key) if key == "paxos" => {
-                {
+                let refused = {
+                    let metas = req.headers().get(commands::METAS_HEADER).cloned();
                     let cmd = hyper::body::to_bytes(req.into_body()).await?;
                     let mut replica = self.replica.lock().await;
-                    commands::invoke::<PaxosReplica>(&mut replica, cmd);
-                }
+                    commands::invoke::<PaxosReplica<T>>(&mut replica, cmd, metas.as_ref())
+                };
 
-                respond(StatusCode::ACCEPTED)
+                match refused {
+                    Ok(()) => respond(StatusCode::ACCEPTED),
+                    Err(e) => {
+                        warn!("Refused Paxos command: {}", e);
+                        respond(StatusCode::BAD_REQUEST)
+                    }
+                }
+            }
+            (&Method::GET, key) if key == "_status" => {
+                let status = self.replica.lock().await.inner().status();
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .body(serde_json::to_vec(&status).unwrap().into())
+                    .unwrap())
             }
             (&Method::POST, key) => {
+                let cmd_metas = client_metas(&req);
                 let value = hyper::body::to_bytes(req.into_body()).await?;
-                let request_id = random();
-                let receiver = self.store.register_set(request_id);
-                {
-                    self.replica.lock().await.receive(
-                        Command::Proposal {
-                            payload: KvCommand::Set { request_id, key, value }.into(),
-                        },
-                        CommandMetas("".into()),
-                    );
-                }
+                let command = KvCommand::Set { key, value };
+                let response = match self.propose(command, cmd_metas).await {
+                    Ok(response) => response,
+                    Err(_) => return respond(StatusCode::SERVICE_UNAVAILABLE),
+                };
 
-                match receiver.await {
-                    Ok(slot) => Ok(Response::builder()
+                match response.await {
+                    Ok((slot, _)) => Ok(Response::builder()
                         .status(StatusCode::NO_CONTENT)
                         .header("X-Paxos-Slot", slot)
                         .body(Body::empty())
                         .unwrap()),
-                    Err(_) => respond(StatusCode::INTERNAL_SERVER_ERROR),
+                    Err(_) => respond(StatusCode::SERVICE_UNAVAILABLE),
                 }
             }
             (&Method::GET, key) => {
-                let request_id = random::<u64>();
-                let receiver = self.store.register_get(request_id);
-                {
-                    self.replica.lock().await.receive(
-                        Command::Proposal { payload: (KvCommand::Get { request_id, key }.into()) },
-                        CommandMetas("".into()),
-                    );
-                }
+                let response = match self.get(key.clone(), client_metas(&req)).await {
+                    Ok(response) => response,
+                    Err(PaxosError::NotLeader { leader: Some(leader) }) => {
+                        return self.redirect(leader, &key).await
+                    }
+                    Err(_) => return respond(StatusCode::SERVICE_UNAVAILABLE),
+                };
 
-                match receiver.await {
-                    Ok(Some((slot, value))) => Ok(Response::builder()
+                match response.await {
+                    Ok((slot, Some(value))) => Ok(Response::builder()
                         .status(StatusCode::OK)
                         .header("X-Paxos-Slot", slot)
                         .body(value.into())
                         .unwrap()),
-                    Ok(None) => respond(StatusCode::NOT_FOUND),
-                    Err(_) => respond(StatusCode::INTERNAL_SERVER_ERROR),
+                    Ok((_, None)) => respond(StatusCode::NOT_FOUND),
+                    Err(_) => respond(StatusCode::SERVICE_UNAVAILABLE),
                 }
             }
             (_, key) if key == "paxos" => respond(StatusCode::METHOD_NOT_ALLOWED),
@@ -101,6 +175,16 @@ impl Handler {
     }
 }
 
+/// Continues the trace of the client's `traceparent` header, or starts a
+/// new trace
+fn client_metas(req: &Request<Body>) -> CommandMetas {
+    req.headers()
+        .get("traceparent")
+        .and_then(|header| header.to_str().ok())
+        .and_then(CommandMetas::from_traceparent)
+        .unwrap_or_else(|| CommandMetas::trace(random::<u128>() | 1, random::<u64>() | 1))
+}
+
 fn respond(code: StatusCode) -> Result<Response<Body>, hyper::Error> {
     let mut resp = Response::default();
     *resp.status_mut() = code;
@@ -1,16 +1,16 @@
-use crate::{config::QuorumSet, Ballot, NodeId};
+use crate::{config::QuorumSet, status::AcceptorStatus, Ballot, NodeId, Value};
 use bytes::Bytes;
 use std::cmp::max;
 
 /// Encoding of Acceptor (persistent Paxos memory) role
-pub struct Acceptor {
+pub struct Acceptor<V = Bytes> {
     /// last accepted ballot/value pair within this instance
-    state: AcceptorState,
+    state: AcceptorState<V>,
 }
 
-impl Acceptor {
+impl<V: Value> Acceptor<V> {
     /// New acceptor with last promised ballot and Phase 2 quorum size
-    pub fn new(promised: Option<Ballot>, quorum: usize) -> Acceptor {
+    pub fn new(promised: Option<Ballot>, quorum: usize) -> Acceptor<V> {
         assert!(quorum > 1);
         Acceptor { state: AcceptorState::AwaitValue { promised, quorum } }
     }
@@ -25,7 +25,7 @@ impl Acceptor {
     }
 
     /// Value of the highest accepted value
-    pub fn highest_value(&self) -> Option<(Ballot, Bytes)> {
+    pub fn highest_value(&self) -> Option<(Ballot, V)> {
         match self.state {
             AcceptorState::AwaitValue { .. } => None,
             AcceptorState::AwaitQuorum { ref proposed, .. } => Some(proposed.clone()),
@@ -34,7 +34,7 @@ impl Acceptor {
     }
 
     /// Shows the resolution, if available
-    pub fn resolution(&self) -> Option<(Ballot, Bytes)> {
+    pub fn resolution(&self) -> Option<(Ballot, V)> {
         if let AcceptorState::Resolved { accepted, ref value } = self.state {
             Some((accepted, value.clone()))
         } else {
@@ -42,16 +42,35 @@ impl Acceptor {
         }
     }
 
+    /// Snapshot of the acceptor's state
+    pub fn status(&self) -> AcceptorStatus {
+        match self.state {
+            AcceptorState::AwaitValue { promised, .. } => AcceptorStatus::AwaitValue { promised },
+            AcceptorState::AwaitQuorum { promised, proposed: (accepted, _), ref quorum } => {
+                // the acceptor counts itself towards the quorum
+                AcceptorStatus::AwaitQuorum {
+                    promised,
+                    accepted,
+                    acknowledged: quorum.count() + 1,
+                    quorum: quorum.len() + 1,
+                }
+            }
+            AcceptorState::Resolved { accepted, .. } => {
+                AcceptorStatus::Resolved { ballot: accepted }
+            }
+        }
+    }
+
     /// Indicator of the learner considering the instance resolved to a value
     pub fn resolved(&self) -> bool {
-        if let AcceptorState::Resolved { .. } = self.state { true } else { false }
+        matches!(self.state, AcceptorState::Resolved { .. })
     }
 
     /// Resolves a value within the learner state
-    pub fn resolve(&mut self, bal: Ballot, val: Bytes) {
+    pub fn resolve(&mut self, bal: Ballot, val: V) {
         // ignore if the acceptor is already resolved
         if let AcceptorState::Resolved { accepted, ref value } = self.state {
-            if accepted != bal || val != value {
+            if accepted != bal || &val != value {
                 warn!(
                     "Attempt to resolve to a different ballot or value. Accepted=<{:?},{:?}>, Attempted=<{:?},{:?}>",
                     accepted, value, bal, val
@@ -66,7 +85,7 @@ impl Acceptor {
     /// Handler for a PREPARE message sent from a proposer. The result is either
     /// a PROMISE to the proposer to not accept ballots > proposal or a
     /// REJECT if a ballot has been promised with a ballot > proposal.
-    pub fn receive_prepare(&mut self, ballot: Ballot) -> PrepareResponse {
+    pub fn receive_prepare(&mut self, ballot: Ballot) -> PrepareResponse<V> {
         if self.resolved() {
             return PrepareResponse::Resolved;
         }
@@ -97,7 +116,7 @@ impl Acceptor {
     /// quorum for the Phase 1 PREPARE has been made from acceptors.
     /// Opposing ballots may still happen in Phase 2, in which case a REJECT
     /// is sent.
-    pub fn receive_accept(&mut self, ballot: Ballot, value: Bytes) -> AcceptResponse {
+    pub fn receive_accept(&mut self, ballot: Ballot, value: V) -> AcceptResponse<V> {
         // set the promised value accordingly. In Paxos, it is possible
         // for an acceptor to miss the PREPARE (as in, not participate in quorum)
         // yet still participate in Phase 2 quorum. Once this is the case, we need
@@ -134,7 +153,7 @@ impl Acceptor {
     ///
     /// The value returned is the ballot and value that was previously the
     /// highest see by the acceptor.
-    pub fn notice_value(&mut self, ballot: Ballot, value: Bytes) -> Option<(Ballot, Bytes)> {
+    pub fn notice_value(&mut self, ballot: Ballot, value: V) -> Option<(Ballot, V)> {
         let (next_state, preempted_proposal) = match self.state {
             AcceptorState::AwaitValue { promised, quorum } => {
                 // quorum must be at least _2_. We remove 1 from this state in order
@@ -191,10 +210,10 @@ impl Acceptor {
 
 /// Result of receiving a PREPARE from a proposer
 #[derive(Debug, PartialEq, Eq)]
-pub enum PrepareResponse {
+pub enum PrepareResponse<V> {
     /// Acceptor has promised to not accept a ballot less than the
     /// proposed ballot
-    Promise { proposed: Ballot, value: Option<(Ballot, Bytes)> },
+    Promise { proposed: Ballot, value: Option<(Ballot, V)> },
     /// Phase 1 is rejected due to a previously accepted ballot
     /// that is higher than the proposed ballot
     Reject { proposed: Ballot, preempted: Ballot },
@@ -204,13 +223,13 @@ pub enum PrepareResponse {
 
 /// Result of receiving an ACCEPT from a proposer
 #[derive(Debug, PartialEq, Eq)]
-pub enum AcceptResponse {
+pub enum AcceptResponse<V> {
     /// Acceptor has accepted the value of the proposed ballot
     Accepted {
         proposed: Ballot,
         /// Proposal that the acceptor as previously ACCEPTED
         /// with ballot < proposed
-        preempted_proposal: Option<(Ballot, Bytes)>,
+        preempted_proposal: Option<(Ballot, V)>,
     },
     /// Phase 2 is rejected due to a previously accepted ballot
     /// that is higher than the accept message ballot
@@ -220,7 +239,7 @@ pub enum AcceptResponse {
 }
 
 #[derive(Debug)]
-enum AcceptorState {
+enum AcceptorState<V> {
     AwaitValue {
         /// last promised ballot within this instance
         promised: Option<Ballot>,
@@ -236,7 +255,7 @@ enum AcceptorState {
 
         /// thus we need to wait for the final ballot's quorum to proceed
         /// to the final state
-        proposed: (Ballot, Bytes),
+        proposed: (Ballot, V),
 
         /// Set of acceptors that have sent ACCEPTED responses for this instance
         /// (only used when current node is the proposer)
@@ -247,7 +266,7 @@ enum AcceptorState {
         /// Final ballot that was committed
         accepted: Ballot,
         /// Accepted value
-        value: Bytes,
+        value: V,
     },
 }
 
@@ -257,7 +276,7 @@ mod tests {
 
     #[test]
     fn receive_prepare() {
-        let mut acceptor = Acceptor::new(None, 2);
+        let mut acceptor: Acceptor = Acceptor::new(None, 2);
 
         // acceptor promises the ballot when nothing promised
         let res = acceptor.receive_prepare(Ballot(100, 1));
@@ -297,7 +316,7 @@ mod tests {
 
     #[test]
     fn receive_accept() {
-        let mut acceptor = Acceptor::new(None, 2);
+        let mut acceptor: Acceptor = Acceptor::new(None, 2);
 
         // acceptor allows ACCEPT without a promise
         let res = acceptor.receive_accept(Ballot(101, 1), "ab".into());
@@ -329,7 +348,7 @@ mod tests {
         let res = acceptor.receive_accept(Ballot(105, 5), "cde".into());
         assert_eq!(res, AcceptResponse::Resolved);
 
-        let mut acceptor = Acceptor::new(None, 2);
+        let mut acceptor: Acceptor = Acceptor::new(None, 2);
         acceptor.receive_prepare(Ballot(100, 4));
         assert_eq!(acceptor.promised(), Some(Ballot(100, 4)));
 
@@ -343,7 +362,7 @@ mod tests {
 
     #[test]
     fn receive_accepted() {
-        let mut acceptor = Acceptor::new(None, 3);
+        let mut acceptor: Acceptor = Acceptor::new(None, 3);
 
         // accepts new ballot
         assert_eq!(
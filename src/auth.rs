@@ -0,0 +1,197 @@
+//! Cluster identity and authentication of messages between replicas.
+//!
+//! Frames written by `codec::encode_sealed` carry the identifier of the
+//! cluster and the epoch of its membership. When the `Configuration` holds
+//! shared keys, frames also carry an HMAC-SHA256 of their contents. The
+//! receiving side checks the `Seal` of each frame with `Authenticator::verify`
+//! before handing the command to the replica.
+//!
+//! Shared keys require the `auth` feature. Without it frames are only
+//! checked for the cluster and epoch.
+use crate::error::{PaxosError, Result};
+use bytes::Bytes;
+#[cfg(feature = "auth")]
+use hmac_sha256::HMAC;
+#[cfg(feature = "auth")]
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Length of the authentication code of a frame
+pub const MAC_LEN: usize = 32;
+
+/// Cluster identity and authentication code carried by a frame
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Seal {
+    /// Identifier of the sender's cluster
+    pub cluster: u64,
+    /// Epoch of the sender's membership
+    pub epoch: u64,
+    /// Authentication code along with the part of the frame it covers
+    pub(crate) mac: Option<(Bytes, Bytes)>,
+}
+
+/// Signs and verifies frames on behalf of a cluster.
+///
+/// Created with `Configuration::authenticator`. Without shared keys frames
+/// are only checked for the cluster and epoch.
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    cluster: u64,
+    epoch: u64,
+    keys: Vec<Bytes>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(cluster: u64, epoch: u64, keys: Vec<Bytes>) -> Authenticator {
+        Authenticator { cluster, epoch, keys }
+    }
+
+    /// Identifier of the cluster written to frames
+    pub fn cluster(&self) -> u64 {
+        self.cluster
+    }
+
+    /// Epoch of the membership written to frames
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Authentication code of a frame, or `None` without shared keys
+    #[cfg(feature = "auth")]
+    pub(crate) fn sign(&self, signed: &[u8]) -> Option<[u8; MAC_LEN]> {
+        self.keys.first().map(|key| HMAC::mac(signed, key))
+    }
+
+    /// Frames are never signed without the `auth` feature
+    #[cfg(not(feature = "auth"))]
+    pub(crate) fn sign(&self, _signed: &[u8]) -> Option<[u8; MAC_LEN]> {
+        None
+    }
+
+    /// Checks that a frame was sent by a member of the same cluster and
+    /// epoch, signed by one of the shared keys
+    pub fn verify(&self, seal: &Seal) -> Result<()> {
+        if seal.cluster != self.cluster {
+            return Err(PaxosError::WrongCluster { cluster: seal.cluster });
+        }
+        if seal.epoch != self.epoch {
+            return Err(PaxosError::WrongEpoch { epoch: seal.epoch });
+        }
+        if self.keys.is_empty() {
+            return Ok(());
+        }
+        self.verify_mac(seal)
+    }
+
+    #[cfg(feature = "auth")]
+    fn verify_mac(&self, seal: &Seal) -> Result<()> {
+        let (signed, mac) = seal.mac.as_ref().ok_or(PaxosError::Unauthenticated)?;
+        let mac =
+            <&[u8; MAC_LEN]>::try_from(mac.as_ref()).map_err(|_| PaxosError::Unauthenticated)?;
+        if self.keys.iter().any(|key| HMAC::verify(signed, key, mac)) {
+            Ok(())
+        } else {
+            Err(PaxosError::Unauthenticated)
+        }
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn verify_mac(&self, _seal: &Seal) -> Result<()> {
+        Err(PaxosError::Unauthenticated)
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Authenticator")
+            .field("cluster", &self.cluster)
+            .field("epoch", &self.epoch)
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::{decode_sealed, encode, encode_sealed},
+        commands::{Command, CommandMetas},
+        Ballot,
+    };
+
+    fn seal(frame: Bytes) -> Seal {
+        decode_sealed::<Bytes>(frame).unwrap().2
+    }
+
+    fn prepare() -> Command {
+        Command::Prepare { payload: (Ballot(3, 1), 0) }
+    }
+
+    fn metas() -> CommandMetas {
+        CommandMetas::default().with_baggage("trace")
+    }
+
+    #[test]
+    fn auth_checks_cluster_and_epoch() {
+        let auth = Authenticator::new(7, 2, vec![]);
+        assert_eq!(Ok(()), auth.verify(&seal(encode_sealed(&prepare(), &metas(), &auth))));
+
+        let other = Authenticator::new(8, 2, vec![]);
+        let frame = encode_sealed(&prepare(), &metas(), &other);
+        assert_eq!(Err(PaxosError::WrongCluster { cluster: 8 }), auth.verify(&seal(frame)));
+
+        let stale = Authenticator::new(7, 1, vec![]);
+        let frame = encode_sealed(&prepare(), &metas(), &stale);
+        assert_eq!(Err(PaxosError::WrongEpoch { epoch: 1 }), auth.verify(&seal(frame)));
+
+        // unsealed frames belong to cluster 0 and epoch 0
+        let frame = encode(&prepare(), &metas());
+        assert_eq!(Ok(()), Authenticator::default().verify(&seal(frame.clone())));
+        assert_eq!(Err(PaxosError::WrongCluster { cluster: 0 }), auth.verify(&seal(frame)));
+    }
+
+    #[test]
+    #[cfg(feature = "auth")]
+    fn auth_signs_frames() {
+        let auth = Authenticator::new(7, 2, vec!["key".into()]);
+        let frame = encode_sealed(&prepare(), &metas(), &auth);
+        let (cmd, cmd_metas, signed) = decode_sealed(frame).unwrap();
+        assert_eq!(prepare(), cmd);
+        assert_eq!("trace", cmd_metas.baggage);
+        assert_eq!(Ok(()), auth.verify(&signed));
+
+        // verified with any of the keys
+        let rotated = Authenticator::new(7, 2, vec!["new-key".into(), "key".into()]);
+        assert_eq!(Ok(()), rotated.verify(&signed));
+
+        let other = Authenticator::new(7, 2, vec!["other-key".into()]);
+        assert_eq!(Err(PaxosError::Unauthenticated), other.verify(&signed));
+
+        // unsigned frames are refused
+        let unsigned = Authenticator::new(7, 2, vec![]);
+        let frame = encode_sealed(&prepare(), &metas(), &unsigned);
+        assert_eq!(Err(PaxosError::Unauthenticated), auth.verify(&seal(frame)));
+    }
+
+    #[test]
+    #[cfg(feature = "auth")]
+    fn auth_detects_tampering() {
+        let auth = Authenticator::new(7, 2, vec!["key".into()]);
+        let frame = encode_sealed(&prepare(), &metas(), &auth);
+
+        // the round of the ballot
+        let mut tampered = frame.to_vec();
+        tampered[5] = 4;
+        let (cmd, _, seal) = decode_sealed::<Bytes>(tampered.into()).unwrap();
+        assert_eq!(Command::Prepare { payload: (Ballot(4, 1), 0) }, cmd);
+        assert_eq!(Err(PaxosError::Unauthenticated), auth.verify(&seal));
+
+        // fields following the authentication code are not covered by it
+        let mut appended = frame.to_vec();
+        appended.extend_from_slice(&[0x7a, 0x01, b'x']);
+        let (_, cmd_metas, seal) = decode_sealed::<Bytes>(appended.into()).unwrap();
+        assert_eq!("x", cmd_metas.baggage);
+        assert_eq!(Err(PaxosError::Unauthenticated), auth.verify(&seal));
+    }
+}
@@ -0,0 +1,138 @@
+//! Flow control of the catchup of decided slots from peers.
+//!
+//! A replica that learns of decisions past its open window asks a single
+//! peer at a time for the decided values of at most `MAX_CATCHUP_SLOTS`
+//! slots. The next request is only sent once the previous one has been
+//! answered, and a peer that stops making progress is replaced by another.
+use crate::{NodeId, Slot};
+use std::ops::Range;
+
+/// Maximum number of slots covered by a single catchup request
+pub const MAX_CATCHUP_SLOTS: Slot = 1024;
+
+/// Maximum number of slots in each resolution sent in response to a catchup
+pub const CATCHUP_CHUNK: usize = 128;
+
+/// Number of commands received without progress on a catchup before the
+/// request is sent to another peer
+pub const CATCHUP_TIMEOUT: u64 = 32;
+
+/// Next step of the catchup
+#[derive(Debug, PartialEq, Eq)]
+pub enum CatchupPoll {
+    /// Nothing to request, or waiting on the request in flight
+    Wait,
+    /// Request the next slots from the leader
+    Request,
+    /// Request the slots again from a peer other than the one given
+    Retry(NodeId),
+}
+
+/// Request sent to a peer and not yet fully answered
+#[derive(Debug)]
+struct InFlight {
+    peer: NodeId,
+    /// End of the requested slots
+    end: Slot,
+    /// First undecided slot when progress was last made
+    progress: Slot,
+    /// Number of commands received when progress was last made
+    received: u64,
+}
+
+/// Tracks the slots known to be decided by peers and the catchup request
+/// in flight
+#[derive(Debug, Default)]
+pub struct Catchup {
+    /// Slot following the highest slot known to be decided
+    target: Slot,
+    in_flight: Option<InFlight>,
+}
+
+impl Catchup {
+    /// Notes a slot decided by the peers
+    pub fn observe(&mut self, slot: Slot) {
+        self.target = self.target.max(slot.saturating_add(1));
+    }
+
+    /// Slot following the highest slot known to be decided
+    pub fn target(&self) -> Slot {
+        self.target
+    }
+
+    /// Next step given the first undecided slot and the number of commands
+    /// received so far
+    pub fn poll(&mut self, decided: Slot, received: u64) -> CatchupPoll {
+        if decided >= self.target {
+            self.in_flight = None;
+            return CatchupPoll::Wait;
+        }
+
+        match self.in_flight {
+            None => CatchupPoll::Request,
+            Some(ref req) if decided >= req.end => CatchupPoll::Request,
+            Some(ref mut req) if decided > req.progress => {
+                req.progress = decided;
+                req.received = received;
+                CatchupPoll::Wait
+            }
+            Some(ref req) if received - req.received >= CATCHUP_TIMEOUT => {
+                CatchupPoll::Retry(req.peer)
+            }
+            Some(_) => CatchupPoll::Wait,
+        }
+    }
+
+    /// Notes a request for the slots up to `end` sent to a peer
+    pub fn requested(&mut self, peer: NodeId, end: Slot, decided: Slot, received: u64) {
+        self.in_flight = Some(InFlight { peer, end, progress: decided, received });
+    }
+}
+
+/// Ranges covering the slots, which must be sorted
+pub fn coalesce<I: IntoIterator<Item = Slot>>(slots: I) -> Vec<Range<Slot>> {
+    let mut ranges: Vec<Range<Slot>> = Vec::new();
+    for slot in slots {
+        match ranges.last_mut() {
+            Some(range) if range.end == slot => range.end += 1,
+            _ => ranges.push(slot..slot + 1),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catchup_flow_control() {
+        let mut catchup = Catchup::default();
+        assert_eq!(CatchupPoll::Wait, catchup.poll(0, 1));
+
+        catchup.observe(2000);
+        catchup.observe(10);
+        assert_eq!(2001, catchup.target());
+        assert_eq!(CatchupPoll::Request, catchup.poll(0, 1));
+        catchup.requested(2, 1024, 0, 1);
+
+        // progress resets the timeout
+        assert_eq!(CatchupPoll::Wait, catchup.poll(0, 2));
+        assert_eq!(CatchupPoll::Wait, catchup.poll(512, 30));
+        assert_eq!(CatchupPoll::Wait, catchup.poll(512, 61));
+        assert_eq!(CatchupPoll::Retry(2), catchup.poll(512, 62));
+        catchup.requested(3, 1536, 512, 62);
+
+        // answered in full
+        assert_eq!(CatchupPoll::Request, catchup.poll(1536, 63));
+        catchup.requested(2, 2001, 1536, 63);
+        assert_eq!(CatchupPoll::Wait, catchup.poll(2001, 64));
+        assert_eq!(CatchupPoll::Wait, catchup.poll(2001, 200));
+    }
+
+    #[test]
+    fn catchup_coalesce() {
+        assert_eq!(Vec::<Range<Slot>>::new(), coalesce(vec![]));
+        assert_eq!(vec![0..3, 5..6, 7..9], coalesce(vec![0, 1, 2, 5, 7, 8]));
+    }
+}
@@ -0,0 +1,872 @@
+//! Versioned binary encoding of `Command` for transports.
+//!
+//! A frame starts with the protocol version and the message type, each a
+//! single byte, followed by the message's fields. Every field is prefixed
+//! with a varint key holding the field number and the wire type of the
+//! value, in the manner of protocol buffers:
+//!
+//! | Wire type | Value                                    |
+//! | --------- | ---------------------------------------- |
+//! | 0         | varint                                   |
+//! | 1         | 8 bytes, little-endian                   |
+//! | 2         | varint length followed by as many bytes  |
+//! | 5         | 4 bytes, little-endian                   |
+//!
+//! Ballots and the entries of lists are nested messages encoded as
+//! length-delimited fields, and lists repeat the field once per entry.
+//! Timestamps are encoded as microseconds since the Unix epoch.
+//!
+//! Decoders skip fields they do not know, so a newer version may add
+//! fields without breaking older replicas during a rolling upgrade. Frames
+//! from versions older than `MIN_PROTOCOL_VERSION` are refused, and frames
+//! with a message type the decoder does not know fail with
+//! `DecodeError::UnknownMessage`, which receivers can drop without closing
+//! the connection.
+//!
+//! Frames carry the cluster identity of the sender, which is decoded into
+//! both the `CommandMetas` and the `auth::Seal` of the frame. Frames
+//! written by `encode_sealed` take the identity from the `Authenticator`
+//! and may end with an authentication code covering every byte before it.
+//!
+//! Values are encoded by their `WireValue` implementation. `Bytes` values
+//! are written as they are, and `Proposed` values as a nested message
+//! holding the identity of the proposal along with the encoded value.
+//!
+//! Decoded values and metadata are slices of the frame, so decoding does
+//! not copy payloads.
+use crate::{
+    auth::{Authenticator, Seal},
+    commands::{Command, CommandMetas},
+    Ballot, NodeId, ProposalId, Proposed, Slot,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::{
+    convert::TryFrom,
+    error, fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Version of the protocol written by `encode`
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest version of the protocol accepted by `decode`
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Fields of every message holding the `Seal`
+const CLUSTER_FIELD: u32 = 12;
+const EPOCH_FIELD: u32 = 13;
+const MAC_FIELD: u32 = 14;
+
+/// Fields of every message holding the `CommandMetas`. The tracing
+/// context is a nested message, omitted when it has no values.
+const TRACE_FIELD: u32 = 11;
+const BAGGAGE_FIELD: u32 = 15;
+
+const PROPOSAL: u8 = 1;
+const PREPARE: u8 = 2;
+const PROMISE: u8 = 3;
+const ACCEPT: u8 = 4;
+const REJECT: u8 = 5;
+const ACCEPTED: u8 = 6;
+const RESOLUTION: u8 = 7;
+const CATCHUP: u8 = 8;
+const HANDOVER: u8 = 9;
+const CONFIRM: u8 = 10;
+const CONFIRMED: u8 = 11;
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const FIXED32: u8 = 5;
+
+/// Reason a frame could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The frame was written with a protocol version that is no longer
+    /// supported
+    UnsupportedVersion(u8),
+    /// The message type was added by a newer version of the protocol
+    UnknownMessage(u8),
+    /// The frame ended in the middle of a value
+    Truncated,
+    /// A varint is longer than 10 bytes
+    InvalidVarint,
+    /// A field holds a wire type that is unknown or does not match the
+    /// field's type
+    InvalidWireType { field: u32, wire_type: u8 },
+    /// A required field is absent
+    MissingField { field: u32 },
+    /// A field holds a value out of range for its type
+    InvalidValue { field: u32 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(fmt, "unsupported protocol version {}", version)
+            }
+            DecodeError::UnknownMessage(kind) => write!(fmt, "unknown message type {}", kind),
+            DecodeError::Truncated => write!(fmt, "frame is truncated"),
+            DecodeError::InvalidVarint => write!(fmt, "varint exceeds 64 bits"),
+            DecodeError::InvalidWireType { field, wire_type } => {
+                write!(fmt, "invalid wire type {} for field {}", wire_type, field)
+            }
+            DecodeError::MissingField { field } => write!(fmt, "missing field {}", field),
+            DecodeError::InvalidValue { field } => write!(fmt, "invalid value for field {}", field),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Value decided by replicas that can be written to frames
+pub trait WireValue: crate::Value {
+    /// Writes the value as the contents of a length-delimited field
+    fn encode(&self, buf: &mut BytesMut);
+
+    /// Reads the value from the contents of a length-delimited field
+    fn decode(buf: Bytes) -> Result<Self, DecodeError>;
+}
+
+impl WireValue for Bytes {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_slice(self);
+    }
+
+    fn decode(buf: Bytes) -> Result<Bytes, DecodeError> {
+        Ok(buf)
+    }
+}
+
+impl<V: WireValue> WireValue for Proposed<V> {
+    fn encode(&self, buf: &mut BytesMut) {
+        let mut w = Writer(BytesMut::new());
+        w.varint(1, u64::from(self.id.node));
+        w.varint(2, self.id.seq);
+        w.value(3, &self.value);
+        buf.put_slice(&w.0);
+    }
+
+    fn decode(buf: Bytes) -> Result<Proposed<V>, DecodeError> {
+        let mut fields = Fields::read(buf)?;
+        let id = ProposalId { node: fields.node(1)?, seq: fields.varint(2)? };
+        Ok(Proposed { id, value: fields.value(3)? })
+    }
+}
+
+/// Encodes a command along with its metadata as a frame of the current
+/// protocol version
+pub fn encode<V: WireValue>(cmd: &Command<V>, cmd_metas: &CommandMetas) -> Bytes {
+    let mut w = write(cmd, cmd_metas);
+    w.identity(cmd_metas.cluster, cmd_metas.epoch);
+    w.0.freeze()
+}
+
+/// Encodes a command along with its metadata, sealed with the identity of
+/// the cluster and signed when the cluster has shared keys
+pub fn encode_sealed<V: WireValue>(
+    cmd: &Command<V>,
+    cmd_metas: &CommandMetas,
+    auth: &Authenticator,
+) -> Bytes {
+    let mut w = write(cmd, cmd_metas);
+    w.identity(auth.cluster(), auth.epoch());
+    if let Some(mac) = auth.sign(&w.0) {
+        w.bytes(MAC_FIELD, &mac);
+    }
+    w.0.freeze()
+}
+
+fn write<V: WireValue>(cmd: &Command<V>, cmd_metas: &CommandMetas) -> Writer {
+    let mut w = Writer(BytesMut::with_capacity(64));
+    let kind = match cmd {
+        Command::Proposal { .. } => PROPOSAL,
+        Command::Prepare { .. } => PREPARE,
+        Command::Promise { .. } => PROMISE,
+        Command::Accept { .. } => ACCEPT,
+        Command::Reject { .. } => REJECT,
+        Command::Accepted { .. } => ACCEPTED,
+        Command::Resolution { .. } => RESOLUTION,
+        Command::Catchup { .. } => CATCHUP,
+        Command::Handover { .. } => HANDOVER,
+        Command::Confirm { .. } => CONFIRM,
+        Command::Confirmed { .. } => CONFIRMED,
+    };
+    w.0.put_u8(PROTOCOL_VERSION);
+    w.0.put_u8(kind);
+
+    match cmd {
+        Command::Proposal { payload: val } => w.value(1, val),
+        Command::Prepare { payload: (bal, decided) } => {
+            w.ballot(1, *bal);
+            // absent in frames of replicas that reported every decided slot
+            if *decided != 0 {
+                w.varint(2, *decided);
+            }
+        }
+        Command::Promise { payload: (node, bal, accepted) } => {
+            w.varint(1, u64::from(*node));
+            w.ballot(2, *bal);
+            for (slot, bal, val) in accepted {
+                w.message(3, |w| {
+                    w.varint(1, *slot);
+                    w.ballot(2, *bal);
+                    w.value(3, val);
+                });
+            }
+        }
+        Command::Accept { payload: (bal, slot_vals) } => {
+            w.ballot(1, *bal);
+            w.slot_values(2, slot_vals);
+        }
+        Command::Reject { payload: (node, proposed, preempted) } => {
+            w.varint(1, u64::from(*node));
+            w.ballot(2, *proposed);
+            w.ballot(3, *preempted);
+        }
+        Command::Accepted { payload: (node, bal, slots) } => {
+            w.varint(1, u64::from(*node));
+            w.ballot(2, *bal);
+            slots.iter().for_each(|slot| w.varint(3, *slot));
+        }
+        Command::Resolution { payload: (bal, slot_vals) } => {
+            w.ballot(1, *bal);
+            w.slot_values(2, slot_vals);
+        }
+        Command::Catchup { payload: (node, ranges) } => {
+            w.varint(1, u64::from(*node));
+            for range in ranges {
+                w.message(3, |w| {
+                    w.varint(1, range.start);
+                    w.varint(2, range.end);
+                });
+            }
+        }
+        Command::Handover { payload: (bal, decided) } => {
+            w.ballot(1, *bal);
+            w.varint(2, *decided);
+        }
+        Command::Confirm { payload: (bal, read) } => {
+            w.ballot(1, *bal);
+            w.varint(2, *read);
+        }
+        Command::Confirmed { payload: (node, bal, read) } => {
+            w.varint(1, u64::from(*node));
+            w.ballot(2, *bal);
+            w.varint(3, *read);
+        }
+    }
+    w.trace(TRACE_FIELD, cmd_metas);
+    w.bytes(BAGGAGE_FIELD, &cmd_metas.baggage);
+    w
+}
+
+/// Decodes a frame written by `encode` of the same or a newer protocol
+/// version
+pub fn decode<V: WireValue>(frame: Bytes) -> Result<(Command<V>, CommandMetas), DecodeError> {
+    decode_sealed(frame).map(|(cmd, cmd_metas, _)| (cmd, cmd_metas))
+}
+
+/// Decodes a frame along with its seal, which is left for the caller to
+/// verify. Frames written by `encode` carry no authentication code.
+pub fn decode_sealed<V: WireValue>(
+    frame: Bytes,
+) -> Result<(Command<V>, CommandMetas, Seal), DecodeError> {
+    if frame.len() < 2 {
+        return Err(DecodeError::Truncated);
+    }
+    let (version, kind) = (frame[0], frame[1]);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    if !(PROPOSAL..=CONFIRMED).contains(&kind) {
+        return Err(DecodeError::UnknownMessage(kind));
+    }
+
+    let mut fields = Fields::default();
+    let mut metas = CommandMetas::default();
+    let mut seal = Seal::default();
+    let mut r = Reader { buf: frame.slice(2..), pos: 0 };
+    loop {
+        let offset = 2 + r.pos;
+        let (field, value) = match r.next()? {
+            Some(next) => next,
+            None => break,
+        };
+        match field {
+            TRACE_FIELD => read_trace(value.bytes(field)?, &mut metas)?,
+            BAGGAGE_FIELD => metas.baggage = value.bytes(field)?,
+            CLUSTER_FIELD => seal.cluster = value.varint(field)?,
+            EPOCH_FIELD => seal.epoch = value.varint(field)?,
+            MAC_FIELD => seal.mac = Some((frame.slice(..offset), value.bytes(field)?)),
+            _ => fields.push(field, value),
+        }
+        // the authentication code only counts when it covers the whole frame
+        if field != MAC_FIELD {
+            seal.mac = None;
+        }
+    }
+
+    let cmd = match kind {
+        PROPOSAL => Command::Proposal { payload: fields.value(1)? },
+        PREPARE => Command::Prepare {
+            payload: (fields.ballot(1)?, fields.optional(2, Fields::slot)?.unwrap_or_default()),
+        },
+        PROMISE => {
+            let accepted = fields
+                .messages(3)?
+                .map(|mut entry| Ok((entry.slot(1)?, entry.ballot(2)?, entry.value(3)?)))
+                .collect::<Result<_, DecodeError>>()?;
+            Command::Promise { payload: (fields.node(1)?, fields.ballot(2)?, accepted) }
+        }
+        ACCEPT => Command::Accept { payload: (fields.ballot(1)?, fields.slot_values(2)?) },
+        REJECT => Command::Reject {
+            payload: (fields.node(1)?, fields.ballot(2)?, fields.ballot(3)?),
+        },
+        ACCEPTED => Command::Accepted {
+            payload: (fields.node(1)?, fields.ballot(2)?, fields.slots(3)?),
+        },
+        RESOLUTION => {
+            Command::Resolution { payload: (fields.ballot(1)?, fields.slot_values(2)?) }
+        }
+        CATCHUP => {
+            // version 1 requested individual slots
+            let slots = fields.slots(2)?.into_iter().map(|slot| slot..slot.saturating_add(1));
+            let ranges = fields
+                .messages(3)?
+                .map(|mut range| Ok(range.slot(1)?..range.slot(2)?))
+                .collect::<Result<Vec<_>, DecodeError>>()?;
+            Command::Catchup { payload: (fields.node(1)?, slots.chain(ranges).collect()) }
+        }
+        HANDOVER => Command::Handover { payload: (fields.ballot(1)?, fields.slot(2)?) },
+        CONFIRM => Command::Confirm { payload: (fields.ballot(1)?, fields.varint(2)?) },
+        _ => Command::Confirmed {
+            payload: (fields.node(1)?, fields.ballot(2)?, fields.varint(3)?),
+        },
+    };
+    metas.cluster = seal.cluster;
+    metas.epoch = seal.epoch;
+    Ok((cmd, metas, seal))
+}
+
+fn read_trace(buf: Bytes, metas: &mut CommandMetas) -> Result<(), DecodeError> {
+    let mut trace = Fields::read(buf)?;
+    if let Some(trace_id) = trace.optional(1, Fields::bytes)? {
+        let trace_id = <[u8; 16]>::try_from(trace_id.as_ref())
+            .map_err(|_| DecodeError::InvalidValue { field: 1 })?;
+        metas.trace_id = u128::from_be_bytes(trace_id);
+    }
+    metas.span_id = trace.optional(2, Fields::slot)?.unwrap_or_default();
+    metas.parent_span_id = trace.optional(3, Fields::slot)?.unwrap_or_default();
+    metas.origin = trace.optional(4, Fields::node)?;
+    metas.sent_at = trace.optional(5, Fields::time)?;
+    metas.deadline = trace.optional(6, Fields::time)?;
+    Ok(())
+}
+
+struct Writer(BytesMut);
+
+impl Writer {
+    fn key(&mut self, field: u32, wire_type: u8) {
+        put_varint(&mut self.0, u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn varint(&mut self, field: u32, val: u64) {
+        self.key(field, VARINT);
+        put_varint(&mut self.0, val);
+    }
+
+    fn bytes(&mut self, field: u32, val: &[u8]) {
+        self.key(field, LENGTH_DELIMITED);
+        put_varint(&mut self.0, val.len() as u64);
+        self.0.put_slice(val);
+    }
+
+    fn value<V: WireValue>(&mut self, field: u32, val: &V) {
+        let mut buf = BytesMut::new();
+        val.encode(&mut buf);
+        self.bytes(field, &buf);
+    }
+
+    fn message<F: FnOnce(&mut Writer)>(&mut self, field: u32, f: F) {
+        let mut nested = Writer(BytesMut::new());
+        f(&mut nested);
+        self.bytes(field, &nested.0);
+    }
+
+    fn ballot(&mut self, field: u32, Ballot(round, node): Ballot) {
+        self.message(field, |w| {
+            w.varint(1, u64::from(round));
+            w.varint(2, u64::from(node));
+        });
+    }
+
+    /// Cluster identity of the sender, omitted for the default cluster
+    fn identity(&mut self, cluster: u64, epoch: u64) {
+        if cluster != 0 {
+            self.varint(CLUSTER_FIELD, cluster);
+        }
+        if epoch != 0 {
+            self.varint(EPOCH_FIELD, epoch);
+        }
+    }
+
+    fn trace(&mut self, field: u32, metas: &CommandMetas) {
+        let mut trace = Writer(BytesMut::new());
+        if metas.trace_id != 0 {
+            trace.bytes(1, &metas.trace_id.to_be_bytes());
+        }
+        let ids = [(2, metas.span_id), (3, metas.parent_span_id)];
+        for (id_field, id) in ids.iter().filter(|(_, id)| *id != 0) {
+            trace.varint(*id_field, *id);
+        }
+        if let Some(origin) = metas.origin {
+            trace.varint(4, u64::from(origin));
+        }
+        let times = [(5, metas.sent_at), (6, metas.deadline)];
+        for (time_field, time) in times.iter() {
+            if let Some(time) = time {
+                let micros = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+                trace.varint(*time_field, micros as u64);
+            }
+        }
+        if !trace.0.is_empty() {
+            self.bytes(field, &trace.0);
+        }
+    }
+
+    fn slot_values<V: WireValue>(&mut self, field: u32, slot_vals: &[(Slot, V)]) {
+        for (slot, val) in slot_vals {
+            self.message(field, |w| {
+                w.varint(1, *slot);
+                w.value(2, val);
+            });
+        }
+    }
+}
+
+fn put_varint(buf: &mut BytesMut, mut val: u64) {
+    while val >= 0x80 {
+        buf.put_u8(val as u8 | 0x80);
+        val >>= 7;
+    }
+    buf.put_u8(val as u8);
+}
+
+/// Value of a field as read from the wire
+enum Value {
+    Varint(u64),
+    Bytes(Bytes),
+    /// Fixed width values are not used by the current version and are
+    /// only skipped
+    Fixed,
+}
+
+impl Value {
+    fn varint(&self, field: u32) -> Result<u64, DecodeError> {
+        match self {
+            Value::Varint(val) => Ok(*val),
+            _ => Err(self.mismatch(field)),
+        }
+    }
+
+    fn bytes(self, field: u32) -> Result<Bytes, DecodeError> {
+        match self {
+            Value::Bytes(val) => Ok(val),
+            _ => Err(self.mismatch(field)),
+        }
+    }
+
+    fn mismatch(&self, field: u32) -> DecodeError {
+        let wire_type = match self {
+            Value::Varint(_) => VARINT,
+            Value::Bytes(_) => LENGTH_DELIMITED,
+            Value::Fixed => FIXED64,
+        };
+        DecodeError::InvalidWireType { field, wire_type }
+    }
+}
+
+struct Reader {
+    buf: Bytes,
+    pos: usize,
+}
+
+impl Reader {
+    fn next(&mut self) -> Result<Option<(u32, Value)>, DecodeError> {
+        if self.pos == self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let field = (key >> 3) as u32;
+        let wire_type = (key & 0x7) as u8;
+        let value = match wire_type {
+            VARINT => Value::Varint(self.varint()?),
+            FIXED64 => self.skip(8).map(|_| Value::Fixed)?,
+            LENGTH_DELIMITED => {
+                let len = self.varint()? as usize;
+                let start = self.pos;
+                self.skip(len)?;
+                Value::Bytes(self.buf.slice(start..self.pos))
+            }
+            FIXED32 => self.skip(4).map(|_| Value::Fixed)?,
+            _ => return Err(DecodeError::InvalidWireType { field, wire_type }),
+        };
+        Ok(Some((field, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or(DecodeError::Truncated)?;
+            self.pos += 1;
+            val |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), DecodeError> {
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError::Truncated);
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// Known fields of a message in the order they were read
+#[derive(Default)]
+struct Fields(Vec<(u32, Value)>);
+
+impl Fields {
+    fn read(buf: Bytes) -> Result<Fields, DecodeError> {
+        let mut fields = Fields::default();
+        let mut r = Reader { buf, pos: 0 };
+        while let Some((field, value)) = r.next()? {
+            fields.push(field, value);
+        }
+        Ok(fields)
+    }
+
+    fn push(&mut self, field: u32, value: Value) {
+        self.0.push((field, value));
+    }
+
+    /// Last value of a field, as later values override earlier ones
+    fn take(&mut self, field: u32) -> Result<Value, DecodeError> {
+        let i = self.0.iter().rposition(|(f, _)| *f == field);
+        let i = i.ok_or(DecodeError::MissingField { field })?;
+        Ok(self.0.remove(i).1)
+    }
+
+    fn repeated(&mut self, field: u32) -> impl Iterator<Item = Value> {
+        let (values, rest): (Vec<_>, _) =
+            std::mem::take(&mut self.0).into_iter().partition(|(f, _)| *f == field);
+        self.0 = rest;
+        values.into_iter().map(|(_, value)| value)
+    }
+
+    /// Value of a field that may be absent
+    fn optional<T, F>(&mut self, field: u32, read: F) -> Result<Option<T>, DecodeError>
+    where
+        F: FnOnce(&mut Fields, u32) -> Result<T, DecodeError>,
+    {
+        if self.0.iter().any(|(f, _)| *f == field) {
+            read(self, field).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn bytes(&mut self, field: u32) -> Result<Bytes, DecodeError> {
+        self.take(field)?.bytes(field)
+    }
+
+    fn value<V: WireValue>(&mut self, field: u32) -> Result<V, DecodeError> {
+        V::decode(self.bytes(field)?)
+    }
+
+    fn time(&mut self, field: u32) -> Result<SystemTime, DecodeError> {
+        let micros = self.take(field)?.varint(field)?;
+        Ok(UNIX_EPOCH + Duration::from_micros(micros))
+    }
+
+    fn varint(&mut self, field: u32) -> Result<u64, DecodeError> {
+        self.take(field)?.varint(field)
+    }
+
+    fn slot(&mut self, field: u32) -> Result<Slot, DecodeError> {
+        self.take(field)?.varint(field)
+    }
+
+    fn node(&mut self, field: u32) -> Result<NodeId, DecodeError> {
+        let val = self.take(field)?.varint(field)?;
+        NodeId::try_from(val).map_err(|_| DecodeError::InvalidValue { field })
+    }
+
+    fn ballot(&mut self, field: u32) -> Result<Ballot, DecodeError> {
+        let mut ballot = Fields::read(self.bytes(field)?)?;
+        let round = ballot.take(1)?.varint(1)?;
+        let round = u32::try_from(round).map_err(|_| DecodeError::InvalidValue { field: 1 })?;
+        Ok(Ballot(round, ballot.node(2)?))
+    }
+
+    fn messages(&mut self, field: u32) -> Result<impl Iterator<Item = Fields>, DecodeError> {
+        self.repeated(field)
+            .map(|value| Fields::read(value.bytes(field)?))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Vec::into_iter)
+    }
+
+    fn slot_values<V: WireValue>(&mut self, field: u32) -> Result<Vec<(Slot, V)>, DecodeError> {
+        self.messages(field)?.map(|mut entry| Ok((entry.slot(1)?, entry.value(2)?))).collect()
+    }
+
+    fn slots(&mut self, field: u32) -> Result<Vec<Slot>, DecodeError> {
+        self.repeated(field).map(|value| value.varint(field)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames written by the current version of the protocol
+    fn golden() -> Vec<(&'static str, Command)> {
+        vec![
+            (include_str!("../testdata/codec/v2/proposal.hex"), Command::Proposal {
+                payload: "hello".into(),
+            }),
+            (include_str!("../testdata/codec/v2/prepare.hex"), Command::Prepare {
+                payload: (Ballot(3, 1), 0),
+            }),
+            (include_str!("../testdata/codec/v2/prepare_decided.hex"), Command::Prepare {
+                payload: (Ballot(3, 1), 42),
+            }),
+            (include_str!("../testdata/codec/v2/promise.hex"), Command::Promise {
+                payload: (2, Ballot(3, 1), vec![(5, Ballot(2, 0), "foo".into())]),
+            }),
+            (include_str!("../testdata/codec/v2/accept.hex"), Command::Accept {
+                payload: (Ballot(3, 1), vec![(5, "foo".into()), (6, "bar".into())]),
+            }),
+            (include_str!("../testdata/codec/v2/reject.hex"), Command::Reject {
+                payload: (2, Ballot(3, 1), Ballot(4, 2)),
+            }),
+            (include_str!("../testdata/codec/v2/accepted.hex"), Command::Accepted {
+                payload: (2, Ballot(3, 1), vec![5, 6]),
+            }),
+            (include_str!("../testdata/codec/v2/resolution.hex"), Command::Resolution {
+                payload: (Ballot(3, 1), vec![(5, "foo".into())]),
+            }),
+            (include_str!("../testdata/codec/v2/catchup.hex"), Command::Catchup {
+                payload: (2, vec![5..6, 300..302]),
+            }),
+            (include_str!("../testdata/codec/v2/handover.hex"), Command::Handover {
+                payload: (Ballot(3, 1), 42),
+            }),
+            (include_str!("../testdata/codec/v2/confirm.hex"), Command::Confirm {
+                payload: (Ballot(3, 1), 7),
+            }),
+            (include_str!("../testdata/codec/v2/confirmed.hex"), Command::Confirmed {
+                payload: (2, Ballot(3, 1), 7),
+            }),
+        ]
+    }
+
+    /// Frames written by version 1 of the protocol, which every later
+    /// version must keep decoding
+    fn golden_v1() -> Vec<(&'static str, Command)> {
+        vec![
+            (include_str!("../testdata/codec/v1/proposal.hex"), Command::Proposal {
+                payload: "hello".into(),
+            }),
+            (include_str!("../testdata/codec/v1/prepare.hex"), Command::Prepare {
+                payload: (Ballot(3, 1), 0),
+            }),
+            (include_str!("../testdata/codec/v1/promise.hex"), Command::Promise {
+                payload: (2, Ballot(3, 1), vec![(5, Ballot(2, 0), "foo".into())]),
+            }),
+            (include_str!("../testdata/codec/v1/accept.hex"), Command::Accept {
+                payload: (Ballot(3, 1), vec![(5, "foo".into()), (6, "bar".into())]),
+            }),
+            (include_str!("../testdata/codec/v1/reject.hex"), Command::Reject {
+                payload: (2, Ballot(3, 1), Ballot(4, 2)),
+            }),
+            (include_str!("../testdata/codec/v1/accepted.hex"), Command::Accepted {
+                payload: (2, Ballot(3, 1), vec![5, 6]),
+            }),
+            (include_str!("../testdata/codec/v1/resolution.hex"), Command::Resolution {
+                payload: (Ballot(3, 1), vec![(5, "foo".into())]),
+            }),
+            (include_str!("../testdata/codec/v1/catchup.hex"), Command::Catchup {
+                payload: (2, vec![5..6, 300..301]),
+            }),
+        ]
+    }
+
+    fn unhex(hex: &str) -> Bytes {
+        let hex = hex.trim();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn metas() -> CommandMetas {
+        CommandMetas::default().with_baggage("trace")
+    }
+
+    /// Decodes frames of commands of `Bytes` values
+    fn decode(frame: Bytes) -> Result<(Command, CommandMetas), DecodeError> {
+        super::decode(frame)
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let commands: Vec<Command> = vec![
+            Command::Proposal { payload: Bytes::new() },
+            Command::Promise { payload: (0, Ballot(0, 0), vec![]) },
+            Command::Accept { payload: (Ballot(u32::MAX, u32::MAX), vec![(u64::MAX, "x".into())]) },
+            Command::Catchup { payload: (1, vec![]) },
+        ];
+        for cmd in commands.into_iter().chain(golden().into_iter().map(|(_, cmd)| cmd)) {
+            let (decoded, cmd_metas) = decode(encode(&cmd, &metas())).unwrap();
+            assert_eq!(cmd, decoded);
+            assert_eq!("trace", cmd_metas.baggage);
+        }
+    }
+
+    #[test]
+    fn codec_round_trip_proposed() {
+        let foo = Proposed { id: ProposalId { node: 2, seq: u64::MAX }, value: "foo".into() };
+        let noop = Proposed { id: ProposalId::default(), value: Bytes::new() };
+        let commands = vec![
+            Command::Proposal { payload: foo.clone() },
+            Command::Promise { payload: (1, Ballot(3, 1), vec![(4, Ballot(2, 0), noop)]) },
+            Command::Accept { payload: (Ballot(3, 1), vec![(5, foo)]) },
+        ];
+        for cmd in commands {
+            let (decoded, _) = super::decode(encode(&cmd, &metas())).unwrap();
+            assert_eq!(cmd, decoded);
+        }
+    }
+
+    #[test]
+    fn codec_round_trip_trace() {
+        let cmd: Command = Command::Prepare { payload: (Ballot(3, 1), 0) };
+        let sent_at = UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456);
+        let metas = CommandMetas {
+            trace_id: u128::MAX - 1,
+            span_id: 9,
+            parent_span_id: 8,
+            origin: Some(2),
+            sent_at: Some(sent_at),
+            deadline: Some(sent_at + Duration::from_secs(1)),
+            baggage: "tenant".into(),
+            cluster: 7,
+            epoch: 2,
+        };
+        let (_, cmd_metas) = decode(encode(&cmd, &metas)).unwrap();
+        assert_eq!(metas, cmd_metas);
+
+        let root = CommandMetas::trace(1, 2);
+        let (_, cmd_metas) = decode(encode(&cmd, &root)).unwrap();
+        assert_eq!(root, cmd_metas);
+    }
+
+    #[test]
+    fn codec_golden_files() {
+        for (hex, cmd) in golden() {
+            let frame = unhex(hex);
+            assert_eq!(frame, encode(&cmd, &metas()), "{:?}", cmd);
+            let (decoded, cmd_metas) = decode(frame).unwrap();
+            assert_eq!(cmd, decoded);
+            assert_eq!("trace", cmd_metas.baggage);
+        }
+    }
+
+    #[test]
+    fn codec_decodes_v1_frames() {
+        for (hex, cmd) in golden_v1() {
+            let (decoded, cmd_metas) = decode(unhex(hex)).unwrap();
+            assert_eq!(cmd, decoded);
+            assert_eq!("trace", cmd_metas.baggage);
+        }
+    }
+
+    #[test]
+    fn codec_skips_unknown_fields() {
+        // a frame of a later version adding a varint, a fixed width and a
+        // nested field to PREPARE, including within the ballot
+        let mut frame = BytesMut::new();
+        frame.put_slice(&[PROTOCOL_VERSION + 1, PREPARE]);
+        frame.put_slice(&[0x20, 0xac, 0x02]);
+        frame.put_slice(&[0x19, 0, 0, 0, 0, 0, 0, 0, 0]);
+        frame.put_slice(&[0x0a, 0x09, 0x08, 0x03, 0x10, 0x01, 0x1a, 0x03]);
+        frame.put_slice(b"new");
+        frame.put_slice(&[0x25, 1, 2, 3, 4]);
+
+        let (cmd, cmd_metas) = decode(frame.freeze()).unwrap();
+        assert_eq!(Command::Prepare { payload: (Ballot(3, 1), 0) }, cmd);
+        assert!(cmd_metas.baggage.is_empty());
+    }
+
+    #[test]
+    fn codec_refuses_invalid_frames() {
+        let prepare = encode::<Bytes>(&Command::Prepare { payload: (Ballot(3, 1), 0) }, &metas());
+
+        assert_eq!(Err(DecodeError::Truncated), decode(Bytes::from_static(&[1])).map(|_| ()));
+        assert_eq!(Err(DecodeError::Truncated), decode(prepare.slice(..5)).map(|_| ()));
+        assert_eq!(
+            Err(DecodeError::UnsupportedVersion(0)),
+            decode(Bytes::from_static(&[0, PREPARE])).map(|_| ())
+        );
+        assert_eq!(
+            Err(DecodeError::UnknownMessage(200)),
+            decode(Bytes::from_static(&[PROTOCOL_VERSION, 200])).map(|_| ())
+        );
+        assert_eq!(
+            Err(DecodeError::MissingField { field: 1 }),
+            decode(Bytes::from_static(&[PROTOCOL_VERSION, PREPARE])).map(|_| ())
+        );
+        assert_eq!(
+            Err(DecodeError::InvalidWireType { field: 1, wire_type: VARINT }),
+            decode(Bytes::from_static(&[PROTOCOL_VERSION, PREPARE, 0x08, 0x01])).map(|_| ())
+        );
+        assert_eq!(
+            Err(DecodeError::InvalidWireType { field: 1, wire_type: 3 }),
+            decode(Bytes::from_static(&[PROTOCOL_VERSION, PREPARE, 0x0b])).map(|_| ())
+        );
+
+        let frame = [PROTOCOL_VERSION, CATCHUP, 0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let frame = Bytes::from([&frame[..], &[0xff; 6]].concat());
+        assert_eq!(Err(DecodeError::InvalidVarint), decode(frame).map(|_| ()));
+
+        // node identifiers do not fit in 32 bits
+        let frame = [PROTOCOL_VERSION, CATCHUP, 0x08, 0x80, 0x80, 0x80, 0x80, 0x10];
+        assert_eq!(
+            Err(DecodeError::InvalidValue { field: 1 }),
+            decode(Bytes::copy_from_slice(&frame)).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn codec_slices_payloads() {
+        let cmd: Command = Command::Accept { payload: (Ballot(3, 1), vec![(5, "foo".into())]) };
+        let frame = encode(&cmd, &metas());
+        let range = frame.as_ptr_range();
+
+        let (decoded, cmd_metas) = decode(frame.clone()).unwrap();
+        let val = match decoded {
+            Command::Accept { payload: (_, mut slot_vals) } => slot_vals.remove(0).1,
+            cmd => panic!("unexpected command {:?}", cmd),
+        };
+        assert!(range.contains(&val.as_ptr()));
+        assert!(range.contains(&cmd_metas.baggage.as_ptr()));
+    }
+}
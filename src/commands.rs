@@ -1,87 +1,122 @@
-use crate::{Ballot, NodeId, NodeMetadata, Slot};
+use crate::{error::Result, Ballot, NodeId, NodeMetadata, Slot, Value};
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 /// Sends commands to other replicas in addition to applying
 /// resolved commands at the current replica
-pub trait Transport {
+pub trait Transport<V = Bytes> {
     /// Send a message to a single node
-    fn send(&mut self, node: NodeId, node_metadata: &NodeMetadata, command: Command, command_metadata: CommandMetas);
+    fn send(&mut self, node: NodeId, node_metadata: &NodeMetadata, command: Command<V>, command_metadata: CommandMetas);
 }
 
 /// Receiver of Paxos commands.
-pub trait Receiver {
-    /// Receives a command and reacts accordingly
-    fn receive(&mut self, command: Command, cmd_metas: CommandMetas);
+pub trait Receiver<V = Bytes> {
+    /// Receives a command and reacts accordingly. Commands naming unknown
+    /// peers, slots outside of the window or violating the protocol are
+    /// refused with an error.
+    fn receive(&mut self, command: Command<V>, cmd_metas: CommandMetas) -> Result<()>;
 }
 
 /// Receiver of Paxos commands.
 ///
 /// This is a convenience trait that breaks out reactors for each command.
-pub trait Commander {
+pub trait Commander<V: Value = Bytes> {
     /// Receive a proposal
-    fn proposal(&mut self, val: Bytes, cmd_metas: CommandMetas);
+    fn proposal(&mut self, val: V, cmd_metas: CommandMetas) -> Result<()>;
 
     /// Receive a Phase 1a PREPARE message containing the proposed ballot
-    fn prepare(&mut self, bal: Ballot, cmd_metas: CommandMetas);
+    /// and the first slot that the candidate has not learned the decision of
+    fn prepare(&mut self, bal: Ballot, decided: Slot, cmd_metas: CommandMetas) -> Result<()>;
 
     /// Receive a Phase 1b PROMISE message containing the node
     /// that generated the promise, the ballot promised and all accepted
     /// values within the open window.
-    fn promise(&mut self, node: NodeId, bal: Ballot, accepted: Vec<(Slot, Ballot, Bytes)>, cmd_metas: CommandMetas);
+    fn promise(&mut self, node: NodeId, bal: Ballot, accepted: Vec<(Slot, Ballot, V)>, cmd_metas: CommandMetas) -> Result<()>;
 
     /// Receive a Phase 2a ACCEPT message that contains the the slot, proposed
     /// ballot and value of the proposal. The ballot contains the node of
     /// the leader of the slot.
-    fn accept(&mut self, bal: Ballot, slot_values: Vec<(Slot, Bytes)>, cmd_metas: CommandMetas);
+    fn accept(&mut self, bal: Ballot, slot_values: Vec<(Slot, V)>, cmd_metas: CommandMetas) -> Result<()>;
 
     /// Receives a REJECT message from a peer containing a higher ballot that
     /// preempts either a Phase 1a (PREPARE) for Phase 2a (ACCEPT) message.
-    fn reject(&mut self, node: NodeId, proposed: Ballot, preempted: Ballot, cmd_metas: CommandMetas);
+    fn reject(&mut self, node: NodeId, proposed: Ballot, preempted: Ballot, cmd_metas: CommandMetas) -> Result<()>;
 
     /// Receives a Phase 2b ACCEPTED message containing the acceptor that has
     /// accepted the slot's proposal along with the ballot that generated
     /// the slot.
-    fn accepted(&mut self, node: NodeId, bal: Ballot, slots: Vec<Slot>, cmd_metas: CommandMetas);
+    fn accepted(&mut self, node: NodeId, bal: Ballot, slots: Vec<Slot>, cmd_metas: CommandMetas) -> Result<()>;
 
     /// Receives a final resolution of a slot that has been accepted by a
     /// majority of acceptors.
     ///
     /// NOTE: Resolutions may arrive out-of-order. No guarantees are made on
     /// slot order.
-    fn resolution(&mut self, bal: Ballot, values: Vec<(Slot, Bytes)>, cmd_metas: CommandMetas);
-
-    /// Request sent to a distinguished learner to catch up to latest slot
-    /// values.
-    fn catchup(&mut self, node: NodeId, slots: Vec<Slot>, cmd_metas: CommandMetas);
-}
-
-impl<T: Commander> Receiver for T {
-    fn receive(&mut self, command: Command, cmd_metas: CommandMetas) {
+    fn resolution(&mut self, bal: Ballot, values: Vec<(Slot, V)>, cmd_metas: CommandMetas) -> Result<()>;
+
+    /// Request sent to a peer to catch up on the decided values of ranges
+    /// of slots.
+    fn catchup(&mut self, node: NodeId, slots: Vec<Range<Slot>>, cmd_metas: CommandMetas) -> Result<()>;
+
+    /// Receives an offer from the leader holding the ballot to take over
+    /// leadership once the slots preceding the given slot are decided.
+    fn handover(&mut self, bal: Ballot, decided: Slot, cmd_metas: CommandMetas) -> Result<()>;
+
+    /// Receives a request from the leader holding the ballot to confirm
+    /// that no higher ballot was promised, on behalf of a read.
+    fn confirm(&mut self, bal: Ballot, read: u64, cmd_metas: CommandMetas) -> Result<()>;
+
+    /// Receives the confirmation of a node that it promised no ballot
+    /// higher than the leader's ballot, on behalf of a read.
+    fn confirmed(
+        &mut self,
+        node: NodeId,
+        bal: Ballot,
+        read: u64,
+        cmd_metas: CommandMetas,
+    ) -> Result<()>;
+
+    /// Invokes the reactor matching the command. Implementors forward
+    /// `Receiver::receive` to this method.
+    fn dispatch(&mut self, command: Command<V>, cmd_metas: CommandMetas) -> Result<()> {
         match command {
             Command::Proposal { payload: val } => {
-                self.proposal(val, cmd_metas);
+                self.proposal(val, cmd_metas)
             }
-            Command::Prepare { payload: bal } => {
-                self.prepare(bal, cmd_metas);
+            Command::Prepare { payload: (bal, decided) } => {
+                self.prepare(bal, decided, cmd_metas)
             }
             Command::Promise { payload: (node, bal, accepted)} => {
-                self.promise(node, bal, accepted, cmd_metas);
+                self.promise(node, bal, accepted, cmd_metas)
             }
             Command::Accept { payload: (bal, slot_vals)} => {
-                self.accept(bal, slot_vals, cmd_metas);
+                self.accept(bal, slot_vals, cmd_metas)
             }
             Command::Reject { payload: (node, proposed, preempted)} => {
-                self.reject(node, proposed, preempted, cmd_metas);
+                self.reject(node, proposed, preempted, cmd_metas)
             }
             Command::Accepted { payload: (node, bal, slots)} => {
-                self.accepted(node, bal, slots, cmd_metas);
+                self.accepted(node, bal, slots, cmd_metas)
             }
             Command::Resolution { payload: (bal, slot_vals)} => {
-                self.resolution(bal, slot_vals, cmd_metas);
+                self.resolution(bal, slot_vals, cmd_metas)
             }
             Command::Catchup { payload: (node, slots)} => {
-                self.catchup(node, slots, cmd_metas);
+                self.catchup(node, slots, cmd_metas)
+            }
+            Command::Handover { payload: (bal, decided)} => {
+                self.handover(bal, decided, cmd_metas)
+            }
+            Command::Confirm { payload: (bal, read)} => {
+                self.confirm(bal, read, cmd_metas)
+            }
+            Command::Confirmed { payload: (node, bal, read)} => {
+                self.confirmed(node, bal, read, cmd_metas)
             }
         }
     }
@@ -130,22 +165,24 @@ impl<T: Commander> Receiver for T {
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// RPC commands sent between replicas
 #[serde(tag = "messageName")]
-pub enum Command {
+pub enum Command<V = Bytes> {
     /// Propose a value
-    Proposal { payload: Bytes },
+    Proposal { payload: V },
 
-    /// Phase 1a PREPARE message containing the proposed ballot
-    Prepare { payload: Ballot },
+    /// Phase 1a PREPARE message containing the proposed ballot and the
+    /// first slot that the candidate has not learned the decision of.
+    /// Promises report the decided slots from that slot on.
+    Prepare { payload: (Ballot, Slot) },
 
     /// Phase 1b PROMISE message containing the node
     /// that generated the promise, the ballot promised and all accepted
     /// values within the open window.
-    Promise { payload: (NodeId, Ballot, Vec<(Slot, Ballot, Bytes)>) },
+    Promise { payload: (NodeId, Ballot, Vec<(Slot, Ballot, V)>) },
 
     /// Phase 2a ACCEPT message that contains the the slot, proposed
     /// ballot and value of the proposal. The ballot contains the node of
     /// the leader of the slot.
-    Accept { payload: (Ballot, Vec<(Slot, Bytes)>) },
+    Accept { payload: (Ballot, Vec<(Slot, V)>) },
 
     /// REJECT a peer's previous message containing a higher ballot that
     /// preempts either a Phase 1a (PREPARE) for Phase 2a (ACCEPT) message.
@@ -161,34 +198,176 @@ pub enum Command {
     ///
     /// NOTE: Resolutions may arrive out-of-order. No guarantees are made on
     /// slot order.
-    Resolution { payload: (Ballot, Vec<(Slot, Bytes)>) },
+    Resolution { payload: (Ballot, Vec<(Slot, V)>) },
+
+    /// Request sent to a peer to catch up on the decided values of ranges
+    /// of slots.
+    Catchup { payload: (NodeId, Vec<Range<Slot>>) },
+
+    /// Offer from the leader holding the ballot to a preferred node to take
+    /// over leadership, once the node has decided the slots preceding the
+    /// slot.
+    Handover { payload: (Ballot, Slot) },
+
+    /// Request from the leader holding the ballot to confirm that it still
+    /// leads, on behalf of the read with the identifier.
+    Confirm { payload: (Ballot, u64) },
+
+    /// Confirmation by the node that it promised no ballot higher than the
+    /// leader's ballot, on behalf of the read with the identifier.
+    Confirmed { payload: (NodeId, Ballot, u64) },
+}
+
+/// Tracing context carried along with every command.
+///
+/// A client starts a trace with `CommandMetas::trace`. Commands that a
+/// replica sends in reaction to a command continue its trace with a new
+/// span, whose parent is the span of the command received. The PREPARE,
+/// ACCEPT, ACCEPTED and RESOLUTION messages caused by a proposal therefore
+/// share the trace of the proposal on every replica.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandMetas {
+    /// Identifier of the trace, zero for commands that are not traced
+    pub trace_id: u128,
+    /// Identifier of the span of the command, zero when not traced
+    pub span_id: u64,
+    /// Span of the command that caused this one, zero for the root
+    pub parent_span_id: u64,
+    /// Node that sent the command, `None` when sent by a client
+    pub origin: Option<NodeId>,
+    /// Time at which the command was sent
+    pub sent_at: Option<SystemTime>,
+    /// Time after which the client no longer waits for the outcome
+    pub deadline: Option<SystemTime>,
+    /// Application defined data propagated along with the trace
+    pub baggage: Bytes,
+    /// Cluster of the node that sent the command, zero for clients
+    #[serde(default)]
+    pub cluster: u64,
+    /// Epoch of the membership of the node that sent the command
+    #[serde(default)]
+    pub epoch: u64,
+}
+
+impl CommandMetas {
+    /// Starts a trace with a root span
+    pub fn trace(trace_id: u128, span_id: u64) -> CommandMetas {
+        CommandMetas { trace_id, span_id, ..CommandMetas::default() }
+    }
+
+    /// Parses a W3C `traceparent` header. The span of the header becomes
+    /// the span of the command.
+    pub fn from_traceparent(header: &str) -> Option<CommandMetas> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next().filter(|v| v.len() == 2 && *v != "ff")?;
+        let trace_id = parts.next().filter(|t| t.len() == 32)?;
+        let span_id = parts.next().filter(|s| s.len() == 16)?;
+        let flags = parts.next().filter(|f| f.len() == 2)?;
+        // later versions may append fields
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        u8::from_str_radix(flags, 16).ok()?;
+
+        let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|id| *id != 0)?;
+        let span_id = u64::from_str_radix(span_id, 16).ok().filter(|id| *id != 0)?;
+        Some(CommandMetas::trace(trace_id, span_id))
+    }
+
+    /// W3C `traceparent` header of the span, or `None` when not traced
+    pub fn traceparent(&self) -> Option<String> {
+        if !self.is_traced() {
+            return None;
+        }
+        let mut header = String::with_capacity(55);
+        write!(header, "00-{:032x}-{:016x}-01", self.trace_id, self.span_id).unwrap();
+        Some(header)
+    }
+
+    /// Sets the time after which the client no longer waits for the outcome
+    pub fn with_deadline(mut self, deadline: SystemTime) -> CommandMetas {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the application defined data propagated along with the trace
+    pub fn with_baggage<B: Into<Bytes>>(mut self, baggage: B) -> CommandMetas {
+        self.baggage = baggage.into();
+        self
+    }
+
+    /// Flag indicating whether the command belongs to a trace
+    pub fn is_traced(&self) -> bool {
+        self.trace_id != 0
+    }
+
+    /// Metadata of a command sent by `origin` in reaction to this one. The
+    /// trace, deadline and baggage are kept, while the cluster and epoch are
+    /// set by the sending node.
+    pub fn child(&self, origin: NodeId, span_id: u64) -> CommandMetas {
+        let traced = self.is_traced();
+        CommandMetas {
+            trace_id: self.trace_id,
+            span_id: if traced { span_id } else { 0 },
+            parent_span_id: if traced { self.span_id } else { 0 },
+            origin: Some(origin),
+            sent_at: Some(SystemTime::now()),
+            deadline: self.deadline,
+            baggage: self.baggage.clone(),
+            cluster: 0,
+            epoch: 0,
+        }
+    }
+}
 
-    /// Request sent to a distinguished learner to catch up to latest slot
-    /// values.
-    Catchup { payload: (NodeId, Vec<Slot>) },
+/// Generator of span identifiers for the commands sent by a node
+pub(crate) struct SpanIds {
+    state: u64,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct CommandMetas(pub Bytes);
+impl SpanIds {
+    /// Seeds the generator with the node and the current time, so spans of
+    /// different nodes and restarts do not collide
+    pub(crate) fn new(node: NodeId) -> SpanIds {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        SpanIds { state: (nanos as u64) ^ (u64::from(node) << 32) }
+    }
+
+    /// Next span identifier, never zero
+    pub(crate) fn next(&mut self) -> u64 {
+        // splitmix64
+        loop {
+            self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            if z != 0 {
+                return z;
+            }
+        }
+    }
+}
 
 #[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
 mod tests {
     use super::*;
     #[test]
     fn it_serializes_command_proposal() {
         let json = r#"{"messageName":"Proposal","payload":[]}"#;
 
-        let command = Command::Proposal { payload: "".into() };
+        let command: Command = Command::Proposal { payload: "".into() };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
 
     #[test]
     fn it_serializes_command_prepare() {
-        let json = r#"{"messageName":"Prepare","payload":[123,345]}"#;
+        let json = r#"{"messageName":"Prepare","payload":[[123,345],0]}"#;
         let ballot = Ballot(123_u32, 345_u32);
 
-        let command = Command::Prepare { payload: ballot };
+        let command: Command = Command::Prepare { payload: (ballot, 0) };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
@@ -199,7 +378,7 @@ mod tests {
         let v = vec![(0u64, Ballot(123_u32, 345_u32), "hello".into())];
         let payload = (42, Ballot(123_u32, 345_u32), v);
 
-        let command = Command::Promise { payload };
+        let command: Command = Command::Promise { payload };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
@@ -210,7 +389,7 @@ mod tests {
         let v = vec![(0u64, "hello".into())];
         let payload = (Ballot(123_u32, 345_u32), v);
 
-        let command = Command::Accept { payload };
+        let command: Command = Command::Accept { payload };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
@@ -220,7 +399,7 @@ mod tests {
         let json = r#"{"messageName":"Reject","payload":[13,[123,345],[123,345]]}"#;
         let ballot = Ballot(123_u32, 345_u32);
 
-        let command = Command::Reject { payload: (13, ballot, ballot) };
+        let command: Command = Command::Reject { payload: (13, ballot, ballot) };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
@@ -231,7 +410,7 @@ mod tests {
         let v = vec![15_u64];
         let ballot = Ballot(123_u32, 345_u32);
 
-        let command = Command::Accepted { payload: (13, ballot, v) };
+        let command: Command = Command::Accepted { payload: (13, ballot, v) };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
@@ -242,17 +421,56 @@ mod tests {
         let v = vec![(15_u64, "".into())];
         let ballot = Ballot(123_u32, 345_u32);
 
-        let command = Command::Resolution { payload: (ballot, v) };
+        let command: Command = Command::Resolution { payload: (ballot, v) };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
 
+    #[test]
+    fn command_metas_child() {
+        let root = CommandMetas::trace(7, 3).with_baggage("tenant");
+        let child = root.child(2, 11);
+        assert_eq!(7, child.trace_id);
+        assert_eq!(11, child.span_id);
+        assert_eq!(3, child.parent_span_id);
+        assert_eq!(Some(2), child.origin);
+        assert!(child.sent_at.is_some());
+        assert_eq!("tenant", child.baggage);
+
+        let untraced = CommandMetas::default().child(2, 11);
+        assert!(!untraced.is_traced());
+        assert_eq!((0, 0), (untraced.span_id, untraced.parent_span_id));
+    }
+
+    #[test]
+    fn command_metas_traceparent() {
+        let header = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let metas = CommandMetas::from_traceparent(header).unwrap();
+        assert_eq!(0x0af7651916cd43dd8448eb211c80319c, metas.trace_id);
+        assert_eq!(0xb7ad6b7169203331, metas.span_id);
+        assert_eq!(Some(header.to_string()), metas.traceparent());
+
+        let short = "00-0af7651916cd43dd-b7ad6b7169203331-01";
+        assert!(CommandMetas::from_traceparent(short).is_none());
+        let zero = "00-00000000000000000000000000000000-b7ad6b7169203331-01";
+        assert!(CommandMetas::from_traceparent(zero).is_none());
+        assert_eq!(None, CommandMetas::default().traceparent());
+    }
+
+    #[test]
+    fn span_ids() {
+        let mut spans = SpanIds::new(1);
+        let first = spans.next();
+        assert_ne!(first, spans.next());
+        assert_ne!(0, first);
+    }
+
     #[test]
     fn it_serializes_command_catchup() {
-        let json = r#"{"messageName":"Catchup","payload":[16,[444]]}"#;
-        let v = vec![444_u64];
+        let json = r#"{"messageName":"Catchup","payload":[16,[{"start":444,"end":446}]]}"#;
+        let v = vec![444_u64..446];
 
-        let command = Command::Catchup { payload: (16, v) };
+        let command: Command = Command::Catchup { payload: (16, v) };
         let serialized_command = serde_json::to_string(&command).unwrap();
         assert_eq!(&serialized_command, json);
     }
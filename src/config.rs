@@ -1,7 +1,10 @@
-use crate::NodeId;
+use crate::{auth::Authenticator, NodeId};
 use bytes::Bytes;
 use std::{collections::HashMap, fmt, ops::Index};
 
+/// Heartbeats within which a leader must hear from a phase 2 quorum
+const DEFAULT_ELECTION_INTERVAL: u32 = 10;
+
 #[derive(Default, Clone, Debug, Eq, PartialEq)]
 /// Opaque, applicaiton specific metadata for nodes in the system
 pub struct NodeMetadata(pub Bytes);
@@ -12,9 +15,9 @@ impl From<Bytes> for NodeMetadata {
     }
 }
 
-impl Into<Bytes> for NodeMetadata {
-    fn into(self) -> Bytes {
-        self.0
+impl From<NodeMetadata> for Bytes {
+    fn from(val: NodeMetadata) -> Self {
+        val.0
     }
 }
 
@@ -23,6 +26,14 @@ impl Into<Bytes> for NodeMetadata {
 pub struct Configuration {
     current: NodeId,
     peers: HashMap<NodeId, NodeMetadata>,
+    cluster: u64,
+    epoch: u64,
+    keys: Vec<Bytes>,
+    election_interval: u32,
+    priorities: HashMap<NodeId, u32>,
+    zones: HashMap<NodeId, String>,
+    preferred_zone: Option<String>,
+    seed: Option<u64>,
 }
 
 impl Configuration {
@@ -32,7 +43,117 @@ impl Configuration {
         I: Iterator<Item = (NodeId, NodeMetadata)>,
     {
         let peers: HashMap<NodeId, NodeMetadata> = peers.collect();
-        Configuration { current, peers }
+        Configuration {
+            current,
+            peers,
+            cluster: 0,
+            epoch: 0,
+            keys: Vec::new(),
+            election_interval: DEFAULT_ELECTION_INTERVAL,
+            priorities: HashMap::new(),
+            zones: HashMap::new(),
+            preferred_zone: None,
+            seed: None,
+        }
+    }
+
+    /// Sets the identifier of the cluster and the epoch of the membership.
+    /// Messages from other clusters or epochs are refused.
+    pub fn with_cluster(mut self, cluster: u64, epoch: u64) -> Configuration {
+        self.cluster = cluster;
+        self.epoch = epoch;
+        self
+    }
+
+    /// Adds a key shared by the cluster to authenticate messages. Messages
+    /// are signed with the first key and accepted when signed by any key,
+    /// which allows keys to be rotated.
+    #[cfg(feature = "auth")]
+    pub fn with_key<K: Into<Bytes>>(mut self, key: K) -> Configuration {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// Sets the number of heartbeats, sent through
+    /// `Replica::propose_leadership`, within which a leader must hear from a
+    /// phase 2 quorum. A leader that does not steps down.
+    pub fn with_election_interval(mut self, heartbeats: u32) -> Configuration {
+        self.election_interval = heartbeats.max(1);
+        self
+    }
+
+    /// Number of heartbeats within which a leader must hear from a quorum
+    pub fn election_interval(&self) -> u32 {
+        self.election_interval
+    }
+
+    /// Sets the election priority of a node, which may be the current node.
+    /// Nodes do not run for leadership while a healthy node of higher
+    /// priority exists, and leaders hand over to such nodes. Nodes have a
+    /// priority of 0 by default.
+    pub fn with_priority(mut self, node: NodeId, priority: u32) -> Configuration {
+        self.priorities.insert(node, priority);
+        self
+    }
+
+    /// Labels a node, which may be the current node, with the zone it runs in
+    pub fn with_zone<Z: Into<String>>(mut self, node: NodeId, zone: Z) -> Configuration {
+        self.zones.insert(node, zone.into());
+        self
+    }
+
+    /// Sets the zone in which leadership should live. Nodes of the zone are
+    /// preferred as leader over the nodes of other zones, whatever their
+    /// priority.
+    pub fn with_preferred_zone<Z: Into<String>>(mut self, zone: Z) -> Configuration {
+        self.preferred_zone = Some(zone.into());
+        self
+    }
+
+    /// Seeds the randomized backoff between preempted elections, which is
+    /// otherwise seeded from the clock. Simulations seed it to replay runs.
+    pub fn with_seed(mut self, seed: u64) -> Configuration {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Seed of the randomized election backoff, if set
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Election priority of a node
+    pub fn priority(&self, node: NodeId) -> u32 {
+        self.priorities.get(&node).copied().unwrap_or_default()
+    }
+
+    /// Zone of a node, if labeled
+    pub fn zone(&self, node: NodeId) -> Option<&str> {
+        self.zones.get(&node).map(String::as_str)
+    }
+
+    /// Ordering of the nodes as leader, by zone and then priority
+    pub(crate) fn preference(&self, node: NodeId) -> (bool, u32) {
+        let in_preferred_zone = match self.preferred_zone {
+            Some(ref zone) => self.zone(node) == Some(zone.as_str()),
+            None => false,
+        };
+        (in_preferred_zone, self.priority(node))
+    }
+
+    /// Identifier of the cluster
+    pub fn cluster(&self) -> u64 {
+        self.cluster
+    }
+
+    /// Epoch of the membership of the cluster
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Signs and verifies messages for the cluster and epoch
+    pub fn authenticator(&self) -> Authenticator {
+        Authenticator::new(self.cluster, self.epoch, self.keys.clone())
     }
 
     /// Size of phase 1 and phase 2 quorums.
@@ -52,8 +173,13 @@ impl Configuration {
         self.peers.keys().copied()
     }
 
+    /// Metadata of a peer, or `None` if the node is not a peer
+    pub fn peer(&self, node: NodeId) -> Option<&NodeMetadata> {
+        self.peers.get(&node)
+    }
+
     /// Iterator containing all nodes along with their metadata
-    pub fn peers<'a>(&'a self) -> impl Iterator<Item = (NodeId, &NodeMetadata)> + 'a {
+    pub fn peers<'a>(&'a self) -> impl Iterator<Item = (NodeId, &'a NodeMetadata)> + 'a {
         self.peers.iter().map(|(id, meta)| (*id, meta))
     }
 }
@@ -71,6 +197,14 @@ impl fmt::Debug for Configuration {
         fmt.debug_struct("Configuration")
             .field("current_node_id", &self.current)
             .field("peers", &self.peers)
+            .field("cluster", &self.cluster)
+            .field("epoch", &self.epoch)
+            .field("authenticated", &!self.keys.is_empty())
+            .field("election_interval", &self.election_interval)
+            .field("priorities", &self.priorities)
+            .field("zones", &self.zones)
+            .field("preferred_zone", &self.preferred_zone)
+            .field("seed", &self.seed)
             .field("phase_1_quorum", &p1_q)
             .field("phase_2_quorum", &p2_q)
             .finish()
@@ -139,6 +273,11 @@ impl QuorumSet {
         self.search(n).is_ok()
     }
 
+    /// Number of nodes in the set
+    pub fn count(&self) -> usize {
+        self.values.iter().take_while(|n| n.is_some()).count()
+    }
+
     /// Flag indicating whether the set is empty
     #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
@@ -149,7 +288,6 @@ impl QuorumSet {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use test;
 
     #[test]
     fn quorumset() {
@@ -176,6 +314,7 @@ mod tests {
         assert!(!qs.has_quorum());
         assert!(!qs.is_empty());
         assert_eq!(&[Some(5), Some(7), None, None], qs.values.as_ref());
+        assert_eq!(2, qs.count());
 
         qs.insert(2);
         assert!(qs.contains(5));
@@ -198,6 +337,24 @@ mod tests {
         assert_eq!(&[Some(5), Some(7), Some(2), Some(10)], qs.values.as_ref());
     }
 
+    #[test]
+    fn config_preference() {
+        let config = Configuration::new(0, (1..4).map(|n| (n, NodeMetadata::default())))
+            .with_priority(1, 5)
+            .with_priority(2, 1)
+            .with_zone(2, "us-east")
+            .with_zone(3, "eu-west");
+        assert_eq!(0, config.priority(0));
+        assert_eq!(Some("us-east"), config.zone(2));
+        assert_eq!(None, config.zone(1));
+        assert!(config.preference(1) > config.preference(2));
+        assert_eq!(config.preference(0), config.preference(3));
+
+        let config = config.with_preferred_zone("us-east");
+        assert!(config.preference(2) > config.preference(1));
+        assert!(config.preference(1) > config.preference(3));
+    }
+
     #[test]
     fn quorum_one() {
         let mut qs = QuorumSet::with_size(1);
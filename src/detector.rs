@@ -0,0 +1,265 @@
+//! Liveness of peers as perceived from the messages they send.
+//!
+//! A `Node` notes the arrival of every command from a peer with its
+//! `FailureDetector`. The node consults the detector before forwarding a
+//! proposal: a suspected leader is not sent proposals, and the node starts
+//! Phase 1 itself instead.
+//!
+//! `FixedTimeout` suspects peers that have been silent for longer than a
+//! timeout. `PhiAccrual` adapts to the observed arrival intervals of each
+//! peer and suspects it once a further delay becomes too unlikely.
+use crate::NodeId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Judges whether peers are alive from the arrival times of their messages
+pub trait FailureDetector: Send {
+    /// A message from the peer arrived
+    fn arrived(&mut self, node: NodeId, at: Instant);
+
+    /// Indicator of a peer suspected to have failed. Peers that have not
+    /// been heard from yet are not suspected.
+    fn suspects(&self, node: NodeId, at: Instant) -> bool;
+}
+
+/// Detector that never suspects a peer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoFailureDetector;
+
+impl FailureDetector for NoFailureDetector {
+    fn arrived(&mut self, _node: NodeId, _at: Instant) {}
+
+    fn suspects(&self, _node: NodeId, _at: Instant) -> bool {
+        false
+    }
+}
+
+/// Suspects peers that have not sent a message within a fixed timeout
+#[derive(Clone, Debug)]
+pub struct FixedTimeout {
+    timeout: Duration,
+    last_arrival: HashMap<NodeId, Instant>,
+}
+
+impl FixedTimeout {
+    /// Detector suspecting peers silent for longer than `timeout`
+    pub fn new(timeout: Duration) -> FixedTimeout {
+        FixedTimeout { timeout, last_arrival: HashMap::new() }
+    }
+}
+
+impl FailureDetector for FixedTimeout {
+    fn arrived(&mut self, node: NodeId, at: Instant) {
+        self.last_arrival.insert(node, at);
+    }
+
+    fn suspects(&self, node: NodeId, at: Instant) -> bool {
+        match self.last_arrival.get(&node) {
+            Some(last) => at.saturating_duration_since(*last) > self.timeout,
+            None => false,
+        }
+    }
+}
+
+/// Arrival intervals of a peer, in milliseconds
+#[derive(Clone, Debug)]
+struct History {
+    last_arrival: Instant,
+    intervals: VecDeque<f64>,
+    sum: f64,
+    squared_sum: f64,
+}
+
+impl History {
+    fn push(&mut self, interval: f64, max_samples: usize) {
+        if self.intervals.len() == max_samples {
+            if let Some(oldest) = self.intervals.pop_front() {
+                self.sum -= oldest;
+                self.squared_sum -= oldest * oldest;
+            }
+        }
+        self.intervals.push_back(interval);
+        self.sum += interval;
+        self.squared_sum += interval * interval;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.intervals.len() as f64
+    }
+
+    fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        (self.squared_sum / self.intervals.len() as f64 - mean * mean).max(0.0).sqrt()
+    }
+}
+
+/// Phi accrual failure detector, as described by Hayashibara et al.
+///
+/// Arrival intervals are assumed to be normally distributed. The suspicion
+/// level `phi` of a peer grows with the time elapsed since its last message,
+/// and the peer is suspected once `phi` exceeds the threshold. A threshold
+/// of 8 wrongly suspects a peer about once in 10^8 intervals.
+#[derive(Clone, Debug)]
+pub struct PhiAccrual {
+    threshold: f64,
+    max_samples: usize,
+    min_std_dev: Duration,
+    acceptable_pause: Duration,
+    histories: HashMap<NodeId, History>,
+}
+
+impl Default for PhiAccrual {
+    fn default() -> PhiAccrual {
+        PhiAccrual {
+            threshold: 8.0,
+            max_samples: 200,
+            min_std_dev: Duration::from_millis(100),
+            acceptable_pause: Duration::from_millis(0),
+            histories: HashMap::new(),
+        }
+    }
+}
+
+impl PhiAccrual {
+    /// Sets the level of suspicion above which a peer is suspected
+    pub fn threshold(mut self, threshold: f64) -> PhiAccrual {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets the number of arrival intervals kept for each peer
+    pub fn max_samples(mut self, max_samples: usize) -> PhiAccrual {
+        self.max_samples = max_samples.max(1);
+        self
+    }
+
+    /// Sets a lower bound to the deviation of arrival intervals, which
+    /// avoids suspecting peers that send at a very regular rate as soon as
+    /// a message is late
+    pub fn min_std_dev(mut self, min_std_dev: Duration) -> PhiAccrual {
+        self.min_std_dev = min_std_dev;
+        self
+    }
+
+    /// Sets a delay added to the mean arrival interval, tolerating pauses
+    /// such as garbage collection
+    pub fn acceptable_pause(mut self, acceptable_pause: Duration) -> PhiAccrual {
+        self.acceptable_pause = acceptable_pause;
+        self
+    }
+
+    /// Level of suspicion of a peer. Zero until two messages arrived.
+    pub fn phi(&self, node: NodeId, at: Instant) -> f64 {
+        let history = match self.histories.get(&node) {
+            Some(history) if !history.intervals.is_empty() => history,
+            _ => return 0.0,
+        };
+
+        let elapsed = millis(at.saturating_duration_since(history.last_arrival));
+        let mean = history.mean() + millis(self.acceptable_pause);
+        let std_dev = history.std_dev().max(millis(self.min_std_dev));
+
+        // logistic approximation of the cumulative normal distribution
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070_566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+impl FailureDetector for PhiAccrual {
+    fn arrived(&mut self, node: NodeId, at: Instant) {
+        let max_samples = self.max_samples;
+        match self.histories.get_mut(&node) {
+            Some(history) => {
+                let interval = millis(at.saturating_duration_since(history.last_arrival));
+                history.push(interval, max_samples);
+                history.last_arrival = at;
+            }
+            None => {
+                let history = History {
+                    last_arrival: at,
+                    intervals: VecDeque::new(),
+                    sum: 0.0,
+                    squared_sum: 0.0,
+                };
+                self.histories.insert(node, history);
+            }
+        }
+    }
+
+    fn suspects(&self, node: NodeId, at: Instant) -> bool {
+        self.phi(node, at) > self.threshold
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_timeout() {
+        let start = Instant::now();
+        let mut detector = FixedTimeout::new(Duration::from_secs(1));
+        assert!(!detector.suspects(1, start + Duration::from_secs(10)));
+
+        detector.arrived(1, start);
+        assert!(!detector.suspects(1, start + Duration::from_millis(1000)));
+        assert!(detector.suspects(1, start + Duration::from_millis(1001)));
+
+        detector.arrived(1, start + Duration::from_secs(2));
+        assert!(!detector.suspects(1, start + Duration::from_millis(2500)));
+        assert!(!detector.suspects(2, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn phi_accrual() {
+        let start = Instant::now();
+        let mut detector = PhiAccrual::default();
+        detector.arrived(1, start);
+        assert_eq!(0.0, detector.phi(1, start + Duration::from_secs(60)));
+
+        // regular arrivals every second
+        for i in 1..=10 {
+            detector.arrived(1, start + Duration::from_secs(i));
+        }
+        let last = start + Duration::from_secs(10);
+        let on_time = detector.phi(1, last + Duration::from_millis(1000));
+        let late = detector.phi(1, last + Duration::from_millis(1300));
+        assert!(on_time < 1.0, "phi {}", on_time);
+        assert!(late > on_time);
+        assert!(!detector.suspects(1, last + Duration::from_millis(1300)));
+        assert!(detector.suspects(1, last + Duration::from_secs(3)));
+
+        // a pause is tolerated when acceptable
+        let detector = detector.acceptable_pause(Duration::from_secs(3));
+        assert!(!detector.suspects(1, last + Duration::from_secs(3)));
+        assert!(!detector.suspects(2, last + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn phi_accrual_forgets_old_intervals() {
+        let start = Instant::now();
+        let mut detector = PhiAccrual::default().max_samples(3);
+        let mut at = start;
+        for _ in 0..10 {
+            at += Duration::from_secs(10);
+            detector.arrived(1, at);
+        }
+        // the peer now sends more often, which soon becomes the norm
+        for _ in 0..3 {
+            at += Duration::from_secs(1);
+            detector.arrived(1, at);
+        }
+        assert!(detector.suspects(1, at + Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,322 @@
+//! Asynchronous driver that runs a replica on the tokio runtime.
+//!
+//! The `Driver` owns the replica, so applications no longer need to wrap
+//! it in a lock. Commands from peers are fed in through an mpsc channel,
+//! leader heartbeats are driven from a timer and proposals are submitted
+//! through a cloneable `Handle`.
+use crate::{
+    commands::{Command, CommandMetas},
+    PaxosError, ProposalId, ProposalIds, Proposed, Replica, Slot, Value,
+};
+use bytes::Bytes;
+use std::{collections::HashMap, error, fmt, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::interval,
+};
+
+/// Default period between driver ticks
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Result of an operation against the driver
+pub type Result<T> = std::result::Result<T, DriverError>;
+
+/// Errors surfaced by the driver's handle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError {
+    /// The driver has stopped and is no longer accepting requests
+    Stopped,
+    /// The replica refused the proposal
+    Refused(PaxosError),
+    /// The replica gave up on the proposal, which may still be decided
+    Unknown(PaxosError),
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::Stopped => write!(fmt, "paxos driver has stopped"),
+            DriverError::Refused(e) => write!(fmt, "proposal refused: {}", e),
+            DriverError::Unknown(e) => write!(fmt, "outcome of proposal unknown: {}", e),
+        }
+    }
+}
+
+impl error::Error for DriverError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DriverError::Stopped => None,
+            DriverError::Refused(e) | DriverError::Unknown(e) => Some(e),
+        }
+    }
+}
+
+/// Channel completing a proposal with its decided slot
+type SlotSender = oneshot::Sender<Result<Slot>>;
+
+/// Owns a replica and reacts to incoming commands, proposals and timer ticks.
+///
+/// Values are decided along with the identity of their proposal, so that
+/// proposals of equal values made on other nodes are told apart.
+pub struct Driver<R, V = Bytes> {
+    replica: R,
+    commands: mpsc::Receiver<(Command<Proposed<V>>, CommandMetas)>,
+    proposals: mpsc::UnboundedReceiver<(V, CommandMetas, SlotSender)>,
+    decisions: mpsc::UnboundedSender<(Slot, V)>,
+    ids: ProposalIds,
+    /// Proposals submitted through a `Handle` awaiting their decided slot
+    pending: HashMap<ProposalId, SlotSender>,
+    next_decision: Slot,
+    tick_interval: Duration,
+}
+
+impl<V: Value, R: Replica<Proposed<V>>> Driver<R, V> {
+    /// Creates a driver for a replica that consumes peer commands from the
+    /// channel. The returned `Handle` submits proposals and `Decisions`
+    /// yields every slot decided by the replica in order.
+    pub fn new(
+        replica: R,
+        commands: mpsc::Receiver<(Command<Proposed<V>>, CommandMetas)>,
+    ) -> (Driver<R, V>, Handle<V>, Decisions<V>) {
+        let (proposal_sender, proposals) = mpsc::unbounded_channel();
+        let (decision_sender, decisions) = mpsc::unbounded_channel();
+        let driver = Driver {
+            ids: ProposalIds::new(replica.node()),
+            replica,
+            commands,
+            proposals,
+            decisions: decision_sender,
+            pending: HashMap::new(),
+            next_decision: 0,
+            tick_interval: DEFAULT_TICK_INTERVAL,
+        };
+        (driver, Handle { proposals: proposal_sender }, Decisions { receiver: decisions })
+    }
+
+    /// Sets the period between ticks. Leaders send heartbeats on each tick,
+    /// and nodes holding proposals without a leader run for leadership.
+    pub fn tick_interval(mut self, tick_interval: Duration) -> Driver<R, V> {
+        self.tick_interval = tick_interval;
+        self
+    }
+
+    /// Runs the driver until both the command channel and every `Handle`
+    /// have been dropped.
+    pub async fn run(mut self) {
+        let mut ticks = interval(self.tick_interval);
+        let mut commands_open = true;
+        let mut proposals_open = true;
+
+        while commands_open || proposals_open {
+            tokio::select! {
+                cmd = self.commands.recv(), if commands_open => match cmd {
+                    Some((cmd, cmd_metas)) => {
+                        if let Err(e) = self.replica.receive(cmd, cmd_metas) {
+                            warn!("Refused command from peer: {}", e);
+                        }
+                    }
+                    None => commands_open = false,
+                },
+                proposal = self.proposals.recv(), if proposals_open => match proposal {
+                    Some((value, cmd_metas, sender)) => {
+                        let id = self.ids.next();
+                        let proposal = Command::Proposal { payload: Proposed { id, value } };
+                        match self.replica.receive(proposal, cmd_metas) {
+                            Ok(()) => {
+                                self.pending.insert(id, sender);
+                            }
+                            Err(e) => sender.send(Err(DriverError::Refused(e))).unwrap_or(()),
+                        }
+                    }
+                    None => proposals_open = false,
+                },
+                _ = ticks.tick() => self.tick(),
+            }
+
+            self.publish_decisions();
+            self.fail_proposals();
+        }
+    }
+
+    fn tick(&mut self) {
+        // keep followers aware of the leader's ballot, and keep running for
+        // leadership while holding proposals, which counts down the backoff
+        // after preempted elections
+        if self.replica.is_leader() || self.replica.queued_proposals() > 0 {
+            self.replica.propose_leadership(CommandMetas::default());
+        }
+
+        // callers that have given up on their proposal no longer need tracking
+        self.pending.retain(|_, sender| !sender.is_closed());
+    }
+
+    /// Sends newly decided slots to the decision stream and completes the
+    /// proposals decided in them.
+    fn publish_decisions(&mut self) {
+        let decided = self.replica.decisions().range(self.next_decision..).collect::<Vec<_>>();
+        for (slot, Proposed { id, value }) in decided {
+            self.next_decision = slot + 1;
+
+            if let Some(sender) = self.pending.remove(&id) {
+                sender.send(Ok(slot)).unwrap_or(());
+            }
+
+            // the application may not be interested in the decision stream
+            self.decisions.send((slot, value)).unwrap_or(());
+        }
+    }
+
+    /// Completes the proposals that the replica gave up on with an error.
+    /// Their ACCEPT may have been sent already, so their outcome is unknown.
+    fn fail_proposals(&mut self) {
+        for Proposed { id, .. } in self.replica.take_failed_proposals() {
+            if let Some(sender) = self.pending.remove(&id) {
+                sender.send(Err(DriverError::Unknown(PaxosError::QuorumLost))).unwrap_or(());
+            }
+        }
+    }
+}
+
+/// Cloneable handle used to submit proposals to a running `Driver`.
+pub struct Handle<V = Bytes> {
+    proposals: mpsc::UnboundedSender<(V, CommandMetas, SlotSender)>,
+}
+
+impl<V> Clone for Handle<V> {
+    fn clone(&self) -> Handle<V> {
+        Handle { proposals: self.proposals.clone() }
+    }
+}
+
+impl<V> Handle<V> {
+    /// Proposes a value, resolving to the slot in which the value was decided.
+    pub async fn propose(&self, value: V, cmd_metas: CommandMetas) -> Result<Slot> {
+        let (sender, receiver) = oneshot::channel();
+        self.proposals.send((value, cmd_metas, sender)).map_err(|_| DriverError::Stopped)?;
+        receiver.await.map_err(|_| DriverError::Stopped)?
+    }
+}
+
+/// Stream of decided slots and their values, in slot order.
+pub struct Decisions<V = Bytes> {
+    receiver: mpsc::UnboundedReceiver<(Slot, V)>,
+}
+
+impl<V> Decisions<V> {
+    /// Next decided slot, or `None` once the driver has stopped
+    pub async fn next(&mut self) -> Option<(Slot, V)> {
+        self.receiver.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        detector::FixedTimeout, rng::Rng, Ballot, Configuration, Node, NodeId, NodeMetadata,
+        Transport,
+    };
+    use std::collections::HashMap;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn driver_decides_proposals() {
+        let node_ids = [0u32, 1, 2];
+        let (senders, receivers): (HashMap<_, _>, Vec<_>) = node_ids
+            .iter()
+            .map(|node| {
+                let (sender, receiver) = mpsc::channel(64);
+                ((*node, sender), (*node, receiver))
+            })
+            .unzip();
+
+        let mut handles = Vec::new();
+        let mut decisions = Vec::new();
+        for (node, receiver) in receivers {
+            let config = Configuration::new(
+                node,
+                node_ids.iter().filter(|n| **n != node).map(|n| (*n, NodeMetadata::default())),
+            );
+            let transport = ChannelTransport(senders.clone());
+            let (driver, handle, decision_stream) =
+                Driver::new(Node::new(transport, config), receiver);
+            tokio::spawn(driver.tick_interval(Duration::from_millis(10)).run());
+            handles.push(handle);
+            decisions.push(decision_stream);
+        }
+
+        let slot = handles[1].propose("foo".into(), CommandMetas::default()).await.unwrap();
+        assert_eq!(0, slot);
+
+        let slot = handles[0].propose("bar".into(), CommandMetas::default()).await.unwrap();
+        assert_eq!(1, slot);
+
+        for decision_stream in decisions.iter_mut() {
+            assert_eq!(Some((0, "foo".into())), decision_stream.next().await);
+            assert_eq!(Some((1, "bar".into())), decision_stream.next().await);
+        }
+
+        // equal values proposed on different nodes complete with their own slot
+        let (first, second) = tokio::join!(
+            handles[1].propose("baz".into(), CommandMetas::default()),
+            handles[2].propose("baz".into(), CommandMetas::default())
+        );
+        let mut slots = vec![first.unwrap(), second.unwrap()];
+        slots.sort_unstable();
+        assert_eq!(vec![2, 3], slots);
+    }
+
+    #[tokio::test]
+    async fn driver_runs_for_leadership_while_backing_off() {
+        let (peer_sender, mut peer) = mpsc::channel(64);
+        let (idle_sender, _idle) = mpsc::channel(64);
+        let transport =
+            ChannelTransport(vec![(1, peer_sender), (2, idle_sender)].into_iter().collect());
+        // seed the backoff so that the preempted election skips two elections
+        let seed = (0..).find(|seed| Rng::new(*seed).range(1..3) == 2).unwrap();
+        let peers = vec![(1, NodeMetadata::default()), (2, NodeMetadata::default())];
+        let config = Configuration::new(0, peers.into_iter()).with_seed(seed);
+        // the preempting node is suspected as soon as it has been heard from
+        let node = Node::new(transport, config)
+            .with_failure_detector(FixedTimeout::new(Duration::from_millis(0)));
+
+        let (commands, receiver) = mpsc::channel(64);
+        let (driver, handle, _decisions) = Driver::new(node, receiver);
+        tokio::spawn(driver.tick_interval(Duration::from_millis(10)).run());
+        tokio::spawn(async move { handle.propose("foo".into(), CommandMetas::default()).await });
+
+        let proposed = match recv(&mut peer).await {
+            Command::Prepare { payload: (bal, _) } => bal,
+            cmd => panic!("unexpected command {:?}", cmd),
+        };
+        let preempted = Ballot(proposed.0 + 1, 1);
+        let reject = Command::Reject { payload: (1, proposed, preempted) };
+        commands.send((reject, CommandMetas::default())).await.unwrap();
+
+        // ticks count down the backoff while the proposal is queued
+        match recv(&mut peer).await {
+            Command::Prepare { payload: (bal, _) } => assert!(bal > preempted),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+    }
+
+    async fn recv(receiver: &mut mpsc::Receiver<Message>) -> Command<Proposed<Bytes>> {
+        timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap().0
+    }
+
+    type Message = (Command<Proposed<Bytes>>, CommandMetas);
+
+    struct ChannelTransport(HashMap<NodeId, mpsc::Sender<Message>>);
+
+    impl Transport<Proposed<Bytes>> for ChannelTransport {
+        fn send(
+            &mut self,
+            node: NodeId,
+            _: &NodeMetadata,
+            cmd: Command<Proposed<Bytes>>,
+            cmd_metas: CommandMetas,
+        ) {
+            self.0[&node].try_send((cmd, cmd_metas)).unwrap();
+        }
+    }
+}
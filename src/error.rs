@@ -0,0 +1,69 @@
+//! Errors reported by a replica for commands it refuses to act upon.
+use crate::{multi::GroupId, NodeId, Slot};
+use std::{error, fmt, ops::Range};
+
+/// Result of handing a command to a replica
+pub type Result<T> = std::result::Result<T, PaxosError>;
+
+/// Reason a command was refused. The replica's state is left unchanged by
+/// the refused part of the command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaxosError {
+    /// The command names a node that is not a peer in the configuration
+    UnknownNode(NodeId),
+    /// The command references a slot beyond the range the window can hold,
+    /// or one that is no longer tracked
+    SlotOutOfWindow { slot: Slot, window: Range<Slot> },
+    /// A peer sent a command that an honest replica never sends
+    ProtocolViolation { from: NodeId, reason: &'static str },
+    /// The message was sent by a member of another cluster
+    WrongCluster { cluster: u64 },
+    /// The message was sent with another epoch of the membership
+    WrongEpoch { epoch: u64 },
+    /// The message is not signed by a key shared by the cluster
+    Unauthenticated,
+    /// The leader stepped down after losing contact with a quorum. The
+    /// proposal may still be decided if it reached a quorum beforehand.
+    QuorumLost,
+    /// The command names a group that is not hosted by the node
+    UnknownGroup(GroupId),
+    /// The group was split or merged by the shard operation decided in the
+    /// slot, and takes no more proposals
+    Frozen { slot: Slot },
+    /// Linearizable reads are confirmed by the leader. The node holding
+    /// the highest ballot known to the replica is given, if any.
+    NotLeader { leader: Option<NodeId> },
+    /// The state machine cannot answer the query without executing it
+    UnsupportedQuery,
+}
+
+impl fmt::Display for PaxosError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaxosError::UnknownNode(node) => write!(fmt, "node {} is not a known peer", node),
+            PaxosError::SlotOutOfWindow { slot, window } => {
+                write!(fmt, "slot {} is outside of the window {:?}", slot, window)
+            }
+            PaxosError::ProtocolViolation { from, reason } => {
+                write!(fmt, "protocol violation by node {}: {}", from, reason)
+            }
+            PaxosError::WrongCluster { cluster } => {
+                write!(fmt, "message belongs to cluster {}", cluster)
+            }
+            PaxosError::WrongEpoch { epoch } => write!(fmt, "message belongs to epoch {}", epoch),
+            PaxosError::Unauthenticated => write!(fmt, "message is not authenticated"),
+            PaxosError::QuorumLost => write!(fmt, "leader lost contact with a quorum"),
+            PaxosError::UnknownGroup(group) => write!(fmt, "group {} is not hosted", group),
+            PaxosError::Frozen { slot } => {
+                write!(fmt, "group was frozen by a shard operation in slot {}", slot)
+            }
+            PaxosError::NotLeader { leader: Some(leader) } => {
+                write!(fmt, "node is not the leader, node {} is", leader)
+            }
+            PaxosError::NotLeader { leader: None } => write!(fmt, "node is not the leader"),
+            PaxosError::UnsupportedQuery => write!(fmt, "state machine does not answer the query"),
+        }
+    }
+}
+
+impl error::Error for PaxosError {}
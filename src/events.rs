@@ -0,0 +1,293 @@
+//! Chronological record of the decisions made by a replica.
+//!
+//! A `Node` reports each step it takes, and the reason for it, as a
+//! `NodeEvent` to an `EventSink`. Where `Metrics` aggregates what happened,
+//! events keep enough detail to reconstruct why a replica acted the way it
+//! did. `EventLog` is a sink that keeps every event in memory and renders
+//! them as a human-readable timeline.
+use crate::{Ballot, NodeId, Slot};
+use std::{
+    fmt,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Step taken by a replica
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeEvent {
+    /// A proposal was redirected to the node holding the highest ballot
+    ProposalForwarded { leader: NodeId },
+    /// A proposal was queued until the node's election completes
+    ProposalQueued,
+    /// The node holding the highest ballot is suspected to have failed, so
+    /// proposals are not forwarded to it
+    LeaderSuspected { leader: NodeId },
+    /// Another value took the slot of a proposal, which is proposed again
+    ProposalPreempted { slot: Slot },
+    /// A value proposed by this node was decided in the slot
+    ProposalDecided { slot: Slot },
+    /// Phase 1 started with a new ballot
+    ElectionStarted { ballot: Ballot },
+    /// Phase 1 was not started, backing off after consecutive preempted
+    /// elections
+    ElectionDeferred { preemptions: u32 },
+    /// Phase 1 was not started because a healthy peer is preferred as
+    /// leader, to which the queued proposals were forwarded
+    ElectionYielded { to: NodeId },
+    /// The leader offered leadership to a preferred peer
+    HandoverOffered { to: NodeId },
+    /// A quorum of promises was received for the ballot
+    BecameLeader { ballot: Ballot },
+    /// The node stopped being candidate or leader for its ballot after
+    /// observing a higher ballot
+    SteppedDown { ballot: Ballot, preempted_by: Ballot },
+    /// The leader stepped down after not hearing from a quorum within the
+    /// election interval, failing its pending proposals
+    QuorumLost { ballot: Ballot, failed: usize },
+    /// The acceptors promised the ballot for the slots of the open window
+    Promised { ballot: Ballot, slots: Range<Slot> },
+    /// A PREPARE was rejected because a higher ballot had been promised
+    PrepareRejected { from: NodeId, proposed: Ballot, promised: Ballot },
+    /// ACCEPT messages were sent for the slots
+    AcceptSent { ballot: Ballot, slots: Vec<Slot> },
+    /// The acceptors accepted the leader's values for the slots
+    Accepted { from: NodeId, ballot: Ballot, slots: Vec<Slot> },
+    /// An ACCEPT was rejected because a higher ballot had been promised
+    AcceptRejected { from: NodeId, proposed: Ballot, promised: Ballot },
+    /// A peer rejected a ballot proposed by this node
+    RejectReceived { from: NodeId, proposed: Ballot, promised: Ballot },
+    /// A quorum of acceptors accepted the slots
+    Resolved { ballot: Ballot, slots: Vec<Slot> },
+    /// Resolutions for the slots were received from the leader
+    ResolutionReceived { ballot: Ballot, slots: Vec<Slot> },
+    /// Missing slots were requested from a peer
+    CatchupRequested { from: NodeId, slots: Vec<Range<Slot>> },
+    /// Resolutions were sent to a peer catching up
+    CatchupServed { to: NodeId, slots: Vec<Range<Slot>> },
+}
+
+impl fmt::Display for NodeEvent {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeEvent::ProposalForwarded { leader } => {
+                write!(fmt, "forwarded proposal to leader {}", leader)
+            }
+            NodeEvent::ProposalQueued => write!(fmt, "queued proposal until elected"),
+            NodeEvent::LeaderSuspected { leader } => {
+                write!(fmt, "suspected leader {} to have failed", leader)
+            }
+            NodeEvent::ProposalPreempted { slot } => {
+                write!(fmt, "proposing again the value preempted in slot {}", slot)
+            }
+            NodeEvent::ProposalDecided { slot } => write!(fmt, "decided proposal in slot {}", slot),
+            NodeEvent::ElectionStarted { ballot } => {
+                write!(fmt, "started election with ballot {:?}", ballot)
+            }
+            NodeEvent::ElectionDeferred { preemptions } => {
+                write!(fmt, "deferred election after {} preempted elections", preemptions)
+            }
+            NodeEvent::ElectionYielded { to } => {
+                write!(fmt, "yielded election to preferred node {}", to)
+            }
+            NodeEvent::HandoverOffered { to } => {
+                write!(fmt, "offered leadership to preferred node {}", to)
+            }
+            NodeEvent::BecameLeader { ballot } => {
+                write!(fmt, "became leader at ballot {:?}", ballot)
+            }
+            NodeEvent::SteppedDown { ballot, preempted_by } => write!(
+                fmt,
+                "stepped down from ballot {:?} because of ballot {:?}",
+                ballot, preempted_by
+            ),
+            NodeEvent::QuorumLost { ballot, failed } => write!(
+                fmt,
+                "stepped down from ballot {:?} without a quorum, failing {} proposals",
+                ballot, failed
+            ),
+            NodeEvent::Promised { ballot, slots } => {
+                write!(fmt, "promised ballot {:?} for slots {:?}", ballot, slots)
+            }
+            NodeEvent::PrepareRejected { from, proposed, promised } => write!(
+                fmt,
+                "rejected PREPARE from node {} for ballot {:?} because promised {:?}",
+                from, proposed, promised
+            ),
+            NodeEvent::AcceptSent { ballot, slots } => {
+                write!(fmt, "sent ACCEPT at ballot {:?} for slots {:?}", ballot, slots)
+            }
+            NodeEvent::Accepted { from, ballot, slots } => write!(
+                fmt,
+                "accepted slots {:?} from node {} at ballot {:?}",
+                slots, from, ballot
+            ),
+            NodeEvent::AcceptRejected { from, proposed, promised } => write!(
+                fmt,
+                "rejected ACCEPT from node {} for ballot {:?} because promised {:?}",
+                from, proposed, promised
+            ),
+            NodeEvent::RejectReceived { from, proposed, promised } => write!(
+                fmt,
+                "node {} rejected ballot {:?} because promised {:?}",
+                from, proposed, promised
+            ),
+            NodeEvent::Resolved { ballot, slots } => {
+                write!(fmt, "resolved slots {:?} at ballot {:?}", slots, ballot)
+            }
+            NodeEvent::ResolutionReceived { ballot, slots } => {
+                write!(fmt, "learned resolution of slots {:?} at ballot {:?}", slots, ballot)
+            }
+            NodeEvent::CatchupRequested { from, slots } => {
+                write!(fmt, "requested catchup of slots {:?} from node {}", slots, from)
+            }
+            NodeEvent::CatchupServed { to, slots } => {
+                write!(fmt, "sent catchup of slots {:?} to node {}", slots, to)
+            }
+        }
+    }
+}
+
+/// Receiver of the events of one or more replicas
+pub trait EventSink: Send + Sync {
+    /// Called synchronously as the node takes a step
+    fn emit(&self, node: NodeId, event: NodeEvent);
+}
+
+/// Emits the events of a single node, skipping the construction of events
+/// when no sink is attached
+#[derive(Clone)]
+pub(crate) struct EventStream {
+    node: NodeId,
+    sink: Option<Arc<dyn EventSink>>,
+}
+
+impl EventStream {
+    pub(crate) fn new(node: NodeId) -> EventStream {
+        EventStream { node, sink: None }
+    }
+
+    pub(crate) fn set_sink(&mut self, sink: Arc<dyn EventSink>) {
+        self.sink = Some(sink);
+    }
+
+    #[inline]
+    pub(crate) fn emit<F: FnOnce() -> NodeEvent>(&self, event: F) {
+        if let Some(sink) = &self.sink {
+            sink.emit(self.node, event());
+        }
+    }
+}
+
+/// Event along with the node that emitted it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Time since the log was created
+    pub elapsed: Duration,
+    pub node: NodeId,
+    pub event: NodeEvent,
+}
+
+impl fmt::Display for RecordedEvent {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:>12.6}s  node {}: {}", self.elapsed.as_secs_f64(), self.node, self.event)
+    }
+}
+
+/// In-memory sink that keeps events in the order they were emitted. Clones
+/// share the same log, so one log can record a whole cluster.
+#[derive(Clone)]
+pub struct EventLog {
+    start: Instant,
+    events: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl Default for EventLog {
+    fn default() -> EventLog {
+        EventLog { start: Instant::now(), events: Arc::default() }
+    }
+}
+
+impl EventLog {
+    /// Copy of the events recorded so far
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Renders the recorded events one per line, oldest first
+    pub fn timeline(&self) -> String {
+        Timeline(&self.events()).to_string()
+    }
+}
+
+impl EventSink for EventLog {
+    fn emit(&self, node: NodeId, event: NodeEvent) {
+        let elapsed = self.start.elapsed();
+        self.events.lock().unwrap().push(RecordedEvent { elapsed, node, event });
+    }
+}
+
+/// Human-readable timeline of recorded events
+pub struct Timeline<'a>(pub &'a [RecordedEvent]);
+
+impl<'a> fmt::Display for Timeline<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for event in self.0 {
+            writeln!(fmt, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeline_format() {
+        let events = vec![
+            RecordedEvent {
+                elapsed: Duration::from_micros(1500),
+                node: 1,
+                event: NodeEvent::Promised { ballot: Ballot(3, 2), slots: 4..7 },
+            },
+            RecordedEvent {
+                elapsed: Duration::from_millis(20),
+                node: 0,
+                event: NodeEvent::AcceptRejected {
+                    from: 2,
+                    proposed: Ballot(1, 2),
+                    promised: Ballot(3, 2),
+                },
+            },
+        ];
+
+        assert_eq!(
+            "    0.001500s  node 1: promised ballot Ballot(3, 2) for slots 4..7\n    \
+             0.020000s  node 0: rejected ACCEPT from node 2 for ballot Ballot(1, 2) because \
+             promised Ballot(3, 2)\n",
+            Timeline(&events).to_string()
+        );
+    }
+
+    #[test]
+    fn event_stream_without_sink() {
+        let stream = EventStream::new(0);
+        stream.emit(|| unreachable!("events are not built without a sink"));
+
+        let log = EventLog::default();
+        let mut stream = EventStream::new(3);
+        stream.set_sink(Arc::new(log.clone()));
+        stream.emit(|| NodeEvent::ProposalQueued);
+        stream.emit(|| NodeEvent::BecameLeader { ballot: Ballot(0, 3) });
+
+        let events = log.events().into_iter().map(|e| (e.node, e.event)).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (3, NodeEvent::ProposalQueued),
+                (3, NodeEvent::BecameLeader { ballot: Ballot(0, 3) })
+            ],
+            events
+        );
+    }
+}
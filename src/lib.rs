@@ -29,23 +29,162 @@ extern crate log;
 extern crate lazy_static;
 
 mod acceptor;
+pub mod auth;
+mod catchup;
+pub mod codec;
 pub mod commands;
 mod config;
+pub mod detector;
+#[cfg(feature = "driver")]
+pub mod driver;
+pub mod error;
+pub mod events;
+pub mod metrics;
+pub mod multi;
 mod node;
 mod proposer;
+mod rng;
+pub mod sim;
 pub mod statemachine;
+pub mod status;
+#[cfg(feature = "tcp")]
+pub mod tcp;
 mod window;
 
-use std::cmp;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use std::{cmp, fmt::Debug};
 
 pub use commands::{Command, CommandMetas, Receiver, Transport};
 pub use config::{Configuration, NodeMetadata};
+pub use error::PaxosError;
 pub use node::Node;
 use serde::{Deserialize, Serialize};
 pub use statemachine::ReplicatedState;
 use std::marker::Sized;
 pub use window::DecisionSet;
 
+/// Value decided by the replicas for each slot.
+///
+/// Slots can be decided without a proposal, for example when a new leader
+/// fills holes in the window. Those slots are decided as the `noop` value,
+/// which is not applied to the state machine.
+pub trait Value: Clone + PartialEq + Debug + Serialize + DeserializeOwned {
+    /// Value of slots decided without a proposal
+    fn noop() -> Self;
+
+    /// Indicator of a value decided without a proposal
+    fn is_noop(&self) -> bool;
+}
+
+impl Value for Bytes {
+    fn noop() -> Bytes {
+        Bytes::new()
+    }
+
+    fn is_noop(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+/// Identity of a proposal, telling apart proposals of equal values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ProposalId {
+    /// Node that made the proposal
+    pub node: NodeId,
+    /// Sequence number of the proposal on the node
+    pub seq: u64,
+}
+
+/// Value proposed along with the identity of its proposal, so that the
+/// node that made the proposal recognizes its decision
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Proposed<V> {
+    pub id: ProposalId,
+    pub value: V,
+}
+
+impl<V: Value> Value for Proposed<V> {
+    fn noop() -> Proposed<V> {
+        Proposed { id: ProposalId::default(), value: V::noop() }
+    }
+
+    fn is_noop(&self) -> bool {
+        self.value.is_noop()
+    }
+}
+
+/// Value decided along with the identity of its proposal, so that the node
+/// that made the proposal recognizes its decision among equal commands
+pub trait Identified: Value {
+    /// Command applied to the state machine
+    type Command: Value;
+
+    /// Value proposing a command under the identity of the proposal
+    fn identify(id: ProposalId, command: Self::Command) -> Self;
+
+    /// Identity of the proposal and its command
+    fn into_parts(self) -> (ProposalId, Self::Command);
+}
+
+impl<V: Value> Identified for Proposed<V> {
+    type Command = V;
+
+    fn identify(id: ProposalId, value: V) -> Proposed<V> {
+        Proposed { id, value }
+    }
+
+    fn into_parts(self) -> (ProposalId, V) {
+        (self.id, self.value)
+    }
+}
+
+/// Length of the identity prefixing identified `Bytes` values
+const PROPOSAL_ID_LEN: usize = 12;
+
+/// `Bytes` commands are prefixed by the identity of their proposal. Values
+/// shorter than the prefix, such as the noop, have the default identity.
+impl Identified for Bytes {
+    type Command = Bytes;
+
+    fn identify(id: ProposalId, command: Bytes) -> Bytes {
+        let mut buf = BytesMut::with_capacity(PROPOSAL_ID_LEN + command.len());
+        buf.put_u32(id.node);
+        buf.put_u64(id.seq);
+        buf.put_slice(&command);
+        buf.freeze()
+    }
+
+    fn into_parts(mut self) -> (ProposalId, Bytes) {
+        if self.len() < PROPOSAL_ID_LEN {
+            return (ProposalId::default(), self);
+        }
+        let mut id = self.split_to(PROPOSAL_ID_LEN);
+        (ProposalId { node: id.get_u32(), seq: id.get_u64() }, self)
+    }
+}
+
+/// Generator of the identities of the proposals made by a node
+pub(crate) struct ProposalIds {
+    node: NodeId,
+    next: u64,
+}
+
+impl ProposalIds {
+    /// Sequence numbers start at a random value, so that proposals made
+    /// before a restart are not mistaken for later ones
+    pub(crate) fn new(node: NodeId) -> ProposalIds {
+        ProposalIds { node, next: commands::SpanIds::new(node).next() }
+    }
+
+    /// Identity of the next proposal
+    pub(crate) fn next(&mut self) -> ProposalId {
+        let id = ProposalId { node: self.node, seq: self.next };
+        self.next = self.next.wrapping_add(1);
+        id
+    }
+}
+
 /// Increasing sequence number of Paxos instances.
 pub type Slot = u64;
 
@@ -69,10 +208,7 @@ impl Ballot {
 
 impl PartialOrd for Ballot {
     fn partial_cmp(&self, other: &Ballot) -> Option<cmp::Ordering> {
-        match self.0.cmp(&other.0) {
-            cmp::Ordering::Equal => self.1.partial_cmp(&other.1),
-            o => Some(o),
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -85,7 +221,13 @@ impl Ord for Ballot {
     }
 }
 
-pub trait Replica: Receiver {
+pub trait Replica<V: Value = Bytes>: Receiver<V> {
+    /// Node hosting the replica
+    fn node(&self) -> NodeId;
+
+    /// Determines if a node is a peer of the replica
+    fn is_peer(&self, node: NodeId) -> bool;
+
     /// Proposes that the current node take over leadership
     fn propose_leadership(&mut self, cmd_metas: CommandMetas);
 
@@ -93,15 +235,46 @@ pub trait Replica: Receiver {
     fn is_leader(&self) -> bool;
 
     /// Resolved slots within the replica
-    fn decisions(&self) -> DecisionSet;
+    fn decisions(&self) -> DecisionSet<'_, V>;
+
+    /// Number of proposals held until the node leads or learns of a leader
+    fn queued_proposals(&self) -> usize {
+        0
+    }
+
+    /// Takes the values of the proposals that the replica gave up on. Only
+    /// the most recent are kept, so the values should be taken regularly.
+    fn take_failed_proposals(&mut self) -> Vec<V> {
+        Vec::new()
+    }
+
+    /// Slot following the highest slot known to be decided, by the replica
+    /// or by its peers
+    fn known_decided(&self) -> Slot {
+        self.decisions().iter().last().map_or(0, |(slot, _)| slot + 1)
+    }
 
-    /// Configures the replica to use a custom state machine to apply decisions
-    fn state_machine<R: ReplicatedState>(
-        self,
-        state_machine: R,
-    ) -> statemachine::StateMachineReplica<Self, R>
+    /// Starts confirming with a quorum that the node leads, for a read that
+    /// reflects every slot decided so far. Returns the identifier of the
+    /// read, as given back by `take_confirmed_reads`.
+    fn confirm_read(&mut self, _cmd_metas: CommandMetas) -> error::Result<u64> {
+        Err(PaxosError::NotLeader { leader: None })
+    }
+
+    /// Takes the reads confirmed or failed since the last call. A confirmed
+    /// read reflects every decided slot once the slots before the given
+    /// slot are executed.
+    fn take_confirmed_reads(&mut self) -> Vec<(u64, error::Result<Slot>)> {
+        Vec::new()
+    }
+
+    /// Configures the replica to use a custom state machine to apply decisions.
+    /// The replica decides commands along with the identity of their proposal.
+    fn state_machine<S>(self, state_machine: S) -> statemachine::StateMachineReplica<Self, S, V>
     where
         Self: Sized,
+        V: Identified,
+        S: ReplicatedState<V::Command>,
     {
         statemachine::StateMachineReplica::new(self, state_machine)
     }
@@ -129,4 +302,13 @@ mod tests {
         assert_eq!(Ballot(7, 5), b.higher_for(5));
         assert_eq!(Ballot(7, 1), b.higher_for(1));
     }
+
+    #[test]
+    fn bytes_identified() {
+        let id = ProposalId { node: 3, seq: u64::MAX - 1 };
+        let value = Bytes::identify(id, "foo".into());
+        assert_eq!((id, Bytes::from("foo")), value.into_parts());
+        assert_eq!((id, Bytes::noop()), Bytes::identify(id, Bytes::noop()).into_parts());
+        assert_eq!((ProposalId::default(), Bytes::noop()), Bytes::noop().into_parts());
+    }
 }
@@ -0,0 +1,472 @@
+//! Hooks for observing the protocol.
+//!
+//! `Node`, `Proposer` and `SlotWindow` report what they do to a shared
+//! `Metrics` implementation. Every hook defaults to doing nothing, so an
+//! implementation only overrides the events it is interested in. The hooks
+//! are called synchronously while the replica handles a command and should
+//! return quickly.
+//!
+//! `Counters` is an implementation that keeps counters, gauges and an
+//! accept latency histogram that can be read at any time with
+//! `Counters::snapshot`.
+use crate::{commands::Command, error::PaxosError, Ballot, NodeId, Slot};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Type of a command sent between replicas
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum CommandKind {
+    Proposal,
+    Prepare,
+    Promise,
+    Accept,
+    Reject,
+    Accepted,
+    Resolution,
+    Catchup,
+    Handover,
+    Confirm,
+    Confirmed,
+}
+
+impl CommandKind {
+    /// Every kind of command
+    pub const ALL: [CommandKind; 11] = [
+        CommandKind::Proposal,
+        CommandKind::Prepare,
+        CommandKind::Promise,
+        CommandKind::Accept,
+        CommandKind::Reject,
+        CommandKind::Accepted,
+        CommandKind::Resolution,
+        CommandKind::Catchup,
+        CommandKind::Handover,
+        CommandKind::Confirm,
+        CommandKind::Confirmed,
+    ];
+
+    /// Lowercase name of the command
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandKind::Proposal => "proposal",
+            CommandKind::Prepare => "prepare",
+            CommandKind::Promise => "promise",
+            CommandKind::Accept => "accept",
+            CommandKind::Reject => "reject",
+            CommandKind::Accepted => "accepted",
+            CommandKind::Resolution => "resolution",
+            CommandKind::Catchup => "catchup",
+            CommandKind::Handover => "handover",
+            CommandKind::Confirm => "confirm",
+            CommandKind::Confirmed => "confirmed",
+        }
+    }
+}
+
+impl<'a, V> From<&'a Command<V>> for CommandKind {
+    fn from(cmd: &'a Command<V>) -> CommandKind {
+        match cmd {
+            Command::Proposal { .. } => CommandKind::Proposal,
+            Command::Prepare { .. } => CommandKind::Prepare,
+            Command::Promise { .. } => CommandKind::Promise,
+            Command::Accept { .. } => CommandKind::Accept,
+            Command::Reject { .. } => CommandKind::Reject,
+            Command::Accepted { .. } => CommandKind::Accepted,
+            Command::Resolution { .. } => CommandKind::Resolution,
+            Command::Catchup { .. } => CommandKind::Catchup,
+            Command::Handover { .. } => CommandKind::Handover,
+            Command::Confirm { .. } => CommandKind::Confirm,
+            Command::Confirmed { .. } => CommandKind::Confirmed,
+        }
+    }
+}
+
+/// Receiver of protocol events from a replica
+pub trait Metrics: Send + Sync {
+    /// A command was handed to the transport
+    fn message_sent(&self, _to: NodeId, _kind: CommandKind) {}
+
+    /// A command was received from a peer or a client
+    fn message_received(&self, _kind: CommandKind) {}
+
+    /// A command was refused by the replica, or a frame by the transport
+    fn message_refused(&self, _error: &PaxosError) {}
+
+    /// The proposer started Phase 1 with a new ballot
+    fn election_started(&self, _ballot: Ballot) {}
+
+    /// The proposer received a quorum of promises and became leader
+    fn election_won(&self, _ballot: Ballot) {}
+
+    /// The proposer stepped down as candidate or leader after observing a
+    /// higher ballot
+    fn leadership_lost(&self, _ballot: Ballot) {}
+
+    /// The election of the proposer was preempted by a higher ballot, for
+    /// the given number of consecutive times
+    fn election_preempted(&self, _ballot: Ballot, _consecutive: u32) {}
+
+    /// The highest ballot observed by the proposer increased
+    fn ballot_observed(&self, _ballot: Ballot) {}
+
+    /// A peer rejected a proposed ballot in favor of a promised ballot
+    fn reject_received(&self, _proposed: Ballot, _promised: Ballot) {}
+
+    /// The leader sent an ACCEPT for a slot
+    fn accept_sent(&self, _slot: Slot) {}
+
+    /// The leader received a quorum of ACCEPTED messages for a slot
+    fn slot_resolved(&self, _slot: Slot) {}
+
+    /// Slots were added to the contiguous range of decisions
+    fn slots_decided(&self, _slots: Range<Slot>) {}
+
+    /// Number of slots within the open window after it changed
+    fn window_size(&self, _open: usize) {}
+
+    /// Missing slots were requested from a peer
+    fn catchup_requested(&self, _slots: usize) {}
+
+    /// Resolutions were sent to a peer catching up
+    fn catchup_served(&self, _slots: usize) {}
+}
+
+/// Metrics implementation that ignores every event
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Upper bounds of the accept latency histogram buckets, in microseconds
+const LATENCY_BUCKETS: [u64; 12] =
+    [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000];
+
+/// Histogram of durations with fixed buckets
+#[derive(Default)]
+struct Histogram {
+    /// Counts for each bucket of `LATENCY_BUCKETS` and the overflow bucket
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS.iter().position(|le| micros <= *le);
+        self.buckets[bucket.unwrap_or(LATENCY_BUCKETS.len())].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let counts = self.buckets.iter().map(|count| count.load(Ordering::Relaxed));
+        let mut cumulative = 0;
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .map(|le| Some(Duration::from_micros(*le)))
+            .chain(Some(None))
+            .zip(counts)
+            .map(|(le, count)| {
+                cumulative += count;
+                (le, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: cumulative,
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Point in time copy of a histogram
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Cumulative count of observations less than or equal to each upper
+    /// bound. The last bucket has no upper bound.
+    pub buckets: Vec<(Option<Duration>, u64)>,
+    /// Number of observations
+    pub count: u64,
+    /// Sum of all observations
+    pub sum: Duration,
+}
+
+/// Counters, gauges and histograms updated from protocol events
+#[derive(Default)]
+pub struct Counters {
+    sent: [AtomicU64; CommandKind::ALL.len()],
+    received: [AtomicU64; CommandKind::ALL.len()],
+    refused: AtomicU64,
+    elections_started: AtomicU64,
+    elections_won: AtomicU64,
+    leadership_lost: AtomicU64,
+    elections_preempted: AtomicU64,
+    consecutive_preemptions: AtomicU64,
+    ballot_increases: AtomicU64,
+    rejects: AtomicU64,
+    accepts_sent: AtomicU64,
+    slots_resolved: AtomicU64,
+    slots_decided: AtomicU64,
+    window_size: AtomicU64,
+    catchup_requested: AtomicU64,
+    catchup_served: AtomicU64,
+    accept_latency: Histogram,
+    /// Time at which the first ACCEPT of each unresolved slot was sent
+    accepts_in_flight: Mutex<HashMap<Slot, Instant>>,
+}
+
+impl Counters {
+    /// Copy of the current values
+    pub fn snapshot(&self) -> CountersSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let by_kind = |counters: &[AtomicU64]| {
+            CommandKind::ALL
+                .iter()
+                .zip(counters)
+                .map(|(kind, count)| (*kind, load(count)))
+                .collect()
+        };
+        CountersSnapshot {
+            sent: by_kind(&self.sent),
+            received: by_kind(&self.received),
+            refused: load(&self.refused),
+            elections_started: load(&self.elections_started),
+            elections_won: load(&self.elections_won),
+            leadership_lost: load(&self.leadership_lost),
+            elections_preempted: load(&self.elections_preempted),
+            consecutive_preemptions: load(&self.consecutive_preemptions),
+            ballot_increases: load(&self.ballot_increases),
+            rejects: load(&self.rejects),
+            accepts_sent: load(&self.accepts_sent),
+            slots_resolved: load(&self.slots_resolved),
+            slots_decided: load(&self.slots_decided),
+            window_size: load(&self.window_size),
+            catchup_requested: load(&self.catchup_requested),
+            catchup_served: load(&self.catchup_served),
+            accept_latency: self.accept_latency.snapshot(),
+        }
+    }
+}
+
+impl Metrics for Counters {
+    fn message_sent(&self, _to: NodeId, kind: CommandKind) {
+        self.sent[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_received(&self, kind: CommandKind) {
+        self.received[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_refused(&self, _error: &PaxosError) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn election_started(&self, _ballot: Ballot) {
+        self.elections_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn election_won(&self, _ballot: Ballot) {
+        self.elections_won.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_preemptions.store(0, Ordering::Relaxed);
+    }
+
+    fn leadership_lost(&self, _ballot: Ballot) {
+        self.leadership_lost.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn election_preempted(&self, _ballot: Ballot, consecutive: u32) {
+        self.elections_preempted.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_preemptions.store(u64::from(consecutive), Ordering::Relaxed);
+    }
+
+    fn ballot_observed(&self, _ballot: Ballot) {
+        self.ballot_increases.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reject_received(&self, _proposed: Ballot, _promised: Ballot) {
+        self.rejects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn accept_sent(&self, slot: Slot) {
+        self.accepts_sent.fetch_add(1, Ordering::Relaxed);
+        // latency is measured from the first ACCEPT, retries do not reset it
+        self.accepts_in_flight.lock().unwrap().entry(slot).or_insert_with(Instant::now);
+    }
+
+    fn slot_resolved(&self, slot: Slot) {
+        self.slots_resolved.fetch_add(1, Ordering::Relaxed);
+        if let Some(sent_at) = self.accepts_in_flight.lock().unwrap().remove(&slot) {
+            self.accept_latency.record(sent_at.elapsed());
+        }
+    }
+
+    fn slots_decided(&self, slots: Range<Slot>) {
+        self.slots_decided.fetch_add(slots.end - slots.start, Ordering::Relaxed);
+        // slots resolved through another leader never report a resolution
+        self.accepts_in_flight.lock().unwrap().retain(|slot, _| *slot >= slots.end);
+    }
+
+    fn window_size(&self, open: usize) {
+        self.window_size.store(open as u64, Ordering::Relaxed);
+    }
+
+    fn catchup_requested(&self, slots: usize) {
+        self.catchup_requested.fetch_add(slots as u64, Ordering::Relaxed);
+    }
+
+    fn catchup_served(&self, slots: usize) {
+        self.catchup_served.fetch_add(slots as u64, Ordering::Relaxed);
+    }
+}
+
+/// Point in time copy of `Counters`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CountersSnapshot {
+    /// Commands sent to peers by type
+    pub sent: BTreeMap<CommandKind, u64>,
+    /// Commands received by type
+    pub received: BTreeMap<CommandKind, u64>,
+    /// Commands and frames refused
+    pub refused: u64,
+    /// Number of times Phase 1 was started
+    pub elections_started: u64,
+    /// Number of times Phase 1 reached quorum
+    pub elections_won: u64,
+    /// Number of times the proposer was preempted as candidate or leader
+    pub leadership_lost: u64,
+    /// Number of times Phase 1 was preempted by a higher ballot
+    pub elections_preempted: u64,
+    /// Elections preempted since the last one won
+    pub consecutive_preemptions: u64,
+    /// Number of times the highest observed ballot increased
+    pub ballot_increases: u64,
+    /// REJECT messages received
+    pub rejects: u64,
+    /// Slots sent in ACCEPT messages
+    pub accepts_sent: u64,
+    /// Slots resolved by this node as leader
+    pub slots_resolved: u64,
+    /// Slots in the contiguous range of decisions
+    pub slots_decided: u64,
+    /// Slots in the open window
+    pub window_size: u64,
+    /// Slots requested through catchup
+    pub catchup_requested: u64,
+    /// Slots sent to peers catching up
+    pub catchup_served: u64,
+    /// Time from the first ACCEPT of a slot until it was resolved
+    pub accept_latency: HistogramSnapshot,
+}
+
+impl fmt::Display for CountersSnapshot {
+    /// Renders the snapshot in the Prometheus text exposition format
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (kind, count) in &self.sent {
+            writeln!(fmt, "paxos_messages_sent_total{{type=\"{}\"}} {}", kind.name(), count)?;
+        }
+        for (kind, count) in &self.received {
+            writeln!(fmt, "paxos_messages_received_total{{type=\"{}\"}} {}", kind.name(), count)?;
+        }
+        writeln!(fmt, "paxos_messages_refused_total {}", self.refused)?;
+        writeln!(fmt, "paxos_elections_started_total {}", self.elections_started)?;
+        writeln!(fmt, "paxos_elections_won_total {}", self.elections_won)?;
+        writeln!(fmt, "paxos_leadership_lost_total {}", self.leadership_lost)?;
+        writeln!(fmt, "paxos_elections_preempted_total {}", self.elections_preempted)?;
+        writeln!(fmt, "paxos_consecutive_preemptions {}", self.consecutive_preemptions)?;
+        writeln!(fmt, "paxos_ballot_increases_total {}", self.ballot_increases)?;
+        writeln!(fmt, "paxos_rejects_total {}", self.rejects)?;
+        writeln!(fmt, "paxos_accepts_sent_total {}", self.accepts_sent)?;
+        writeln!(fmt, "paxos_slots_resolved_total {}", self.slots_resolved)?;
+        writeln!(fmt, "paxos_slots_decided_total {}", self.slots_decided)?;
+        writeln!(fmt, "paxos_window_size {}", self.window_size)?;
+        writeln!(fmt, "paxos_catchup_requested_slots_total {}", self.catchup_requested)?;
+        writeln!(fmt, "paxos_catchup_served_slots_total {}", self.catchup_served)?;
+        for (le, count) in &self.accept_latency.buckets {
+            match le {
+                Some(le) => writeln!(
+                    fmt,
+                    "paxos_accept_latency_seconds_bucket{{le=\"{}\"}} {}",
+                    le.as_secs_f64(),
+                    count
+                )?,
+                None => {
+                    writeln!(fmt, "paxos_accept_latency_seconds_bucket{{le=\"+Inf\"}} {}", count)?
+                }
+            }
+        }
+        let sum = self.accept_latency.sum.as_secs_f64();
+        writeln!(fmt, "paxos_accept_latency_seconds_sum {}", sum)?;
+        writeln!(fmt, "paxos_accept_latency_seconds_count {}", self.accept_latency.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let histogram = Histogram::default();
+        histogram.record(Duration::from_micros(50));
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(5));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(4, snapshot.count);
+        assert_eq!(Duration::from_micros(5_003_150), snapshot.sum);
+        assert_eq!((Some(Duration::from_micros(100)), 2), snapshot.buckets[0]);
+        assert_eq!((Some(Duration::from_millis(5)), 3), snapshot.buckets[5]);
+        assert_eq!((Some(Duration::from_secs(1)), 3), snapshot.buckets[11]);
+        assert_eq!((None, 4), snapshot.buckets[12]);
+    }
+
+    #[test]
+    fn counters_accept_latency() {
+        let counters = Counters::default();
+        counters.accept_sent(0);
+        counters.accept_sent(1);
+        counters.accept_sent(2);
+        counters.slot_resolved(0);
+
+        // resolutions without a matching ACCEPT are counted, but not timed
+        counters.slot_resolved(5);
+
+        // decided slots are no longer timed
+        counters.slots_decided(0..2);
+        counters.slot_resolved(1);
+
+        let snapshot = counters.snapshot();
+        assert_eq!(3, snapshot.accepts_sent);
+        assert_eq!(3, snapshot.slots_resolved);
+        assert_eq!(2, snapshot.slots_decided);
+        assert_eq!(1, snapshot.accept_latency.count);
+        assert_eq!(1, counters.accepts_in_flight.lock().unwrap().len());
+    }
+
+    #[test]
+    fn counters_messages_by_kind() {
+        let counters = Counters::default();
+        let cmd: Command = Command::Prepare { payload: (Ballot(0, 1), 0) };
+        counters.message_sent(0, CommandKind::from(&cmd));
+        counters.message_sent(2, CommandKind::from(&cmd));
+        counters.message_received(CommandKind::Promise);
+
+        let snapshot = counters.snapshot();
+        assert_eq!(2, snapshot.sent[&CommandKind::Prepare]);
+        assert_eq!(0, snapshot.sent[&CommandKind::Promise]);
+        assert_eq!(1, snapshot.received[&CommandKind::Promise]);
+
+        let text = snapshot.to_string();
+        assert!(text.contains("paxos_messages_sent_total{type=\"prepare\"} 2\n"));
+        assert!(text.contains("paxos_accept_latency_seconds_bucket{le=\"+Inf\"} 0\n"));
+    }
+}
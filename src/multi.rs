@@ -0,0 +1,297 @@
+//! Host running many independent Paxos groups over a single transport.
+//!
+//! Each group is a replica of its own log, identified by a `GroupId`. The
+//! `MultiNode` hands every group a `GroupSender`, which queues the commands
+//! of the group in a shared outbox rather than sending them. Once the host
+//! is done reacting to an envelope, a proposal or a tick, the queued
+//! commands are sent as one `GroupEnvelope` per peer, so the commands of
+//! all the groups, such as the heartbeat of each group led by the node,
+//! reach each peer in a single message. Incoming envelopes are routed to
+//! the groups named by their commands, and commands from nodes that are not
+//! peers of their group are refused.
+pub mod shard;
+
+use crate::{
+    commands::{Command, CommandMetas, Transport},
+    error::{PaxosError, Result},
+    Configuration, NodeId, NodeMetadata, Replica, Value,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex},
+};
+
+/// Identifier of a Paxos group within a host
+pub type GroupId = u64;
+
+/// Commands of one or more groups sent from one node to another
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupEnvelope<V = Bytes> {
+    /// Node that sent the envelope
+    pub from: NodeId,
+    /// Commands along with the group they belong to, in the order sent
+    pub commands: Vec<(GroupId, Command<V>, CommandMetas)>,
+}
+
+/// Sends envelopes to the hosts of other nodes
+pub trait GroupTransport<V = Bytes> {
+    /// Send an envelope to a single node
+    fn send(&mut self, node: NodeId, node_metadata: &NodeMetadata, envelope: GroupEnvelope<V>);
+}
+
+/// Commands queued by the groups, by destination
+type Outbox<V> = Arc<Mutex<BTreeMap<NodeId, Vec<(GroupId, Command<V>, CommandMetas)>>>>;
+
+/// Transport of a single group, queuing its commands in the host's outbox
+pub struct GroupSender<V = Bytes> {
+    group: GroupId,
+    outbox: Outbox<V>,
+}
+
+impl<V> Transport<V> for GroupSender<V> {
+    fn send(&mut self, node: NodeId, _: &NodeMetadata, cmd: Command<V>, cmd_metas: CommandMetas) {
+        let mut outbox = self.outbox.lock().unwrap();
+        outbox.entry(node).or_default().push((self.group, cmd, cmd_metas));
+    }
+}
+
+/// Hosts the replicas of many groups on a node, multiplexing their
+/// commands over one transport.
+pub struct MultiNode<T, R, V = Bytes> {
+    transport: T,
+    config: Configuration,
+    groups: BTreeMap<GroupId, R>,
+    outbox: Outbox<V>,
+}
+
+impl<V: Value, T: GroupTransport<V>, R: Replica<V>> MultiNode<T, R, V> {
+    /// Creates a host without groups. The configuration holds the current
+    /// node and the metadata of every peer that the groups may send to.
+    pub fn new(transport: T, config: Configuration) -> MultiNode<T, R, V> {
+        MultiNode { transport, config, groups: BTreeMap::new(), outbox: Arc::default() }
+    }
+
+    /// Transport for the replica of a group that will be added to the host
+    pub fn transport(&self, group: GroupId) -> GroupSender<V> {
+        GroupSender { group, outbox: self.outbox.clone() }
+    }
+
+    /// Adds the replica of a group, built with a transport from this host
+    pub fn add_group(&mut self, group: GroupId, replica: R) {
+        self.groups.insert(group, replica);
+    }
+
+    /// Removes the replica of a group. Commands for the group are refused
+    /// until a replica is added again.
+    pub fn remove_group(&mut self, group: GroupId) -> Option<R> {
+        self.groups.remove(&group)
+    }
+
+    /// Reference to the replica of a group
+    pub fn group(&self, group: GroupId) -> Option<&R> {
+        self.groups.get(&group)
+    }
+
+    /// Mutable reference to the replica of a group. Commands sent by the
+    /// replica are held until the host next flushes its outbox.
+    pub fn group_mut(&mut self, group: GroupId) -> Option<&mut R> {
+        self.groups.get_mut(&group)
+    }
+
+    /// Identifiers of the hosted groups, in order
+    pub fn groups(&self) -> impl Iterator<Item = GroupId> + '_ {
+        self.groups.keys().copied()
+    }
+
+    /// Proposes a value to a group
+    pub fn propose(&mut self, group: GroupId, val: V, cmd_metas: CommandMetas) -> Result<()> {
+        let res = self.deliver(group, Command::Proposal { payload: val }, cmd_metas);
+        self.flush();
+        res
+    }
+
+    /// Routes the commands of an envelope received from a peer to their
+    /// groups. Every command is delivered even if an earlier one is
+    /// refused, and the first refusal is returned.
+    pub fn receive(&mut self, envelope: GroupEnvelope<V>) -> Result<()> {
+        let mut res = Ok(());
+        let from = envelope.from;
+        for (group, command, cmd_metas) in envelope.commands {
+            let delivered = match self.groups.get(&group) {
+                Some(replica) if !replica.is_peer(from) => Err(PaxosError::UnknownNode(from)),
+                _ => self.deliver(group, command, cmd_metas),
+            };
+            if let Err(e) = delivered {
+                warn!("Group {} refused command from node {}: {}", group, from, e);
+                res = res.and(Err(e));
+            }
+        }
+        self.flush();
+        res
+    }
+
+    /// Sends heartbeats for the groups led by the node, and runs again for
+    /// leadership of the groups holding proposals, which counts down the
+    /// backoff after preempted elections. The commands of all groups are
+    /// sent to each peer in one envelope.
+    pub fn tick(&mut self) {
+        for replica in self.groups.values_mut() {
+            if replica.is_leader() || replica.queued_proposals() > 0 {
+                replica.propose_leadership(CommandMetas::default());
+            }
+        }
+        self.flush();
+    }
+
+    fn deliver(&mut self, group: GroupId, cmd: Command<V>, cmd_metas: CommandMetas) -> Result<()> {
+        match self.groups.get_mut(&group) {
+            Some(replica) => replica.receive(cmd, cmd_metas),
+            None => Err(PaxosError::UnknownGroup(group)),
+        }
+    }
+
+    /// Sends the commands queued by the groups
+    fn flush(&mut self) {
+        let outbox = mem::take(&mut *self.outbox.lock().unwrap());
+        let from = self.config.current();
+        for (node, commands) in outbox {
+            match self.config.peer(node) {
+                Some(meta) => self.transport.send(node, meta, GroupEnvelope { from, commands }),
+                None => warn!("Dropping {} commands for unknown node {}", commands.len(), node),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{detector::FixedTimeout, Ballot, Node};
+    use std::{collections::VecDeque, time::Duration};
+
+    type Host = MultiNode<QueueTransport, Node<GroupSender>>;
+
+    #[derive(Default, Clone)]
+    struct QueueTransport(Arc<Mutex<VecDeque<(NodeId, GroupEnvelope)>>>);
+
+    impl GroupTransport for QueueTransport {
+        fn send(&mut self, node: NodeId, _: &NodeMetadata, envelope: GroupEnvelope) {
+            self.0.lock().unwrap().push_back((node, envelope));
+        }
+    }
+
+    fn cluster(groups: &[GroupId]) -> (Vec<Host>, QueueTransport) {
+        let transport = QueueTransport::default();
+        let hosts = (0..3)
+            .map(|node| {
+                let peers = (0..3).filter(|n| *n != node).map(|n| (n, NodeMetadata::default()));
+                let config = Configuration::new(node, peers);
+                let mut host = MultiNode::new(transport.clone(), config.clone());
+                for group in groups {
+                    let replica = Node::new(host.transport(*group), config.clone());
+                    host.add_group(*group, replica);
+                }
+                host
+            })
+            .collect();
+        (hosts, transport)
+    }
+
+    fn deliver_all(hosts: &mut [Host], transport: &QueueTransport) {
+        loop {
+            let next = transport.0.lock().unwrap().pop_front();
+            match next {
+                Some((node, envelope)) => hosts[node as usize].receive(envelope).unwrap(),
+                None => return,
+            }
+        }
+    }
+
+    #[test]
+    fn multi_node_routes_groups() {
+        let (mut hosts, transport) = cluster(&[1, 7]);
+        hosts[0].propose(1, "foo".into(), CommandMetas::default()).unwrap();
+        hosts[2].propose(7, "bar".into(), CommandMetas::default()).unwrap();
+        deliver_all(&mut hosts, &transport);
+
+        for host in &hosts {
+            let decided = |group| host.group(group).unwrap().decisions().iter().collect::<Vec<_>>();
+            assert_eq!(vec![(0, Bytes::from("foo"))], decided(1));
+            assert_eq!(vec![(0, Bytes::from("bar"))], decided(7));
+        }
+        assert!(hosts[0].group(1).unwrap().is_leader());
+        assert!(hosts[2].group(7).unwrap().is_leader());
+
+        assert_eq!(
+            Err(PaxosError::UnknownGroup(3)),
+            hosts[1].propose(3, "baz".into(), CommandMetas::default())
+        );
+    }
+
+    #[test]
+    fn multi_node_refuses_unknown_peers() {
+        let (mut hosts, transport) = cluster(&[1]);
+        let prepare = Command::Prepare { payload: (Ballot(0, 1), 0) };
+        let commands = vec![(1, prepare, CommandMetas::default())];
+        let envelope = GroupEnvelope { from: 5, commands };
+        assert_eq!(Err(PaxosError::UnknownNode(5)), hosts[0].receive(envelope));
+        assert!(transport.0.lock().unwrap().is_empty());
+        assert_eq!(None, hosts[0].group(1).unwrap().promised());
+    }
+
+    #[test]
+    fn multi_node_batches_heartbeats() {
+        let groups = (0..10).collect::<Vec<_>>();
+        let (mut hosts, transport) = cluster(&groups);
+        for group in &groups {
+            hosts[0].propose(*group, "foo".into(), CommandMetas::default()).unwrap();
+        }
+        deliver_all(&mut hosts, &transport);
+
+        hosts[0].tick();
+        let sent = transport.0.lock().unwrap().drain(..).collect::<Vec<_>>();
+        assert_eq!(vec![1, 2], sent.iter().map(|(node, _)| *node).collect::<Vec<_>>());
+        for (_, envelope) in sent {
+            assert_eq!(0, envelope.from);
+            assert_eq!(groups, envelope.commands.iter().map(|(g, ..)| *g).collect::<Vec<_>>());
+            assert!(envelope.commands.iter().all(|(_, cmd, _)| {
+                matches!(cmd, Command::Accept { payload: (_, slot_vals) } if slot_vals.is_empty())
+            }));
+        }
+    }
+
+    #[test]
+    fn multi_node_recovers_lost_leader() {
+        let (mut hosts, transport) = cluster(&[]);
+        for host in hosts.iter_mut() {
+            // peers are suspected as soon as they have been heard from
+            let replica = Node::new(host.transport(1), host.config.clone())
+                .with_failure_detector(FixedTimeout::new(Duration::from_millis(0)));
+            host.add_group(1, replica);
+        }
+        hosts[0].propose(1, "foo".into(), CommandMetas::default()).unwrap();
+        deliver_all(&mut hosts, &transport);
+        assert!(hosts[0].group(1).unwrap().is_leader());
+
+        // the leader is lost along with the first election of the next node
+        hosts[1].propose(1, "bar".into(), CommandMetas::default()).unwrap();
+        transport.0.lock().unwrap().clear();
+        assert_eq!(1, hosts[1].group(1).unwrap().queued_proposals());
+
+        hosts[1].tick();
+        loop {
+            let next = transport.0.lock().unwrap().pop_front();
+            match next {
+                Some((0, _)) => {}
+                Some((node, envelope)) => hosts[node as usize].receive(envelope).unwrap(),
+                None => break,
+            }
+        }
+        assert!(hosts[1].group(1).unwrap().is_leader());
+        let decided = hosts[1].group(1).unwrap().decisions().iter().collect::<Vec<_>>();
+        assert_eq!(vec![(0, Bytes::from("foo")), (1, Bytes::from("bar"))], decided);
+    }
+}
@@ -0,0 +1,336 @@
+//! Splits and merges of groups by key range.
+//!
+//! A shard operation is proposed to a group as a command and decided in its
+//! log like any other. The group's `ShardedState` recognizes the command
+//! through `ShardedState::shard_op` and the group freezes at its slot:
+//! later commands are not executed and proposals are refused. Every replica
+//! of the group freezes with the same state, so each host derives the same
+//! snapshots when `MultiNode::reshard` creates the new groups.
+//!
+//! A merge is decided by both of the groups merged, and the new group is
+//! created once both are frozen by the same operation, provided that their
+//! key ranges are adjacent. Frozen groups stay
+//! hosted so that lagging peers can still learn the decisions leading up
+//! to the operation.
+use super::{GroupId, GroupSender, GroupTransport, MultiNode};
+use crate::{
+    statemachine::{ReplicatedState, StateMachineReplica},
+    Configuration, Node, Proposed, Replica, Value,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// Split or merge of groups, decided in the logs of the groups involved
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShardOp {
+    /// Splits the group at a key. The keys before it move to `left` and
+    /// the other keys to `right`.
+    Split { at: Bytes, left: GroupId, right: GroupId },
+    /// Merges `left` with `right`, which holds the keys following those of
+    /// `left`, into `into`. Both groups must decide the operation.
+    Merge { left: GroupId, right: GroupId, into: GroupId },
+}
+
+/// Keys held by a group, from `start` up to `end` excluded. An empty start
+/// holds the keys from the first, and no end the keys up to the last.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyRange {
+    pub start: Bytes,
+    pub end: Option<Bytes>,
+}
+
+/// State machine partitioned by key, which can be split and merged.
+///
+/// The commands recognized by `shard_op` must freeze the group through
+/// `ReplicatedState::freezes`.
+pub trait ShardedState<V = Bytes>: ReplicatedState<V> + Sized {
+    /// Split or merge of the group requested by a command
+    fn shard_op(&self, command: &V) -> Option<ShardOp>;
+
+    /// Keys held by the state
+    fn key_range(&self) -> KeyRange;
+
+    /// Snapshots the state into the states of two new groups, one for the
+    /// keys before `at` and one for the keys from `at`
+    fn split(&self, at: &[u8]) -> Option<(Self, Self)>;
+
+    /// Snapshots the state along with the state of the group holding the
+    /// keys that follow, into the state of a new group
+    fn merge(&self, right: &Self) -> Option<Self>;
+}
+
+/// Replica of a group hosted by a `MultiNode`, which can be split and merged
+pub type ShardReplica<S, V = Bytes> =
+    StateMachineReplica<Node<GroupSender<Proposed<V>>, Proposed<V>>, S, Proposed<V>>;
+
+impl<V, T, S> MultiNode<T, ShardReplica<S, V>, Proposed<V>>
+where
+    V: Value,
+    T: GroupTransport<Proposed<V>>,
+    S: ShardedState<V>,
+{
+    /// Creates the groups resulting from the shard operations decided by
+    /// the hosted groups, returning the operations carried out. New groups
+    /// take the configuration of the frozen group in the next epoch.
+    pub fn reshard(&mut self) -> Vec<ShardOp> {
+        let frozen = self
+            .groups
+            .iter()
+            .filter_map(|(group, replica)| Some((*group, shard_op(replica)?)))
+            .collect::<Vec<_>>();
+
+        let mut done = Vec::new();
+        for (group, op) in frozen {
+            let created = match op {
+                ShardOp::Split { ref at, left, right } => self.split(group, at, left, right),
+                ShardOp::Merge { left, right, into } if group == left => {
+                    self.merge(&op, right, into)
+                }
+                ShardOp::Merge { .. } => false,
+            };
+            if created {
+                done.push(op);
+            }
+        }
+        done
+    }
+
+    fn split(&mut self, group: GroupId, at: &[u8], left: GroupId, right: GroupId) -> bool {
+        if self.groups.contains_key(&left) || self.groups.contains_key(&right) {
+            return false;
+        }
+        let parent = &self.groups[&group];
+        let (left_state, right_state) = match parent.state_machine().split(at) {
+            Some(states) => states,
+            None => {
+                warn!("State of group {} cannot be split", group);
+                return false;
+            }
+        };
+
+        let config = parent.inner().configuration();
+        let config = config.clone().with_cluster(config.cluster(), config.epoch() + 1);
+        self.add_shard(left, config.clone(), left_state);
+        self.add_shard(right, config, right_state);
+        true
+    }
+
+    fn merge(&mut self, op: &ShardOp, right: GroupId, into: GroupId) -> bool {
+        let left = match *op {
+            ShardOp::Merge { left, .. } => left,
+            ShardOp::Split { .. } => return false,
+        };
+        if self.groups.contains_key(&into) {
+            return false;
+        }
+        let (left, right) = match (self.groups.get(&left), self.groups.get(&right)) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return false,
+        };
+        // the right group may not have decided the merge yet
+        if shard_op(right).as_ref() != Some(op) {
+            return false;
+        }
+        let (left_range, right_range) =
+            (left.state_machine().key_range(), right.state_machine().key_range());
+        if left_range.end.as_ref() != Some(&right_range.start) {
+            warn!("Groups of {:?} do not hold adjacent keys", op);
+            return false;
+        }
+        let state = match left.state_machine().merge(right.state_machine()) {
+            Some(state) => state,
+            None => {
+                warn!("States of groups {:?} cannot be merged", op);
+                return false;
+            }
+        };
+
+        let (left, right) = (left.inner().configuration(), right.inner().configuration());
+        let epoch = left.epoch().max(right.epoch()) + 1;
+        let config = left.clone().with_cluster(left.cluster(), epoch);
+        self.add_shard(into, config, state);
+        true
+    }
+
+    fn add_shard(&mut self, group: GroupId, config: Configuration, state: S) {
+        let replica = Node::new(self.transport(group), config).state_machine(state);
+        self.add_group(group, replica);
+    }
+}
+
+/// Shard operation that froze the replica of a group
+fn shard_op<V: Value, S: ShardedState<V>>(replica: &ShardReplica<S, V>) -> Option<ShardOp> {
+    let (_, command) = replica.frozen()?;
+    replica.state_machine().shard_op(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::CommandMetas, error::PaxosError, multi::GroupEnvelope, NodeId, NodeMetadata,
+    };
+    use std::{
+        collections::{BTreeMap, VecDeque},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    enum KvOp {
+        Noop,
+        Put(String),
+        Shard(ShardOp),
+    }
+
+    impl Value for KvOp {
+        fn noop() -> KvOp {
+            KvOp::Noop
+        }
+
+        fn is_noop(&self) -> bool {
+            matches!(self, KvOp::Noop)
+        }
+    }
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Keys {
+        range: KeyRange,
+        keys: BTreeMap<String, u64>,
+    }
+
+    impl ReplicatedState<KvOp> for Keys {
+        type Response = ();
+
+        fn execute(&mut self, slot: u64, command: KvOp) {
+            if let KvOp::Put(key) = command {
+                self.keys.insert(key, slot);
+            }
+        }
+
+        fn freezes(&self, command: &KvOp) -> bool {
+            matches!(command, KvOp::Shard(_))
+        }
+    }
+
+    impl ShardedState<KvOp> for Keys {
+        fn shard_op(&self, command: &KvOp) -> Option<ShardOp> {
+            match command {
+                KvOp::Shard(op) => Some(op.clone()),
+                _ => None,
+            }
+        }
+
+        fn key_range(&self) -> KeyRange {
+            self.range.clone()
+        }
+
+        fn split(&self, at: &[u8]) -> Option<(Keys, Keys)> {
+            let (left, right) = self.keys.clone().into_iter().partition(|(k, _)| k.as_bytes() < at);
+            let at = Bytes::copy_from_slice(at);
+            let left_range = KeyRange { start: self.range.start.clone(), end: Some(at.clone()) };
+            let right_range = KeyRange { start: at, end: self.range.end.clone() };
+            Some((Keys { range: left_range, keys: left }, Keys { range: right_range, keys: right }))
+        }
+
+        fn merge(&self, right: &Keys) -> Option<Keys> {
+            let range = KeyRange { start: self.range.start.clone(), end: right.range.end.clone() };
+            let keys = self.keys.clone().into_iter().chain(right.keys.clone()).collect();
+            Some(Keys { range, keys })
+        }
+    }
+
+    type Envelope = GroupEnvelope<Proposed<KvOp>>;
+    type Queue = Arc<Mutex<VecDeque<(NodeId, Envelope)>>>;
+    type Host = MultiNode<QueueTransport, ShardReplica<Keys, KvOp>, Proposed<KvOp>>;
+
+    #[derive(Clone, Default)]
+    struct QueueTransport(Queue);
+
+    impl GroupTransport<Proposed<KvOp>> for QueueTransport {
+        fn send(&mut self, node: NodeId, _: &NodeMetadata, envelope: Envelope) {
+            self.0.lock().unwrap().push_back((node, envelope));
+        }
+    }
+
+    fn propose(hosts: &mut [Host], queue: &Queue, group: GroupId, op: KvOp) {
+        let replica = hosts[0].group_mut(group).unwrap();
+        replica.propose(op, CommandMetas::default()).unwrap();
+        hosts[0].flush();
+        loop {
+            let next = queue.lock().unwrap().pop_front();
+            match next {
+                Some((node, envelope)) => hosts[node as usize].receive(envelope).unwrap(),
+                None => return,
+            }
+        }
+    }
+
+    fn keys(host: &Host, group: GroupId) -> Vec<&str> {
+        host.group(group).unwrap().state_machine().keys.keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn shard_split_and_merge() {
+        let transport = QueueTransport::default();
+        let mut hosts = (0..3)
+            .map(|node| {
+                let peers = (0..3).filter(|n| *n != node).map(|n| (n, NodeMetadata::default()));
+                let config = Configuration::new(node, peers).with_cluster(9, 1);
+                let mut host = Host::new(transport.clone(), config.clone());
+                let replica = Node::new(host.transport(1), config).state_machine(Keys::default());
+                host.add_group(1, replica);
+                host
+            })
+            .collect::<Vec<_>>();
+        let queue = transport.0.clone();
+
+        for key in &["a", "m", "z"] {
+            propose(&mut hosts, &queue, 1, KvOp::Put(key.to_string()));
+        }
+        let split = ShardOp::Split { at: "m".into(), left: 2, right: 3 };
+        propose(&mut hosts, &queue, 1, KvOp::Shard(split.clone()));
+        for host in hosts.iter_mut() {
+            assert_eq!(Some((3, &KvOp::Shard(split.clone()))), host.group(1).unwrap().frozen());
+            assert_eq!(vec![split.clone()], host.reshard());
+            assert!(host.reshard().is_empty());
+            assert_eq!(vec![1, 2, 3], host.groups().collect::<Vec<_>>());
+            assert_eq!(vec!["a"], keys(host, 2));
+            assert_eq!(vec!["m", "z"], keys(host, 3));
+            assert_eq!(2, host.group(3).unwrap().inner().configuration().epoch());
+        }
+        let replica = hosts[1].group_mut(1).unwrap();
+        assert_eq!(
+            Some(PaxosError::Frozen { slot: 3 }),
+            replica.propose(KvOp::Put("b".into()), CommandMetas::default()).err()
+        );
+
+        // the new groups decide commands of their own
+        propose(&mut hosts, &queue, 3, KvOp::Put("q".into()));
+        assert_eq!(vec!["m", "q", "z"], keys(&hosts[2], 3));
+
+        // a merge needs to be decided by both groups
+        let merge = ShardOp::Merge { left: 2, right: 3, into: 4 };
+        propose(&mut hosts, &queue, 2, KvOp::Shard(merge.clone()));
+        assert!(hosts[0].reshard().is_empty());
+        propose(&mut hosts, &queue, 3, KvOp::Shard(merge.clone()));
+        for host in hosts.iter_mut() {
+            assert_eq!(vec![merge.clone()], host.reshard());
+            assert_eq!(vec!["a", "m", "q", "z"], keys(host, 4));
+            assert_eq!(3, host.group(4).unwrap().inner().configuration().epoch());
+        }
+
+        // groups that do not hold adjacent keys are not merged
+        let split = ShardOp::Split { at: "q".into(), left: 5, right: 6 };
+        propose(&mut hosts, &queue, 4, KvOp::Shard(split.clone()));
+        for host in hosts.iter_mut() {
+            assert_eq!(vec![split.clone()], host.reshard());
+        }
+        let merge = ShardOp::Merge { left: 6, right: 5, into: 7 };
+        propose(&mut hosts, &queue, 5, KvOp::Shard(merge.clone()));
+        propose(&mut hosts, &queue, 6, KvOp::Shard(merge));
+        for host in hosts.iter_mut() {
+            assert!(host.reshard().is_empty());
+            assert!(host.group(7).is_none());
+        }
+    }
+}
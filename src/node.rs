@@ -1,47 +1,428 @@
 use crate::{
     acceptor::{AcceptResponse, PrepareResponse},
+    catchup::{coalesce, Catchup, CatchupPoll, CATCHUP_CHUNK, MAX_CATCHUP_SLOTS},
     commands::*,
+    config::QuorumSet,
+    detector::{FailureDetector, NoFailureDetector},
+    error::{PaxosError, Result},
+    events::{EventSink, EventStream, NodeEvent},
+    metrics::{CommandKind, Metrics, NoMetrics},
     proposer::{Proposer, ProposerState},
+    status::{NodeStatus, PeerStatus, ProposerStatus, SlotStatus},
     window::{DecisionSet, SlotMutRef, SlotWindow},
-    Ballot, Configuration, NodeId, Replica, Slot,
+    Ballot, Configuration, NodeId, Replica, Slot, Value,
 };
 use bytes::Bytes;
-use std::mem;
+use std::{cmp::Reverse, collections::HashMap, mem, ops::Range, sync::Arc, time::Instant};
+
+/// Proposals given up on that are kept until taken, the oldest are dropped
+pub(crate) const MAX_FAILED_PROPOSALS: usize = 1024;
+
+/// Read awaiting the confirmation of the leader's ballot by a quorum
+struct PendingRead {
+    id: u64,
+    ballot: Ballot,
+    /// Slot following every slot that may have been decided
+    index: Slot,
+    acks: QuorumSet,
+}
 
 /// State manager for multi-paxos group
-pub struct Node<T> {
+pub struct Node<T, V = Bytes> {
     transport: T,
     config: Configuration,
-    proposer: Proposer,
-    window: SlotWindow,
+    proposer: Proposer<V>,
+    window: SlotWindow<V>,
+    metrics: Arc<dyn Metrics>,
+    events: EventStream,
+    /// Number of commands received
+    received: u64,
+    /// Value of `received` and the type of the last command from each peer
+    last_heard: HashMap<NodeId, (u64, CommandKind)>,
+    /// Span identifiers of the commands sent
+    spans: SpanIds,
+    /// Catchup of the slots decided by peers
+    catchup: Catchup,
+    /// Values proposed by this node as leader, by slot, until decided
+    proposed: HashMap<Slot, (V, CommandMetas)>,
+    /// Peers that accepted the leader's ballot since the last quorum check
+    acks: QuorumSet,
+    /// Heartbeats sent by the leader since the last quorum check
+    heartbeats: u32,
+    /// Proposals given up on after losing contact with a quorum
+    failed: Vec<V>,
+    /// Liveness of the peers
+    detector: Box<dyn FailureDetector>,
+    /// Consecutive elections yielded to a preferred peer
+    yields: u32,
+    /// Reads awaiting confirmation, and the identifier of the next read
+    reads: Vec<PendingRead>,
+    next_read: u64,
+    /// Reads confirmed or failed, until taken
+    confirmed_reads: Vec<(u64, Result<Slot>)>,
 }
 
-impl<T: Transport> Node<T> {
+impl<V: Value, T: Transport<V>> Node<T, V> {
     /// Node creation from a sender and starting configuration
-    pub fn new(transport: T, config: Configuration) -> Node<T> {
+    pub fn new(transport: T, config: Configuration) -> Node<T, V> {
         let (p1_quorum, p2_quorum) = config.quorum_size();
         let node = config.current();
+        let mut proposer = Proposer::new(node, p1_quorum);
+        if let Some(seed) = config.seed() {
+            proposer.set_seed(seed);
+        }
         Node {
             transport,
             config,
-            proposer: Proposer::new(node, p1_quorum),
+            proposer,
             window: SlotWindow::new(p2_quorum),
+            metrics: Arc::new(NoMetrics),
+            events: EventStream::new(node),
+            received: 0,
+            last_heard: HashMap::new(),
+            spans: SpanIds::new(node),
+            catchup: Catchup::default(),
+            proposed: HashMap::new(),
+            acks: QuorumSet::with_size(p2_quorum),
+            heartbeats: 0,
+            failed: Vec::new(),
+            detector: Box::new(NoFailureDetector),
+            yields: 0,
+            reads: Vec::new(),
+            next_read: 0,
+            confirmed_reads: Vec::new(),
         }
     }
 
-    /// Broadcast ACCEPT messages once the proposer has phase 1 quorum
-    fn drive_accept(&mut self, cmd_metas: CommandMetas) {
-        if !self.proposer.state().is_leader() {
-            return;
+    /// Reports protocol events of the node to the metrics
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Node<T, V> {
+        self.proposer.set_metrics(metrics.clone());
+        self.window.set_metrics(metrics.clone());
+        self.metrics = metrics;
+        self
+    }
+
+    /// Reports each step taken by the node to the sink
+    pub fn with_event_sink(mut self, sink: Arc<dyn EventSink>) -> Node<T, V> {
+        self.events.set_sink(sink);
+        self
+    }
+
+    /// Judges the liveness of peers with the detector. Proposals are not
+    /// forwarded to a suspected leader, the node runs for leadership instead.
+    pub fn with_failure_detector<D>(mut self, detector: D) -> Node<T, V>
+    where
+        D: FailureDetector + 'static,
+    {
+        self.detector = Box::new(detector);
+        self
+    }
+
+    /// Configuration of the node
+    pub fn configuration(&self) -> &Configuration {
+        &self.config
+    }
+
+    /// Snapshot of the proposer, the open window and the peers
+    pub fn status(&self) -> NodeStatus {
+        let proposer = match *self.proposer.state() {
+            ProposerState::Follower => ProposerStatus::Follower,
+            ProposerState::Candidate { proposal, ref promises } => {
+                ProposerStatus::Candidate { ballot: proposal, promises: promises.count() }
+            }
+            ProposerState::Leader { proposal } => ProposerStatus::Leader { ballot: proposal },
+        };
+
+        let mut peers = self
+            .config
+            .peer_node_ids()
+            .map(|node| {
+                let last_heard = self.last_heard.get(&node);
+                PeerStatus {
+                    node,
+                    last_heard: last_heard.map(|(received, _)| *received),
+                    last_command: last_heard.map(|(_, kind)| *kind),
+                }
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.node);
+
+        let highest_ballot = self.proposer.highest_observed_ballot();
+        let open_range = self.window.open_range();
+        NodeStatus {
+            node: self.config.current(),
+            proposer,
+            highest_ballot,
+            leader: highest_ballot.map(|Ballot(_, node)| node),
+            decided: open_range.start.checked_sub(1),
+            open_range,
+            slots: self
+                .window
+                .open_slots()
+                .map(|(slot, acceptor)| SlotStatus { slot, acceptor: acceptor.status() })
+                .collect(),
+            queued_proposals: self.proposer.queued_proposals(),
+            received: self.received,
+            peers,
         }
+    }
 
-        let bal = self.proposer.highest_observed_ballot().unwrap();
-        assert!(bal.1 == self.config.current());
+    /// Highest ballot seen by the proposer
+    pub(crate) fn highest_observed_ballot(&self) -> Option<Ballot> {
+        self.proposer.highest_observed_ballot()
+    }
 
-        // add queued proposals to new slots
-        for value in self.proposer.take_proposals() {
-            let mut slot = self.window.next_slot();
-            slot.acceptor().notice_value(bal, value.clone());
+    /// Highest ballot promised by the acceptors
+    pub(crate) fn promised(&self) -> Option<Ballot> {
+        self.window.max_promised()
+    }
+
+    /// Notes a received command along with the peer that sent it, if known.
+    /// Commands from nodes outside of the configuration are refused.
+    fn heard(&mut self, from: Option<NodeId>, kind: CommandKind) -> Result<()> {
+        // resolutions served by catchup may carry a ballot of this node
+        let from = from.filter(|node| *node != self.config.current());
+        if let Some(node) = from {
+            self.check_peer(node)?;
+        }
+        self.metrics.message_received(kind);
+        self.received += 1;
+        if let Some(node) = from {
+            self.last_heard.insert(node, (self.received, kind));
+            self.detector.arrived(node, Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Node holding the highest ballot, unless it is the current node or
+    /// is suspected to have failed
+    fn live_leader(&self) -> Option<NodeId> {
+        match self.proposer.highest_observed_ballot() {
+            Some(Ballot(_, leader)) if leader != self.config.current() => {
+                if self.detector.suspects(leader, Instant::now()) {
+                    self.events.emit(|| NodeEvent::LeaderSuspected { leader });
+                    None
+                } else {
+                    Some(leader)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Healthy peer most preferred as leader, if preferred over the current
+    /// node. Peers that were never heard from are not known to be healthy.
+    fn preferred_peer(&self) -> Option<NodeId> {
+        let own = self.config.preference(self.config.current());
+        let now = Instant::now();
+        self.config
+            .peer_node_ids()
+            .filter(|node| self.config.preference(*node) > own)
+            .filter(|node| self.last_heard.contains_key(node))
+            .filter(|node| !self.detector.suspects(*node, now))
+            .max_by_key(|node| (self.config.preference(*node), Reverse(*node)))
+    }
+
+    /// Node holding the highest ballot, unless it is the current node
+    fn known_leader(&self) -> Option<NodeId> {
+        let current = self.config.current();
+        let leader = self.proposer.highest_observed_ballot().map(|Ballot(_, node)| node);
+        leader.filter(|node| *node != current)
+    }
+
+    /// Refuses nodes that are not peers of the current node
+    fn check_peer(&self, node: NodeId) -> Result<()> {
+        match self.config.peer(node) {
+            Some(_) => Ok(()),
+            None => Err(PaxosError::UnknownNode(node)),
+        }
+    }
+
+    /// Refuses slots that the window cannot hold
+    fn check_slots<I: IntoIterator<Item = Slot>>(&self, slots: I) -> Result<()> {
+        let window = self.window.capacity_range();
+        match slots.into_iter().find(|slot| *slot >= window.end) {
+            Some(slot) => Err(PaxosError::SlotOutOfWindow { slot, window }),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses commands responding to a ballot that the current node did
+    /// not propose
+    fn check_own_ballot(&self, from: NodeId, bal: Ballot) -> Result<()> {
+        if bal.1 == self.config.current() {
+            Ok(())
+        } else {
+            let reason = "response to a ballot of another node";
+            Err(PaxosError::ProtocolViolation { from, reason })
+        }
+    }
+
+    /// Notes a ballot seen from a peer, which may preempt the proposer
+    fn observe_ballot(&mut self, bal: Ballot) {
+        let proposal = self.proposer.state().proposal();
+        self.proposer.observe_ballot(bal);
+        self.stepped_down(proposal, bal);
+    }
+
+    /// Emits an event if the proposer gave up its ballot
+    fn stepped_down(&self, proposal: Option<Ballot>, preempted_by: Ballot) {
+        if let Some(ballot) = proposal {
+            if self.proposer.state().is_follower() {
+                self.events.emit(|| NodeEvent::SteppedDown { ballot, preempted_by });
+            }
+        }
+    }
+
+    /// Notes another value accepted or decided for a slot. The value this
+    /// node proposed for the slot is returned if it lost the slot.
+    fn displaced(&mut self, slot: Slot, val: &V, decided: bool) -> Option<(V, CommandMetas)> {
+        let lost = match self.proposed.get(&slot) {
+            Some((proposed, _)) => proposed != val,
+            None => return None,
+        };
+        if lost {
+            self.events.emit(|| NodeEvent::ProposalPreempted { slot });
+            self.proposed.remove(&slot)
+        } else {
+            if decided {
+                self.events.emit(|| NodeEvent::ProposalDecided { slot });
+                self.proposed.remove(&slot);
+            }
+            None
+        }
+    }
+
+    /// Redirects a proposal to the leader, queues it during an election or
+    /// reserves a slot for it
+    fn propose(&mut self, val: V, cmd_metas: CommandMetas) -> Result<()> {
+        match *self.proposer.state() {
+            ProposerState::Follower => match self.live_leader() {
+                // redirect to the node holding the highest ballot
+                Some(leader) => {
+                    self.events.emit(|| NodeEvent::ProposalForwarded { leader });
+                    self.send(leader, Command::Proposal { payload: val }, cmd_metas)?;
+                }
+                _ => {
+                    self.events.emit(|| NodeEvent::ProposalQueued);
+                    self.proposer.push_proposal(val, cmd_metas.clone());
+                    self.propose_leadership(cmd_metas);
+                }
+            },
+            ProposerState::Candidate { .. } => {
+                // still waiting for promises, queue up the value
+                // TODO: should this re-send some PREPARE messages?
+                self.events.emit(|| NodeEvent::ProposalQueued);
+                self.proposer.push_proposal(val, cmd_metas);
+            }
+            ProposerState::Leader { proposal: bal } => {
+                // node is the distinguished proposer
+                self.check_slots(Some(self.window.open_range().end))?;
+                let slot = {
+                    let mut slot_ref = self.window.next_slot();
+                    slot_ref.acceptor().notice_value(bal, val.clone());
+                    slot_ref.slot()
+                };
+                self.proposed.insert(slot, (val.clone(), cmd_metas.clone()));
+                self.metrics.accept_sent(slot);
+                self.events.emit(|| NodeEvent::AcceptSent { ballot: bal, slots: vec![slot] });
+                self.broadcast(Command::Accept { payload: (bal, vec![(slot, val)]) }, cmd_metas);
+            }
+        }
+        Ok(())
+    }
+
+    /// Proposes again the values that lost their slot
+    fn repropose(&mut self, lost: Vec<(V, CommandMetas)>) -> Result<()> {
+        for (val, cmd_metas) in lost {
+            self.propose(val, cmd_metas)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new interval in which the leader must hear from a quorum
+    fn reset_quorum_check(&mut self) {
+        self.acks = QuorumSet::with_size(self.config.quorum_size().1);
+        self.acks.insert(self.config.current());
+        self.heartbeats = 0;
+    }
+
+    /// Steps down from leadership if no phase 2 quorum accepted the
+    /// leader's ballot within the election interval. Pending proposals are
+    /// failed since they cannot be decided without a quorum.
+    fn check_quorum(&mut self) -> bool {
+        self.heartbeats += 1;
+        if self.heartbeats < self.config.election_interval() {
+            return true;
+        }
+        if self.acks.has_quorum() {
+            self.reset_quorum_check();
+            return true;
+        }
+
+        if let Some(ballot) = self.proposer.step_down() {
+            let queued = self.proposer.take_proposals().into_iter().map(|(val, _)| val);
+            let failed = self.proposed.drain().map(|(_, (val, _))| val).chain(queued);
+            let count = self.failed.len();
+            self.failed.extend(failed);
+            let failed = self.failed.len() - count;
+            self.events.emit(|| NodeEvent::QuorumLost { ballot, failed });
+
+            let dropped = self.failed.len().saturating_sub(MAX_FAILED_PROPOSALS);
+            if dropped > 0 {
+                warn!("Dropping {} failed proposals that were never taken", dropped);
+                self.failed.drain(..dropped);
+            }
+        }
+        false
+    }
+
+    /// Starts Phase 1 with a new ballot
+    fn start_election(&mut self, cmd_metas: CommandMetas) {
+        self.yields = 0;
+        let bal = self.proposer.prepare();
+        self.events.emit(|| NodeEvent::ElectionStarted { ballot: bal });
+        let decided = self.window.open_range().start;
+        self.broadcast(Command::Prepare { payload: (bal, decided) }, cmd_metas);
+    }
+
+    /// Leaves the election to a preferred peer, forwarding it the queued
+    /// proposals so that it runs for leadership. A node yields at most for
+    /// the election interval in a row, in case the peer does not run.
+    fn yield_election(&mut self) -> bool {
+        if self.yields >= self.config.election_interval() {
+            return false;
+        }
+        let peer = match self.preferred_peer() {
+            Some(peer) => peer,
+            None => return false,
+        };
+        self.yields += 1;
+        self.events.emit(|| NodeEvent::ElectionYielded { to: peer });
+        for (proposal, cmd_metas) in self.proposer.take_proposals() {
+            self.events.emit(|| NodeEvent::ProposalForwarded { leader: peer });
+            self.send(peer, Command::Proposal { payload: (proposal) }, cmd_metas).unwrap_or(());
+        }
+        true
+    }
+
+    /// Broadcast ACCEPT messages once the proposer has phase 1 quorum
+    fn drive_accept(&mut self, cmd_metas: CommandMetas) {
+        let bal = match *self.proposer.state() {
+            ProposerState::Leader { proposal } => proposal,
+            _ => return,
+        };
+
+        // add queued proposals to new slots, remembering the traced ones
+        let mut traced = HashMap::new();
+        for (value, metas) in self.proposer.take_proposals() {
+            let mut slot_ref = self.window.next_slot();
+            slot_ref.acceptor().notice_value(bal, value.clone());
+            let slot = slot_ref.slot();
+            if metas.is_traced() {
+                traced.insert(slot, metas.clone());
+            }
+            self.proposed.insert(slot, (value, metas));
         }
 
         // queue up all accepts
@@ -56,15 +437,15 @@ impl<T: Transport> Node<T> {
                             open_slot.acceptor().notice_value(bal, val.clone());
                             Some((slot, val))
                         } else {
-                            open_slot.acceptor().notice_value(bal, Bytes::default());
-                            Some((slot, Bytes::default()))
+                            open_slot.acceptor().notice_value(bal, V::noop());
+                            Some((slot, V::noop()))
                         }
                     }
                     SlotMutRef::Empty(empty_slot) => {
                         // fill the hole with an empty slot
                         let mut slot = empty_slot.fill();
-                        slot.acceptor().notice_value(bal, Bytes::default());
-                        Some((slot.slot(), Bytes::default()))
+                        slot.acceptor().notice_value(bal, V::noop());
+                        Some((slot.slot(), V::noop()))
                     }
                     _ => None,
                 }
@@ -72,75 +453,154 @@ impl<T: Transport> Node<T> {
             .collect::<Vec<_>>();
 
         // send out the accepts
+        for (slot, _) in &accepts {
+            self.metrics.accept_sent(*slot);
+        }
         if !accepts.is_empty() {
-            self.broadcast(Command::Accept { payload: (bal, accepts) }, cmd_metas);
+            self.events.emit(|| NodeEvent::AcceptSent {
+                ballot: bal,
+                slots: accepts.iter().map(|(slot, _)| *slot).collect(),
+            });
+
+            // traced proposals are sent on their own to continue their trace
+            let (traced_accepts, accepts): (Vec<_>, Vec<_>) =
+                accepts.into_iter().partition(|(slot, _)| traced.contains_key(slot));
+            for (slot, val) in traced_accepts {
+                let metas = traced.remove(&slot).unwrap();
+                self.broadcast(Command::Accept { payload: (bal, vec![(slot, val)]) }, metas);
+            }
+            if !accepts.is_empty() {
+                self.broadcast(Command::Accept { payload: (bal, accepts) }, cmd_metas);
+            }
         }
     }
 
     /// Forwards pending proposals to the new leader
-    fn forward(&mut self, cmd_metas: CommandMetas) {
+    fn forward(&mut self) -> Result<()> {
         if !self.proposer.state().is_follower() || self.proposer.is_proposal_queue_empty() {
-            return;
+            return Ok(());
         }
 
-        let proposals = self.proposer.take_proposals();
-        if let Some(Ballot(_, node)) = self.proposer.highest_observed_ballot() {
-            for proposal in proposals.into_iter() {
-                self.send(node, Command::Proposal { payload: (proposal) }, cmd_metas.clone());
+        match self.live_leader() {
+            Some(node) => {
+                for (proposal, cmd_metas) in self.proposer.take_proposals() {
+                    self.events.emit(|| NodeEvent::ProposalForwarded { leader: node });
+                    self.send(node, Command::Proposal { payload: (proposal) }, cmd_metas)?;
+                }
             }
+            // the proposals stay queued while the node runs for leadership
+            None => self.propose_leadership(CommandMetas::default()),
+        }
+        Ok(())
+    }
+
+    /// Requests the decided values of the holes preceding the slots known to
+    /// be decided, one batch of slots at a time
+    fn request_catchup(&mut self, from: NodeId, cmd_metas: CommandMetas) -> Result<()> {
+        let start = self.window.open_range().start;
+        let node = self.config.current();
+        let peer = match self.catchup.poll(start, self.received) {
+            CatchupPoll::Wait => return Ok(()),
+            // a candidate may hold the highest ballot itself, in which case
+            // the node that sent the resolution is the one to ask, unless
+            // the resolution was decided at a ballot of this node
+            CatchupPoll::Request => match self.proposer.highest_observed_ballot() {
+                Some(Ballot(_, leader)) if leader != node => Some(leader),
+                _ if from != node => Some(from),
+                _ => self.next_peer(node),
+            },
+            CatchupPoll::Retry(peer) => self.next_peer(peer),
+        };
+        let peer = match peer {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        let end = self
+            .catchup
+            .target()
+            .min(self.window.capacity_range().end)
+            .min(start + MAX_CATCHUP_SLOTS);
+        let slots = (start..end)
+            .filter(|slot| !matches!(self.window.slot_mut(*slot), SlotMutRef::Resolved(..)));
+        let ranges = coalesce(slots);
+        trace!("Sending catchup for slots {:?} to {}", ranges, peer);
+        self.catchup.requested(peer, end, start, self.received);
+        self.metrics.catchup_requested(ranges.iter().map(|r| (r.end - r.start) as usize).sum());
+        self.events.emit(|| NodeEvent::CatchupRequested { from: peer, slots: ranges.clone() });
+        self.send(peer, Command::Catchup { payload: (node, ranges) }, cmd_metas)
+    }
+
+    /// Peer following `node` in the order of node identifiers, wrapping around
+    fn next_peer(&self, node: NodeId) -> Option<NodeId> {
+        let current = self.config.current();
+        let mut peers = self.config.peer_node_ids().filter(|n| *n != current).collect::<Vec<_>>();
+        peers.sort();
+        peers.iter().find(|n| **n > node).or_else(|| peers.first()).copied()
+    }
+
+    /// Metadata of a command sent in reaction to a command with `cmd_metas`
+    fn child_metas(&mut self, cmd_metas: &CommandMetas) -> CommandMetas {
+        let mut child = cmd_metas.child(self.config.current(), self.spans.next());
+        child.cluster = self.config.cluster();
+        child.epoch = self.config.epoch();
+        child
+    }
+
+    /// Refuses commands sent by nodes of other clusters or epochs. Proposals
+    /// come from clients, which do not belong to the cluster.
+    fn check_identity(&self, cmd: &Command<V>, cmd_metas: &CommandMetas) -> Result<()> {
+        if let Command::Proposal { .. } = cmd {
+            return Ok(());
         }
+        if cmd_metas.cluster != self.config.cluster() {
+            return Err(PaxosError::WrongCluster { cluster: cmd_metas.cluster });
+        }
+        if cmd_metas.epoch != self.config.epoch() {
+            return Err(PaxosError::WrongEpoch { epoch: cmd_metas.epoch });
+        }
+        Ok(())
     }
 
+    /// Sends a command caused by a command with `cmd_metas` to a peer
     #[inline(always)]
-    fn send(&mut self, node: NodeId, cmd: Command, cmd_metas: CommandMetas) {
-        self.transport.send(node, &self.config[node], cmd, cmd_metas)
+    fn send(&mut self, node: NodeId, cmd: Command<V>, cmd_metas: CommandMetas) -> Result<()> {
+        let cmd_metas = self.child_metas(&cmd_metas);
+        let meta = self.config.peer(node).ok_or(PaxosError::UnknownNode(node))?;
+        self.metrics.message_sent(node, CommandKind::from(&cmd));
+        self.transport.send(node, meta, cmd, cmd_metas);
+        Ok(())
     }
 
+    /// Sends a command caused by a command with `cmd_metas` to every peer
     #[inline(always)]
-    fn broadcast(&mut self, cmd: Command, cmd_metas: CommandMetas) {
-        for node in self.config.peer_node_ids().into_iter() {
-            self.transport.send(node, &self.config[node], cmd.clone(), cmd_metas.clone());
+    fn broadcast(&mut self, cmd: Command<V>, cmd_metas: CommandMetas) {
+        let cmd_metas = self.child_metas(&cmd_metas);
+        for (node, meta) in self.config.peers() {
+            self.metrics.message_sent(node, CommandKind::from(&cmd));
+            self.transport.send(node, meta, cmd.clone(), cmd_metas.clone());
         }
     }
 }
 
-impl<T: Transport> Commander for Node<T> {
-    fn proposal(&mut self, val: Bytes, cmd_metas: CommandMetas) {
-        // redirect to the distinguished proposer or start PREPARE
-        match *self.proposer.state() {
-            ProposerState::Follower => {
-                // we don't care if there's a leader already,
-                // we'll go through the 2 phases everytime
-                self.proposer.push_proposal(val);
-                self.propose_leadership(cmd_metas);
-            }
-            ProposerState::Candidate { .. } => {
-                // still waiting for promises, queue up the value
-                // TODO: should this re-send some PREPARE messages?
-                self.proposer.push_proposal(val);
-            }
-            ProposerState::Leader { proposal: bal } => {
-                // node is the distinguished proposer
-                let slot = {
-                    let mut slot_ref = self.window.next_slot();
-                    slot_ref.acceptor().notice_value(bal, val.clone());
-                    slot_ref.slot()
-                };
-                self.broadcast(
-                    Command::Accept { payload: (bal, vec![(slot, val.clone())]) },
-                    cmd_metas,
-                );
-            }
-        }
+impl<V: Value, T: Transport<V>> Commander<V> for Node<T, V> {
+    fn proposal(&mut self, val: V, cmd_metas: CommandMetas) -> Result<()> {
+        self.heard(None, CommandKind::Proposal)?;
+        self.propose(val, cmd_metas)
     }
 
-    fn prepare(&mut self, bal: Ballot, cmd_metas: CommandMetas) {
-        self.proposer.observe_ballot(bal);
+    fn prepare(&mut self, bal: Ballot, decided: Slot, cmd_metas: CommandMetas) -> Result<()> {
+        self.heard(Some(bal.1), CommandKind::Prepare)?;
+        self.observe_ballot(bal);
 
         let node_id = self.config.current();
 
+        // decided slots the candidate has not learned are reported as well,
+        // otherwise it could propose a different value for them
         let mut accepted = Vec::new();
-        for slot in self.window.open_range() {
+        let mut rejected = None;
+        let end = self.window.open_range().end;
+        for slot in decided.min(end)..end {
             match self.window.slot_mut(slot) {
                 SlotMutRef::Open(ref mut open_ref) => {
                     match open_ref.acceptor().receive_prepare(bal) {
@@ -148,21 +608,13 @@ impl<T: Transport> Commander for Node<T> {
                             accepted.push((slot, bal, val));
                         }
                         PrepareResponse::Reject { proposed, preempted } => {
-                            // found a slot that accepted a higher ballot, send the reject
-                            let node = bal.1;
-                            self.transport.send(
-                                node,
-                                &self.config[node],
-                                Command::Reject { payload: (node_id, proposed, preempted) },
-                                cmd_metas,
-                            );
-                            return;
+                            rejected = Some((proposed, preempted));
+                            break;
                         }
                         _ => {}
                     }
                 }
                 SlotMutRef::Resolved(bal, val) => {
-                    // TODO: is this the right thing to do here?????
                     accepted.push((slot, bal, val));
                 }
 
@@ -170,82 +622,132 @@ impl<T: Transport> Commander for Node<T> {
                     warn!("Empty slot {} detected in the middle of the open range", slot);
                 }
                 SlotMutRef::ResolutionTruncated => {
-                    unreachable!("Cannot be resolved in the middle of the open range")
+                    // executed decisions are no longer tracked, the candidate
+                    // learns them through catchup instead
                 }
             }
         }
-        self.send(bal.1, Command::Promise { payload: (node_id, bal, accepted) }, cmd_metas);
+        if let Some((proposed, preempted)) = rejected {
+            // found a slot that accepted a higher ballot, send the reject
+            let node = bal.1;
+            self.events.emit(|| NodeEvent::PrepareRejected {
+                from: node,
+                proposed,
+                promised: preempted,
+            });
+            let reject = Command::Reject { payload: (node_id, proposed, preempted) };
+            return self.send(node, reject, cmd_metas);
+        }
+        let slots = self.window.open_range();
+        self.events.emit(|| NodeEvent::Promised { ballot: bal, slots });
+        self.send(bal.1, Command::Promise { payload: (node_id, bal, accepted) }, cmd_metas)
     }
 
     fn promise(
         &mut self,
         node: NodeId,
         bal: Ballot,
-        accepted: Vec<(Slot, Ballot, Bytes)>,
+        accepted: Vec<(Slot, Ballot, V)>,
         cmd_metas: CommandMetas,
-    ) {
+    ) -> Result<()> {
+        self.heard(Some(node), CommandKind::Promise)?;
+        self.check_own_ballot(node, bal)?;
+        self.check_slots(accepted.iter().map(|(slot, ..)| *slot))?;
         if !self.proposer.state().is_candidate() {
-            return;
+            return Ok(());
         }
 
         self.proposer.receive_promise(node, bal);
+        if self.proposer.state().is_leader() {
+            self.events.emit(|| NodeEvent::BecameLeader { ballot: bal });
+            self.reset_quorum_check();
+        }
 
-        // track highest proposals
+        // track highest proposals, queueing again the values of this node
+        // that they replace
         for (slot, bal, val) in accepted.into_iter() {
-            match self.window.slot_mut(slot) {
+            let preempted = match self.window.slot_mut(slot) {
                 SlotMutRef::Open(ref mut open_slot) => {
-                    open_slot.acceptor().notice_value(bal, val);
+                    open_slot.acceptor().notice_value(bal, val.clone())
                 }
                 SlotMutRef::Empty(empty_slot) => {
-                    empty_slot.fill().acceptor().notice_value(bal, val);
+                    empty_slot.fill().acceptor().notice_value(bal, val.clone())
+                }
+                _ => None,
+            };
+            if preempted.is_some() {
+                if let Some((lost, metas)) = self.displaced(slot, &val, false) {
+                    self.proposer.push_proposal(lost, metas);
                 }
-                _ => {}
             }
         }
 
         // if we have phase 1 quorum, we can send out ACCEPT messages
         self.drive_accept(cmd_metas);
+        Ok(())
     }
 
-    fn accept(&mut self, bal: Ballot, slot_values: Vec<(Slot, Bytes)>, cmd_metas: CommandMetas) {
-        self.proposer.observe_ballot(bal);
+    fn accept(
+        &mut self,
+        bal: Ballot,
+        slot_values: Vec<(Slot, V)>,
+        cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        self.heard(Some(bal.1), CommandKind::Accept)?;
+        self.check_slots(slot_values.iter().map(|(slot, _)| *slot))?;
+        self.observe_ballot(bal);
 
         let current_node = self.config.current();
         let mut accepted_slots = Vec::with_capacity(slot_values.len());
+        let mut lost = Vec::new();
         for (slot, val) in slot_values.into_iter() {
             let acceptor_res = match self.window.slot_mut(slot) {
                 SlotMutRef::Empty(empty_slot) => {
                     let mut open_slot = empty_slot.fill();
-                    open_slot.acceptor().receive_accept(bal, val)
+                    open_slot.acceptor().receive_accept(bal, val.clone())
                 }
                 SlotMutRef::Open(ref mut open_slot) => {
-                    open_slot.acceptor().receive_accept(bal, val)
+                    open_slot.acceptor().receive_accept(bal, val.clone())
                 }
-                _ => return,
+                _ => return Ok(()),
             };
 
             match acceptor_res {
-                AcceptResponse::Accepted { .. } => {
-                    // TODO: what do we do w/ the preempted proposal
+                AcceptResponse::Accepted { preempted_proposal, .. } => {
+                    // a value proposed by this node with a lower ballot can
+                    // no longer be chosen for the slot
+                    if preempted_proposal.is_some() {
+                        lost.extend(self.displaced(slot, &val, false));
+                    }
                     accepted_slots.push(slot);
                 }
                 AcceptResponse::Reject { proposed, preempted } => {
-                    self.send(
+                    self.events.emit(|| NodeEvent::AcceptRejected {
+                        from: bal.1,
+                        proposed,
+                        promised: preempted,
+                    });
+                    return self.send(
                         bal.1,
                         Command::Reject { payload: (current_node, proposed, preempted) },
                         cmd_metas,
                     );
-                    return;
                 }
                 _ => {}
             }
         }
 
+        self.events.emit(|| NodeEvent::Accepted {
+            from: bal.1,
+            ballot: bal,
+            slots: accepted_slots.clone(),
+        });
         self.send(
             bal.1,
             Command::Accepted { payload: (current_node, bal, accepted_slots) },
             cmd_metas,
-        );
+        )?;
+        self.repropose(lost)
     }
 
     fn reject(
@@ -253,15 +755,41 @@ impl<T: Transport> Commander for Node<T> {
         node: NodeId,
         proposed: Ballot,
         promised: Ballot,
-        cmd_metas: CommandMetas,
-    ) {
+        _cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        self.heard(Some(node), CommandKind::Reject)?;
+        self.check_own_ballot(node, proposed)?;
+        if promised <= proposed {
+            let reason = "rejected a ballot with a lower ballot";
+            return Err(PaxosError::ProtocolViolation { from: node, reason });
+        }
+        if promised.1 != self.config.current() {
+            self.check_peer(promised.1)?;
+        }
+
+        self.events.emit(|| NodeEvent::RejectReceived { from: node, proposed, promised });
+
         // reject preempted ballot within the proposer
+        let proposal = self.proposer.state().proposal();
         self.proposer.receive_reject(node, proposed, promised);
-        self.forward(cmd_metas);
+        self.stepped_down(proposal, promised);
+        self.forward()
     }
 
-    fn accepted(&mut self, node: NodeId, bal: Ballot, slots: Vec<Slot>, cmd_metas: CommandMetas) {
-        self.proposer.observe_ballot(bal);
+    fn accepted(
+        &mut self,
+        node: NodeId,
+        bal: Ballot,
+        slots: Vec<Slot>,
+        cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        self.heard(Some(node), CommandKind::Accepted)?;
+        self.check_own_ballot(node, bal)?;
+        self.check_slots(slots.iter().copied())?;
+        self.observe_ballot(bal);
+        if self.proposer.state().proposal() == Some(bal) {
+            self.acks.insert(node);
+        }
 
         // notify each slot of the accepted, collecting resolutions
         let mut resolutions = Vec::with_capacity(slots.len());
@@ -269,29 +797,66 @@ impl<T: Transport> Commander for Node<T> {
             match self.window.slot_mut(slot) {
                 SlotMutRef::Open(ref mut open_ref) => {
                     open_ref.acceptor().receive_accepted(node, bal);
-                    open_ref
-                        .acceptor()
-                        .resolution()
-                        .into_iter()
-                        .for_each(|res| resolutions.push((slot, res.1)));
+                    if let Some((_, val)) = open_ref.acceptor().resolution() {
+                        self.metrics.slot_resolved(slot);
+                        resolutions.push((slot, val));
+                    }
                 }
                 SlotMutRef::Empty(_) => {
                     warn!("Received accepted() for slot {} which is unknown", slot);
                 }
-                _ => return,
+                _ => return Ok(()),
             }
         }
 
         if !resolutions.is_empty() {
+            self.events.emit(|| NodeEvent::Resolved {
+                ballot: bal,
+                slots: resolutions.iter().map(|(slot, _)| *slot).collect(),
+            });
+            let lost = resolutions
+                .iter()
+                .filter_map(|(slot, val)| self.displaced(*slot, val, true))
+                .collect();
             resolutions.shrink_to_fit();
-            self.broadcast(Command::Resolution { payload: (bal, resolutions) }, cmd_metas);
+            self.broadcast(Command::Resolution { payload: (bal, resolutions) }, cmd_metas.clone());
+            self.repropose(lost)?;
         }
+        Ok(())
     }
 
-    fn resolution(&mut self, bal: Ballot, slot_vals: Vec<(Slot, Bytes)>, cmd_metas: CommandMetas) {
-        self.proposer.observe_ballot(bal);
+    fn resolution(
+        &mut self,
+        bal: Ballot,
+        slot_vals: Vec<(Slot, V)>,
+        cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        self.heard(Some(bal.1), CommandKind::Resolution)?;
+        for (slot, val) in &slot_vals {
+            // a slot is only ever decided to a single value
+            if let SlotMutRef::Resolved(_, decided) = self.window.slot_mut(*slot) {
+                if decided != *val {
+                    let reason = "resolution conflicts with a decided value";
+                    return Err(PaxosError::ProtocolViolation { from: bal.1, reason });
+                }
+            }
+        }
+        self.observe_ballot(bal);
+        self.events.emit(|| NodeEvent::ResolutionReceived {
+            ballot: bal,
+            slots: slot_vals.iter().map(|(slot, _)| *slot).collect(),
+        });
 
+        // values beyond the window are learned again through catchup once
+        // the window has moved forward
+        let capacity = self.window.capacity_range();
+        let mut lost = Vec::new();
         for (slot, val) in slot_vals.into_iter() {
+            self.catchup.observe(slot);
+            if slot >= capacity.end {
+                continue;
+            }
+            lost.extend(self.displaced(slot, &val, true));
             match self.window.slot_mut(slot) {
                 SlotMutRef::Empty(empty_slot) => empty_slot.fill().acceptor().resolve(bal, val),
                 SlotMutRef::Open(ref mut open) => open.acceptor().resolve(bal, val),
@@ -299,78 +864,173 @@ impl<T: Transport> Commander for Node<T> {
             }
         }
 
-        // Send catchup for holds in the decision making
-        // We can skip catchup if we're caught up and the range only
-        // contains one slot
-        let range = self.window.open_range();
-        if range.end > range.start + 1 {
-            let slots = range
-                .filter(|slot| {
-                    if let SlotMutRef::Resolved(..) = self.window.slot_mut(*slot) {
-                        false
-                    } else {
-                        true
+        self.repropose(lost)?;
+        self.request_catchup(bal.1, cmd_metas)
+    }
+
+    fn catchup(
+        &mut self,
+        node: NodeId,
+        mut ranges: Vec<Range<Slot>>,
+        cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        self.heard(Some(node), CommandKind::Catchup)?;
+        ranges.sort_by_key(|range| range.start);
+
+        // any replica serves the slots it knows to be decided, at most
+        // `MAX_CATCHUP_SLOTS` of them for each request
+        let open_end = self.window.open_range().end;
+        let mut slots = ranges
+            .into_iter()
+            .flat_map(|range| range.start..range.end.min(open_end))
+            .take(MAX_CATCHUP_SLOTS as usize);
+
+        let mut chunks: Vec<(Ballot, Vec<(Slot, V)>)> = Vec::new();
+        let mut served = Vec::new();
+        for slot in &mut slots {
+            if let SlotMutRef::Resolved(bal, val) = self.window.slot_mut(slot) {
+                // a resolution holds a single ballot
+                match chunks.last_mut() {
+                    Some((b, buf)) if *b == bal && buf.len() < CATCHUP_CHUNK => {
+                        buf.push((slot, val))
                     }
-                })
-                .collect::<Vec<Slot>>();
-            trace!("Sending catchup for slots {:?}", slots);
-            let leader = self.proposer.highest_observed_ballot().unwrap().1;
-            let node = self.config.current();
-            self.send(leader, Command::Catchup { payload: (node, slots) }, cmd_metas);
+                    _ => chunks.push((bal, vec![(slot, val)])),
+                }
+                served.push(slot);
+            }
         }
-    }
 
-    fn catchup(&mut self, node: NodeId, mut slots: Vec<Slot>, cmd_metas: CommandMetas) {
-        // TODO: do we want to redirect at this point? Dropping is certainly safer
-        if !self.is_leader() {
-            return;
+        self.metrics.catchup_served(served.len());
+        if !served.is_empty() {
+            self.events.emit(|| NodeEvent::CatchupServed { to: node, slots: coalesce(served) });
         }
+        for (bal, buf) in chunks {
+            self.send(node, Command::Resolution { payload: (bal, buf) }, cmd_metas.clone())?;
+        }
+        Ok(())
+    }
+
+    fn handover(&mut self, bal: Ballot, decided: Slot, cmd_metas: CommandMetas) -> Result<()> {
+        self.heard(Some(bal.1), CommandKind::Handover)?;
+        self.observe_ballot(bal);
 
-        slots.sort();
+        // a stale offer, or an election already under way
+        let follower = self.proposer.state().is_follower();
+        if !follower || self.proposer.highest_observed_ballot() != Some(bal) {
+            return Ok(());
+        }
 
-        let mut buf = Vec::with_capacity(slots.len());
-        let mut run_bal: Option<Ballot> = None;
+        // the leader offers again with its next heartbeat, by which time the
+        // node has hopefully caught up
+        if self.window.open_range().start < decided {
+            self.catchup.observe(decided - 1);
+            return self.request_catchup(bal.1, cmd_metas);
+        }
+        self.start_election(cmd_metas);
+        Ok(())
+    }
 
-        for slot in slots.into_iter() {
-            if let SlotMutRef::Resolved(bal, val) = self.window.slot_mut(slot) {
-                // if we hit a run with a different ballot, send the resolutions we have so far
-                if let Some(b) = run_bal {
-                    if b != bal && !buf.is_empty() {
-                        let next_buf_cap = buf.capacity().saturating_sub(buf.len());
-                        let send_buf = mem::replace(&mut buf, Vec::with_capacity(next_buf_cap));
-                        self.transport.send(
-                            node,
-                            &self.config[node],
-                            Command::Resolution { payload: (b, send_buf) },
-                            cmd_metas.clone(),
-                        );
-                    }
-                }
+    fn confirm(&mut self, bal: Ballot, read: u64, cmd_metas: CommandMetas) -> Result<()> {
+        self.heard(Some(bal.1), CommandKind::Confirm)?;
+        self.observe_ballot(bal);
 
-                run_bal = Some(bal);
-                buf.push((slot, val));
+        // a promise of a higher ballot may have let another leader decide
+        // slots that the read would miss
+        let current_node = self.config.current();
+        let response = match self.window.max_promised() {
+            Some(promised) if promised > bal => {
+                Command::Reject { payload: (current_node, bal, promised) }
             }
+            _ => Command::Confirmed { payload: (current_node, bal, read) },
+        };
+        self.send(bal.1, response, cmd_metas)
+    }
+
+    fn confirmed(
+        &mut self,
+        node: NodeId,
+        bal: Ballot,
+        read: u64,
+        _cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        self.heard(Some(node), CommandKind::Confirmed)?;
+        self.check_own_ballot(node, bal)?;
+        if self.proposer.state().proposal() == Some(bal) {
+            self.acks.insert(node);
+        }
+
+        let i = match self.reads.iter().position(|r| r.id == read && r.ballot == bal) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        self.reads[i].acks.insert(node);
+        if self.reads[i].acks.has_quorum() {
+            let read = self.reads.remove(i);
+            self.confirmed_reads.push((read.id, Ok(read.index)));
         }
+        Ok(())
+    }
+}
 
-        if !buf.is_empty() && run_bal.is_some() {
-            self.send(node, Command::Resolution { payload: (run_bal.unwrap(), buf) }, cmd_metas);
+impl<V: Value, T: Transport<V>> Receiver<V> for Node<T, V> {
+    fn receive(&mut self, command: Command<V>, cmd_metas: CommandMetas) -> Result<()> {
+        let res = self
+            .check_identity(&command, &cmd_metas)
+            .and_then(|_| self.dispatch(command, cmd_metas));
+        if let Err(e) = &res {
+            self.metrics.message_refused(e);
         }
+        res
     }
 }
 
-impl<T: Transport> Replica for Node<T> {
+impl<V: Value, T: Transport<V>> Replica<V> for Node<T, V> {
+    fn node(&self) -> NodeId {
+        self.config.current()
+    }
+
+    fn is_peer(&self, node: NodeId) -> bool {
+        self.config.peer(node).is_some()
+    }
+
     fn propose_leadership(&mut self, cmd_metas: CommandMetas) {
         match *self.proposer.state() {
             ProposerState::Candidate { proposal, .. } => {
-                self.broadcast(Command::Prepare { payload: (proposal) }, cmd_metas)
+                let decided = self.window.open_range().start;
+                self.broadcast(Command::Prepare { payload: (proposal, decided) }, cmd_metas)
             }
             ProposerState::Follower => {
-                let bal = self.proposer.prepare();
-                self.broadcast(Command::Prepare { payload: (bal) }, cmd_metas);
+                if self.proposer.back_off() {
+                    let preemptions = self.proposer.preemptions();
+                    self.events.emit(|| NodeEvent::ElectionDeferred { preemptions });
+                    return;
+                }
+                if !self.yield_election() {
+                    self.start_election(cmd_metas);
+                }
             }
             ProposerState::Leader { proposal } => {
                 // TODO: do we want a special sync here? What about periodic bumping ballot?
-                self.broadcast(Command::Accept { payload: (proposal, vec![]) }, cmd_metas);
+                if !self.check_quorum() {
+                    return;
+                }
+                let accept = Command::Accept { payload: (proposal, vec![]) };
+                self.broadcast(accept, cmd_metas.clone());
+
+                // confirmations may have been lost along the way
+                let reads = self.reads.iter().map(|read| read.id).collect::<Vec<_>>();
+                for read in reads {
+                    let confirm = Command::Confirm { payload: (proposal, read) };
+                    self.broadcast(confirm, cmd_metas.clone());
+                }
+
+                // the preferred peer takes over once it has caught up
+                if let Some(peer) = self.preferred_peer() {
+                    let decided = self.window.open_range().start;
+                    self.events.emit(|| NodeEvent::HandoverOffered { to: peer });
+                    let handover = Command::Handover { payload: (proposal, decided) };
+                    self.send(peer, handover, cmd_metas).unwrap_or(());
+                }
             }
         }
     }
@@ -379,17 +1039,89 @@ impl<T: Transport> Replica for Node<T> {
         self.proposer.state().is_leader()
     }
 
-    fn decisions(&self) -> DecisionSet {
+    fn decisions(&self) -> DecisionSet<'_, V> {
         self.window.decisions()
     }
+
+    fn queued_proposals(&self) -> usize {
+        self.proposer.queued_proposals()
+    }
+
+    fn take_failed_proposals(&mut self) -> Vec<V> {
+        mem::take(&mut self.failed)
+    }
+
+    fn known_decided(&self) -> Slot {
+        self.catchup.target().max(self.window.open_range().start)
+    }
+
+    fn confirm_read(&mut self, cmd_metas: CommandMetas) -> Result<u64> {
+        let ballot = match *self.proposer.state() {
+            ProposerState::Leader { proposal } => proposal,
+            _ => return Err(PaxosError::NotLeader { leader: self.known_leader() }),
+        };
+
+        let id = self.next_read;
+        self.next_read += 1;
+        let mut acks = QuorumSet::with_size(self.config.quorum_size().1);
+        acks.insert(self.config.current());
+        // open slots that hold no value yet cannot hold a completed write
+        let index = self
+            .window
+            .open_slots()
+            .filter(|(_, acceptor)| acceptor.highest_value().is_some())
+            .map(|(slot, _)| slot + 1)
+            .last()
+            .unwrap_or(self.window.open_range().start);
+        if acks.has_quorum() {
+            self.confirmed_reads.push((id, Ok(index)));
+        } else {
+            self.reads.push(PendingRead { id, ballot, index, acks });
+            self.broadcast(Command::Confirm { payload: (ballot, id) }, cmd_metas);
+        }
+        Ok(id)
+    }
+
+    fn take_confirmed_reads(&mut self) -> Vec<(u64, Result<Slot>)> {
+        // reads of a ballot the node no longer leads with cannot be confirmed
+        let leading = self.proposer.state().proposal().filter(|_| self.is_leader());
+        let (reads, lost): (Vec<_>, Vec<_>) =
+            mem::take(&mut self.reads).into_iter().partition(|read| Some(read.ballot) == leading);
+        self.reads = reads;
+        let leader = self.known_leader();
+        let failed = lost.into_iter().map(|read| (read.id, Err(PaxosError::NotLeader { leader })));
+        self.confirmed_reads.extend(failed);
+        mem::take(&mut self.confirmed_reads)
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::single_range_in_vec_init)]
 mod tests {
     use super::*;
-    use crate::NodeMetadata;
+    use crate::{
+        catchup::CATCHUP_TIMEOUT, events::EventLog, metrics::Counters,
+        statemachine::ReplicatedState, status::AcceptorStatus, NodeMetadata, ProposalId, Proposed,
+    };
     use lazy_static::lazy_static;
-    use std::ops::Index;
+    use serde::{Deserialize, Serialize};
+    use std::{ops::Index, sync::Mutex};
+
+    /// Detector suspecting a fixed set of peers, recording arrivals
+    struct Suspects {
+        suspected: Vec<NodeId>,
+        arrivals: Arc<Mutex<Vec<NodeId>>>,
+    }
+
+    impl FailureDetector for Suspects {
+        fn arrived(&mut self, node: NodeId, _at: Instant) {
+            self.arrivals.lock().unwrap().push(node);
+        }
+
+        fn suspects(&self, node: NodeId, _at: Instant) -> bool {
+            self.suspected.contains(&node)
+        }
+    }
 
     lazy_static! {
         static ref CONFIG: Configuration = Configuration::new(
@@ -407,18 +1139,18 @@ mod tests {
     #[test]
     fn node_proposal() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
         // sent with no existing proposal, kickstarts phase 1
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
-        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4)) }], &replica.transport[0]);
-        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4)) }], &replica.transport[1]);
-        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4)) }], &replica.transport[2]);
-        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4)) }], &replica.transport[3]);
+        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4), 0) }], &replica.transport[0]);
+        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4), 0) }], &replica.transport[1]);
+        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4), 0) }], &replica.transport[2]);
+        assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4), 0) }], &replica.transport[3]);
         replica.transport.clear();
 
-        replica.proposal("456".into(), cmd_metas.clone());
+        replica.proposal("456".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
         assert!(replica.transport[0].is_empty());
         assert!(replica.transport[1].is_empty());
@@ -431,12 +1163,12 @@ mod tests {
     #[test]
     fn node_proposal_redirection() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
-        replica.prepare(Ballot(0, 3), cmd_metas.clone());
+        let cmd_metas = CommandMetas::default();
+        replica.prepare(Ballot(0, 3), 0, cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 3)), replica.proposer.highest_observed_ballot());
         replica.transport.clear();
 
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert!(replica.transport[0].is_empty());
         assert!(replica.transport[1].is_empty());
         assert!(replica.transport[2].is_empty());
@@ -448,9 +1180,9 @@ mod tests {
     #[test]
     fn node_prepare() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.prepare(Ballot(1, 0), cmd_metas.clone());
+        replica.prepare(Ballot(1, 0), 0, cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(1, 0)), replica.proposer.highest_observed_ballot());
         assert_eq!(
             &[Command::Promise { payload: (4, Ballot(1, 0), Vec::new()) }],
@@ -461,7 +1193,7 @@ mod tests {
         assert!(&replica.transport[3].is_empty());
         replica.transport.clear();
 
-        replica.prepare(Ballot(0, 2), cmd_metas.clone());
+        replica.prepare(Ballot(0, 2), 0, cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(1, 0)), replica.proposer.highest_observed_ballot());
         assert!(&replica.transport[0].is_empty());
         assert!(&replica.transport[1].is_empty());
@@ -472,22 +1204,42 @@ mod tests {
         assert!(&replica.transport[3].is_empty());
 
         assert!(replica.window.decisions().is_empty());
+
+        // decisions are reported from the first slot the candidate lacks
+        let decided = vec![(0, "a".into()), (1, "b".into())];
+        replica.resolution(Ballot(1, 0), decided, cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+        replica.prepare(Ballot(2, 0), 1, cmd_metas.clone()).unwrap();
+        let accepted = vec![(1, Ballot(1, 0), "b".into())];
+        assert_eq!(
+            &[Command::Promise { payload: (4, Ballot(2, 0), accepted) }],
+            &replica.transport[0]
+        );
+
+        // executed decisions are left to catchup rather than refusing the promise
+        replica.window.truncate_decisions();
+        replica.transport.clear();
+        replica.prepare(Ballot(3, 0), 0, cmd_metas.clone()).unwrap();
+        assert_eq!(
+            &[Command::Promise { payload: (4, Ballot(3, 0), Vec::new()) }],
+            &replica.transport[0]
+        );
     }
 
     #[test]
     fn node_promise_without_existing_accepted_value() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
         replica.transport.clear();
 
         // replica needs 2 more promises to achieve Phase 1 Quorum
-        replica.promise(0, Ballot(0, 4), Vec::new(), cmd_metas.clone());
+        replica.promise(0, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
         (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
 
-        replica.promise(2, Ballot(0, 4), Vec::new(), cmd_metas.clone());
+        replica.promise(2, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
 
         (0..4).for_each(|i| {
             assert_eq!(
@@ -502,17 +1254,19 @@ mod tests {
     #[test]
     fn node_promise_with_existing_accepted_value() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
         replica.transport.clear();
 
         // replica needs 2 more promises to achieve Phase 1 Quorum
-        replica.promise(1, Ballot(0, 4), vec![(0, Ballot(0, 0), "456".into())], cmd_metas.clone());
+        replica
+            .promise(1, Ballot(0, 4), vec![(0, Ballot(0, 0), "456".into())], cmd_metas.clone())
+            .unwrap();
         (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
 
-        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone());
+        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
 
         (0..4).for_each(|i| {
             assert_eq!(
@@ -529,17 +1283,19 @@ mod tests {
     #[test]
     fn node_promise_with_slot_holes() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
         replica.transport.clear();
 
         // replica needs 2 more promises to achieve Phase 1 Quorum
-        replica.promise(1, Ballot(0, 4), vec![(2, Ballot(0, 0), "456".into())], cmd_metas.clone());
+        replica
+            .promise(1, Ballot(0, 4), vec![(2, Ballot(0, 0), "456".into())], cmd_metas.clone())
+            .unwrap();
         (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
 
-        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone());
+        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
 
         (0..4).for_each(|i| {
             assert_eq!(
@@ -564,14 +1320,14 @@ mod tests {
     #[test]
     fn node_accept() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.prepare(Ballot(8, 2), cmd_metas.clone());
+        replica.prepare(Ballot(8, 2), 0, cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(8, 2)), replica.proposer.highest_observed_ballot());
         replica.transport.clear();
 
         // test rejection first for bal < proposer.highest_observed_ballot
-        replica.accept(Ballot(1, 1), vec![(0, "123".into())], cmd_metas.clone());
+        replica.accept(Ballot(1, 1), vec![(0, "123".into())], cmd_metas.clone()).unwrap();
         assert_eq!(
             &[Command::Reject { payload: (4, Ballot(1, 1), Ballot(8, 2)) }],
             &replica.transport[1]
@@ -580,7 +1336,7 @@ mod tests {
 
         // test replying with accepted message when bal =
         // proposer.highest_observed_ballot
-        replica.accept(Ballot(8, 2), vec![(0, "456".into())], cmd_metas.clone());
+        replica.accept(Ballot(8, 2), vec![(0, "456".into())], cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(8, 2)), replica.proposer.highest_observed_ballot());
         assert_eq!(
             &[Command::Accepted { payload: (4, Ballot(8, 2), vec![0]) }],
@@ -590,7 +1346,7 @@ mod tests {
 
         // test replying with accepted message when bal >
         // proposer.highest_observed_ballot
-        replica.accept(Ballot(9, 2), vec![(0, "789".into())], cmd_metas.clone());
+        replica.accept(Ballot(9, 2), vec![(0, "789".into())], cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(9, 2)), replica.proposer.highest_observed_ballot());
         assert_eq!(
             &[Command::Accepted { payload: (4, Ballot(9, 2), vec![0]) }],
@@ -605,7 +1361,7 @@ mod tests {
             Ballot(10, 2),
             vec![(1, "foo".into()), (2, "bar".into())],
             cmd_metas.clone(),
-        );
+        ).unwrap();
         assert_eq!(Some(Ballot(10, 2)), replica.proposer.highest_observed_ballot());
         assert_eq!(
             &[Command::Accepted { payload: (4, Ballot(10, 2), vec![1, 2]) }],
@@ -613,16 +1369,268 @@ mod tests {
         );
     }
 
+    #[test]
+    fn node_repropose_preempted() {
+        let log = EventLog::default();
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone())
+            .with_event_sink(Arc::new(log.clone()));
+        let cmd_metas = CommandMetas::default();
+
+        replica.proposal("foo".into(), cmd_metas.clone()).unwrap();
+        (0..=1).for_each(|n| replica.promise(n, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap());
+        assert!(replica.is_leader());
+        replica.transport.clear();
+
+        // a new leader fills the slot with another value
+        replica.accept(Ballot(1, 2), vec![(0, Bytes::new())], cmd_metas.clone()).unwrap();
+        assert_eq!(
+            &[
+                Command::Accepted { payload: (4, Ballot(1, 2), vec![0]) },
+                Command::Proposal { payload: "foo".into() },
+            ],
+            &replica.transport[2]
+        );
+        replica.transport.clear();
+
+        // values lost while electing the node again are placed in a new slot
+        replica.propose_leadership(cmd_metas.clone());
+        (0..=1).for_each(|n| replica.promise(n, Ballot(1, 4), vec![], cmd_metas.clone()).unwrap());
+        replica.proposal("bar".into(), cmd_metas.clone()).unwrap();
+        replica.prepare(Ballot(2, 3), 0, cmd_metas.clone()).unwrap();
+        replica.propose_leadership(cmd_metas.clone());
+        replica.transport.clear();
+        let accepted = vec![(1, Ballot(2, 3), "baz".into())];
+        replica.promise(0, Ballot(2, 4), accepted, cmd_metas.clone()).unwrap();
+        replica.promise(1, Ballot(2, 4), vec![], cmd_metas.clone()).unwrap();
+        assert!(replica.is_leader());
+        let values = vec![(0, Bytes::new()), (1, "baz".into()), (2, "bar".into())];
+        assert_eq!(&[Command::Accept { payload: (Ballot(2, 4), values) }], &replica.transport[0]);
+        (0..=1)
+            .for_each(|n| replica.accepted(n, Ballot(2, 4), vec![2], cmd_metas.clone()).unwrap());
+        replica.transport.clear();
+
+        // values decided to another value are proposed to the new leader
+        replica.proposal("qux".into(), cmd_metas.clone()).unwrap();
+        replica.resolution(Ballot(3, 1), vec![(3, "other".into())], cmd_metas.clone()).unwrap();
+        assert!(replica.transport[1].contains(&Command::Proposal { payload: "qux".into() }));
+
+        let events = log.events().into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert!(events.contains(&NodeEvent::ProposalPreempted { slot: 0 }));
+        assert!(events.contains(&NodeEvent::ProposalPreempted { slot: 1 }));
+        assert!(events.contains(&NodeEvent::ProposalDecided { slot: 2 }));
+        assert!(events.contains(&NodeEvent::ProposalPreempted { slot: 3 }));
+    }
+
+    #[test]
+    fn node_check_quorum() {
+        let log = EventLog::default();
+        let config = CONFIG.clone().with_election_interval(2);
+        let mut replica =
+            Node::new(VecTransport::default(), config).with_event_sink(Arc::new(log.clone()));
+        let cmd_metas = CommandMetas::default();
+
+        replica.proposal("foo".into(), cmd_metas.clone()).unwrap();
+        (0..=1).for_each(|n| replica.promise(n, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap());
+        (0..=1)
+            .for_each(|n| replica.accepted(n, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap());
+
+        // heard from a quorum within the interval
+        replica.propose_leadership(cmd_metas.clone());
+        replica.propose_leadership(cmd_metas.clone());
+        assert!(replica.is_leader());
+
+        // only a single peer accepts during the next interval
+        replica.proposal("bar".into(), cmd_metas.clone()).unwrap();
+        replica.accepted(0, Ballot(0, 4), vec![1], cmd_metas.clone()).unwrap();
+        replica.propose_leadership(cmd_metas.clone());
+        assert!(replica.is_leader());
+        replica.transport.clear();
+
+        replica.propose_leadership(cmd_metas.clone());
+        assert!(!replica.is_leader());
+        (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
+        assert_eq!(vec![Bytes::from("bar")], replica.take_failed_proposals());
+        assert!(replica.take_failed_proposals().is_empty());
+
+        let events = log.events().into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert_eq!(Some(&NodeEvent::QuorumLost { ballot: Ballot(0, 4), failed: 1 }), events.last());
+    }
+
+    #[test]
+    fn node_suspects_leader() {
+        let log = EventLog::default();
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let detector = Suspects { suspected: vec![2], arrivals: arrivals.clone() };
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone())
+            .with_failure_detector(detector)
+            .with_event_sink(Arc::new(log.clone()));
+        let cmd_metas = CommandMetas::default();
+
+        replica.prepare(Ballot(1, 2), 0, cmd_metas.clone()).unwrap();
+        assert_eq!(vec![2], *arrivals.lock().unwrap());
+        replica.transport.clear();
+
+        // the suspected leader is replaced rather than sent the proposal
+        replica.proposal("foo".into(), cmd_metas.clone()).unwrap();
+        (0..4).for_each(|i| {
+            assert_eq!(&[Command::Prepare { payload: (Ballot(1, 4), 0) }], &replica.transport[i])
+        });
+        let events = log.events().into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert!(events.contains(&NodeEvent::LeaderSuspected { leader: 2 }));
+        replica.transport.clear();
+
+        // proposals are forwarded to a live leader
+        replica.prepare(Ballot(2, 3), 0, cmd_metas.clone()).unwrap();
+        replica.proposal("bar".into(), cmd_metas.clone()).unwrap();
+        assert!(replica.transport[3].contains(&Command::Proposal { payload: "bar".into() }));
+    }
+
+    #[test]
+    fn node_yields_to_preferred_peer() {
+        let log = EventLog::default();
+        let detector = Suspects { suspected: vec![2], arrivals: Arc::default() };
+        let config = CONFIG
+            .clone()
+            .with_election_interval(2)
+            .with_priority(3, 10)
+            .with_zone(1, "us-east")
+            .with_zone(3, "eu-west")
+            .with_preferred_zone("us-east");
+        let mut replica = Node::new(VecTransport::default(), config)
+            .with_failure_detector(detector)
+            .with_event_sink(Arc::new(log.clone()));
+        let cmd_metas = CommandMetas::default();
+
+        replica.prepare(Ballot(1, 1), 0, cmd_metas.clone()).unwrap();
+        replica.prepare(Ballot(1, 3), 0, cmd_metas.clone()).unwrap();
+        replica.prepare(Ballot(2, 2), 0, cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+
+        // the suspected leader is not replaced while node 1 is healthy
+        replica.proposal("foo".into(), cmd_metas.clone()).unwrap();
+        assert_eq!(&[Command::Proposal { payload: "foo".into() }], &replica.transport[1]);
+        (0..4).filter(|i| *i != 1).for_each(|i| assert!(replica.transport[i].is_empty()));
+        let events = log.events().into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert!(events.contains(&NodeEvent::ElectionYielded { to: 1 }));
+
+        // unless the preferred peer does not run within the election interval
+        replica.propose_leadership(cmd_metas.clone());
+        replica.transport.clear();
+        replica.propose_leadership(cmd_metas.clone());
+        assert_eq!(&[Command::Prepare { payload: (Ballot(2, 4), 0) }], &replica.transport[1]);
+    }
+
+    #[test]
+    fn node_hands_over_to_preferred_peer() {
+        let log = EventLog::default();
+        let config = CONFIG.clone().with_priority(1, 1);
+        let mut replica =
+            Node::new(VecTransport::default(), config).with_event_sink(Arc::new(log.clone()));
+        let cmd_metas = CommandMetas::default();
+
+        replica.propose_leadership(cmd_metas.clone());
+        replica.promise(0, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        assert!(replica.is_leader());
+        replica.transport.clear();
+
+        // the preferred peer is offered leadership once heard from
+        replica.propose_leadership(cmd_metas.clone());
+        assert!(replica.transport[1].iter().all(|cmd| matches!(cmd, Command::Accept { .. })));
+        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+        replica.propose_leadership(cmd_metas.clone());
+        assert!(replica.transport[1].contains(&Command::Handover { payload: (Ballot(0, 4), 0) }));
+        let events = log.events().into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert!(events.contains(&NodeEvent::HandoverOffered { to: 1 }));
+        assert!(replica.transport[0].iter().all(|cmd| matches!(cmd, Command::Accept { .. })));
+    }
+
+    #[test]
+    fn node_handover() {
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
+        let cmd_metas = CommandMetas::default();
+        replica.prepare(Ballot(1, 2), 0, cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+
+        // the node catches up on the leader's decisions before taking over
+        replica.handover(Ballot(1, 2), 3, cmd_metas.clone()).unwrap();
+        assert_eq!(&[Command::Catchup { payload: (4, vec![0..3]) }], &replica.transport[2]);
+        assert!(replica.proposer.state().is_follower());
+        let decided = vec![(0, "a".into()), (1, "b".into())];
+        replica.resolution(Ballot(1, 2), decided, cmd_metas.clone()).unwrap();
+        replica.resolution(Ballot(1, 2), vec![(2, "c".into())], cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+
+        // stale offers are ignored
+        replica.handover(Ballot(0, 2), 3, cmd_metas.clone()).unwrap();
+        (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
+
+        replica.handover(Ballot(1, 2), 3, cmd_metas.clone()).unwrap();
+        (0..4).for_each(|i| {
+            assert_eq!(&[Command::Prepare { payload: (Ballot(1, 4), 3) }], &replica.transport[i])
+        });
+    }
+
+    #[test]
+    fn node_confirms_reads() {
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
+        let cmd_metas = CommandMetas::default();
+        let not_leader = PaxosError::NotLeader { leader: None };
+        assert_eq!(Err(not_leader), replica.confirm_read(cmd_metas.clone()));
+
+        replica.propose_leadership(cmd_metas.clone());
+        replica.promise(0, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.proposal("foo".into(), cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+
+        // the read reflects the no-op and the value proposed before it once
+        // a quorum confirms
+        let read = replica.confirm_read(cmd_metas.clone()).unwrap();
+        (0..4).for_each(|i| {
+            assert_eq!(&[Command::Confirm { payload: (Ballot(0, 4), read) }], &replica.transport[i])
+        });
+        replica.confirmed(0, Ballot(0, 4), read, cmd_metas.clone()).unwrap();
+        assert!(replica.take_confirmed_reads().is_empty());
+        replica.confirmed(1, Ballot(0, 4), read, cmd_metas.clone()).unwrap();
+        assert_eq!(vec![(read, Ok(2))], replica.take_confirmed_reads());
+        replica.confirmed(2, Ballot(0, 4), read, cmd_metas.clone()).unwrap();
+        assert!(replica.take_confirmed_reads().is_empty());
+
+        // reads fail once a higher ballot preempts the leader
+        let read = replica.confirm_read(cmd_metas.clone()).unwrap();
+        replica.reject(2, Ballot(0, 4), Ballot(5, 3), cmd_metas.clone()).unwrap();
+        let not_leader = PaxosError::NotLeader { leader: Some(3) };
+        assert_eq!(vec![(read, Err(not_leader))], replica.take_confirmed_reads());
+    }
+
+    #[test]
+    fn node_confirm() {
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
+        let cmd_metas = CommandMetas::default();
+        replica.prepare(Ballot(1, 2), 0, cmd_metas.clone()).unwrap();
+        replica.transport.clear();
+
+        replica.confirm(Ballot(1, 2), 7, cmd_metas.clone()).unwrap();
+        assert_eq!(&[Command::Confirmed { payload: (4, Ballot(1, 2), 7) }], &replica.transport[2]);
+
+        // a leader of a lower ballot is told of the promise
+        replica.confirm(Ballot(0, 3), 8, cmd_metas.clone()).unwrap();
+        let reject = Command::Reject { payload: (4, Ballot(0, 3), Ballot(1, 2)) };
+        assert_eq!(&[reject], &replica.transport[3]);
+    }
+
     #[test]
     fn node_reject() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
         replica.transport.clear();
 
-        replica.reject(2, Ballot(0, 4), Ballot(5, 3), cmd_metas.clone());
+        replica.reject(2, Ballot(0, 4), Ballot(5, 3), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(5, 3)), replica.proposer.highest_observed_ballot());
         assert!(replica.proposer.state().is_follower());
         assert_eq!(&[Command::Proposal { payload: ("123".into()) }], &replica.transport[3]);
@@ -634,20 +1642,20 @@ mod tests {
     #[test]
     fn node_accepted() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.proposal("123".into(), cmd_metas.clone());
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
         assert_eq!(Some(Ballot(0, 4)), replica.proposer.highest_observed_ballot());
-        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone());
-        replica.promise(0, Ballot(0, 4), vec![], cmd_metas.clone());
-        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone());
+        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.promise(0, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.promise(2, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
         replica.transport.clear();
 
         // wait for phase 2 quorum (accepted) before sending resolution
-        replica.accepted(0, Ballot(0, 4), vec![0], cmd_metas.clone());
+        replica.accepted(0, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
         (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
 
-        replica.accepted(2, Ballot(0, 4), vec![0], cmd_metas.clone());
+        replica.accepted(2, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
         (0..4).for_each(|i| {
             assert_eq!(
                 &[Command::Resolution { payload: (Ballot(0, 4), vec![(0, "123".into())]) }],
@@ -658,12 +1666,12 @@ mod tests {
         assert_eq!(vec![(0, "123".into())], replica.window.decisions().iter().collect::<Vec<_>>());
 
         // allow multiple accepted slots
-        replica.proposal("foo".into(), cmd_metas.clone());
-        replica.proposal("bar".into(), cmd_metas.clone());
+        replica.proposal("foo".into(), cmd_metas.clone()).unwrap();
+        replica.proposal("bar".into(), cmd_metas.clone()).unwrap();
         replica.transport.clear();
-        replica.accepted(0, Ballot(0, 4), vec![1, 2], cmd_metas.clone());
+        replica.accepted(0, Ballot(0, 4), vec![1, 2], cmd_metas.clone()).unwrap();
         (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
-        replica.accepted(1, Ballot(0, 4), vec![1, 2], cmd_metas.clone());
+        replica.accepted(1, Ballot(0, 4), vec![1, 2], cmd_metas.clone()).unwrap();
 
         (0..4).for_each(|i| {
             assert_eq!(
@@ -680,12 +1688,12 @@ mod tests {
         );
 
         // allow multiple accepts, but only when the slots receive quorum!
-        replica.proposal("foo2".into(), cmd_metas.clone());
-        replica.proposal("bar2".into(), cmd_metas.clone());
+        replica.proposal("foo2".into(), cmd_metas.clone()).unwrap();
+        replica.proposal("bar2".into(), cmd_metas.clone()).unwrap();
         replica.transport.clear();
-        replica.accepted(0, Ballot(0, 4), vec![3, 4], cmd_metas.clone());
+        replica.accepted(0, Ballot(0, 4), vec![3, 4], cmd_metas.clone()).unwrap();
         (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
-        replica.accepted(1, Ballot(0, 4), vec![3], cmd_metas.clone());
+        replica.accepted(1, Ballot(0, 4), vec![3], cmd_metas.clone()).unwrap();
 
         (0..4).for_each(|i| {
             assert_eq!(
@@ -703,35 +1711,35 @@ mod tests {
     #[test]
     fn node_resolution() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
-        replica.resolution(Ballot(1, 2), vec![(4, "123".into())], cmd_metas.clone());
+        replica.resolution(Ballot(1, 2), vec![(4, "123".into())], cmd_metas.clone()).unwrap();
         assert_eq!((0..5), replica.window.open_range());
-        assert!(match replica.window.slot_mut(4) {
-            SlotMutRef::Resolved(Ballot(1, 2), val) if val == "123" => true,
-            _ => false,
-        });
-        assert_eq!(&[Command::Catchup { payload: (4, vec![0, 1, 2, 3]) }], &replica.transport[2]);
+        assert!(matches!(
+            replica.window.slot_mut(4),
+            SlotMutRef::Resolved(Ballot(1, 2), val) if val == "123"
+        ));
+        assert_eq!(&[Command::Catchup { payload: (4, vec![0..4]) }], &replica.transport[2]);
         replica.transport.clear();
 
         replica.resolution(
             Ballot(1, 2),
             vec![(1, Bytes::default()), (0, "000".into())],
             cmd_metas.clone(),
-        );
+        ).unwrap();
         assert_eq!(
             vec![(0, "000".into()), (1, Bytes::default())],
             replica.window.decisions().iter().collect::<Vec<_>>()
         );
-        assert_eq!(&[Command::Catchup { payload: (4, vec![2, 3]) }], &replica.transport[2]);
-        replica.transport.clear();
+        // the request in flight is making progress
+        assert!(replica.transport[2].is_empty());
 
         // fill hole 1,2
         replica.resolution(
             Ballot(1, 2),
             vec![(2, Bytes::default()), (3, "3".into())],
             cmd_metas.clone(),
-        );
+        ).unwrap();
         assert!(replica.transport[2].is_empty());
 
         assert_eq!(
@@ -746,40 +1754,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn node_catchup_flow_control() {
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
+        let cmd_metas = CommandMetas::default();
+
+        // slots beyond the window are only requested once the window moves
+        let far = replica.window.capacity_range().end + 10;
+        replica.resolution(Ballot(1, 2), vec![(far, "far".into())], cmd_metas.clone()).unwrap();
+        assert_eq!(0..1, replica.window.open_range());
+        let first = Command::Catchup { payload: (4, vec![0..MAX_CATCHUP_SLOTS]) };
+        assert_eq!(vec![first.clone()], replica.transport[2]);
+        replica.transport.clear();
+
+        // a peer that does not answer is replaced by the next one
+        for _ in 1..CATCHUP_TIMEOUT {
+            replica.resolution(Ballot(1, 2), vec![(far, "far".into())], cmd_metas.clone()).unwrap();
+        }
+        (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
+        replica.resolution(Ballot(1, 2), vec![(far, "far".into())], cmd_metas.clone()).unwrap();
+        assert_eq!(&[first], &replica.transport[3]);
+        replica.transport.clear();
+
+        // the next slots are requested once the previous ones are decided
+        let values = (0..MAX_CATCHUP_SLOTS).map(|slot| (slot, Bytes::from("v"))).collect();
+        replica.resolution(Ballot(1, 2), values, cmd_metas.clone()).unwrap();
+        let next = MAX_CATCHUP_SLOTS..2 * MAX_CATCHUP_SLOTS;
+        assert_eq!(&[Command::Catchup { payload: (4, vec![next]) }], &replica.transport[2]);
+    }
+
     #[test]
     fn node_is_leader() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
         assert!(!replica.is_leader());
 
         let bal = replica.proposer.prepare();
         assert!(!replica.is_leader());
 
-        replica.promise(0, bal, vec![], cmd_metas.clone());
+        replica.promise(0, bal, vec![], cmd_metas.clone()).unwrap();
         assert!(!replica.is_leader());
 
-        replica.promise(1, bal, vec![], cmd_metas.clone());
+        replica.promise(1, bal, vec![], cmd_metas.clone()).unwrap();
         assert!(replica.is_leader());
     }
 
     #[test]
     fn node_propose_leadership_as_follower() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
         assert!(!replica.is_leader());
         replica.propose_leadership(cmd_metas);
 
         (0..4).for_each(|i| {
-            assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4)) }], &replica.transport[i])
+            assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4), 0) }], &replica.transport[i])
         });
     }
 
     #[test]
     fn node_propose_leadership_as_candidate() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
         assert!(!replica.is_leader());
         replica.propose_leadership(cmd_metas.clone());
@@ -787,19 +1824,19 @@ mod tests {
 
         replica.propose_leadership(cmd_metas.clone());
         (0..4).for_each(|i| {
-            assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4)) }], &replica.transport[i])
+            assert_eq!(&[Command::Prepare { payload: (Ballot(0, 4), 0) }], &replica.transport[i])
         });
     }
 
     #[test]
     fn node_propose_leadership_as_leader() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
         assert!(!replica.is_leader());
         replica.propose_leadership(cmd_metas.clone());
-        replica.promise(0, Ballot(0, 4), vec![], cmd_metas.clone());
-        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone());
+        replica.promise(0, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
+        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
         assert!(replica.is_leader());
         replica.transport.clear();
 
@@ -815,7 +1852,7 @@ mod tests {
     #[test]
     fn node_catchup() {
         let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
 
         // put in some slots
         // 0, 1, 2 are resolved
@@ -833,24 +1870,25 @@ mod tests {
             replica.window.next_slot().acceptor().receive_accept(Ballot(2, 1), "xyz".into());
         }
 
-        // replica that is not the leader cannot respond to catchup
-        replica.catchup(2, vec![0, 1, 2], cmd_metas.clone());
-        assert!(replica.transport[2].is_empty());
+        // replicas that are not the leader respond to catchup
+        replica.catchup(2, vec![0..3], cmd_metas.clone()).unwrap();
+        assert_eq!(2, replica.transport[2].len());
+        replica.transport.clear();
 
         // make the replica the leader
         assert!(!replica.is_leader());
         replica.propose_leadership(cmd_metas.clone());
-        (0..=1).for_each(|n| replica.promise(n, Ballot(0, 4), vec![], cmd_metas.clone()));
-        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone());
+        (0..=1).for_each(|n| replica.promise(n, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap());
+        replica.promise(1, Ballot(0, 4), vec![], cmd_metas.clone()).unwrap();
         assert!(replica.is_leader());
         replica.transport.clear();
 
         // request catch up for non-closed slots
-        replica.catchup(2, vec![3, 4, 5], cmd_metas.clone());
+        replica.catchup(2, vec![3..6], cmd_metas.clone()).unwrap();
         assert!(replica.transport[2].is_empty());
 
         // request catchup for open slots
-        replica.catchup(2, vec![0, 1, 2, 3], cmd_metas.clone());
+        replica.catchup(2, vec![0..4], cmd_metas.clone()).unwrap();
         assert_eq!(
             &[
                 Command::Resolution {
@@ -862,7 +1900,7 @@ mod tests {
         );
 
         // resolutions must come in order
-        replica.catchup(0, vec![2, 0, 1, 3], cmd_metas.clone());
+        replica.catchup(0, vec![2..3, 0..2, 3..4], cmd_metas.clone()).unwrap();
         assert_eq!(
             &[
                 Command::Resolution {
@@ -874,7 +1912,7 @@ mod tests {
         );
 
         // resolutions can contain holes
-        replica.catchup(3, vec![1, 2], cmd_metas.clone());
+        replica.catchup(3, vec![1..3], cmd_metas.clone()).unwrap();
         assert_eq!(
             &[
                 Command::Resolution { payload: (Ballot(0, 1), vec![(1, "456".into())]) },
@@ -882,6 +1920,358 @@ mod tests {
             ],
             &replica.transport[3]
         );
+        replica.transport.clear();
+
+        // long runs are sent in chunks
+        for slot in 4..300 {
+            replica.window.next_slot().acceptor().resolve(Ballot(2, 1), slot.to_string().into());
+        }
+        replica.catchup(1, vec![4..300], cmd_metas.clone()).unwrap();
+        let chunks = replica.transport[1]
+            .iter()
+            .map(|cmd| match cmd {
+                Command::Resolution { payload: (Ballot(2, 1), vals) } => vals.len(),
+                _ => panic!("unexpected command {:?}", cmd),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![CATCHUP_CHUNK, CATCHUP_CHUNK, 40], chunks);
+    }
+
+    #[test]
+    fn node_propagates_trace() {
+        let mut replica = Node::new(TracingTransport::default(), CONFIG.clone());
+        let untraced = CommandMetas::default();
+
+        // queued while the election is running
+        replica.proposal("123".into(), CommandMetas::trace(7, 1)).unwrap();
+        replica.proposal("456".into(), untraced.clone()).unwrap();
+        let (_, prepare, metas) = replica.transport.0.remove(0);
+        assert!(matches!(prepare, Command::Prepare { .. }));
+        assert_eq!((7, 1, Some(4)), (metas.trace_id, metas.parent_span_id, metas.origin));
+        replica.transport.0.clear();
+
+        // the traced proposal is sent on its own
+        replica.promise(0, Ballot(0, 4), Vec::new(), untraced.clone()).unwrap();
+        replica.promise(2, Ballot(0, 4), Vec::new(), untraced.clone()).unwrap();
+        let accepts = replica.transport.sent_to(0);
+        assert_eq!(2, accepts.len());
+        let (accept, accept_metas) = &accepts[0];
+        assert_eq!(&Command::Accept { payload: (Ballot(0, 4), vec![(0, "123".into())]) }, accept);
+        assert_eq!((7, 1), (accept_metas.trace_id, accept_metas.parent_span_id));
+        assert_ne!(0, accept_metas.span_id);
+        let batch = Command::Accept { payload: (Ballot(0, 4), vec![(1, "456".into())]) };
+        assert_eq!(batch, accepts[1].0);
+        assert!(!accepts[1].1.is_traced());
+        replica.transport.0.clear();
+
+        // ACCEPTED messages continue the trace of the ACCEPT
+        let accepted_metas = accept_metas.child(0, 99);
+        replica.accepted(0, Ballot(0, 4), vec![0], accepted_metas.clone()).unwrap();
+        replica.accepted(1, Ballot(0, 4), vec![0], accepted_metas).unwrap();
+        let (resolution, metas) = replica.transport.sent_to(2).remove(0);
+        assert!(matches!(resolution, Command::Resolution { .. }));
+        assert_eq!((7, 99), (metas.trace_id, metas.parent_span_id));
+    }
+
+    #[test]
+    fn node_metrics() {
+        let metrics = Arc::new(Counters::default());
+        let mut replica =
+            Node::new(VecTransport::default(), CONFIG.clone()).with_metrics(metrics.clone());
+        let cmd_metas = CommandMetas::default();
+
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
+        replica.promise(0, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
+        replica.promise(2, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
+        replica.accepted(0, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
+        replica.accepted(1, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(1, snapshot.elections_started);
+        assert_eq!(1, snapshot.elections_won);
+        assert_eq!(4, snapshot.sent[&CommandKind::Prepare]);
+        assert_eq!(4, snapshot.sent[&CommandKind::Accept]);
+        assert_eq!(4, snapshot.sent[&CommandKind::Resolution]);
+        assert_eq!(1, snapshot.received[&CommandKind::Proposal]);
+        assert_eq!(2, snapshot.received[&CommandKind::Promise]);
+        assert_eq!(2, snapshot.received[&CommandKind::Accepted]);
+        assert_eq!(1, snapshot.accepts_sent);
+        assert_eq!(1, snapshot.slots_resolved);
+        assert_eq!(1, snapshot.slots_decided);
+        assert_eq!(1, snapshot.accept_latency.count);
+
+        // preempted by a higher ballot
+        replica.prepare(Ballot(1, 0), 0, cmd_metas.clone()).unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!(1, snapshot.leadership_lost);
+        assert_eq!(1, snapshot.ballot_increases);
+
+        let cmd = Command::Prepare { payload: (Ballot(2, 9), 0) };
+        assert!(replica.receive(cmd, cmd_metas).is_err());
+        assert_eq!(1, metrics.snapshot().refused);
+    }
+
+    #[test]
+    fn node_events() {
+        let log = EventLog::default();
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone())
+            .with_event_sink(Arc::new(log.clone()));
+        let cmd_metas = CommandMetas::default();
+
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
+        replica.promise(0, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
+        replica.promise(2, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
+        replica.accepted(0, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
+        replica.accepted(1, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
+        replica.accept(Ballot(1, 0), vec![(1, "456".into())], cmd_metas.clone()).unwrap();
+        replica.accept(Ballot(0, 2), vec![(2, "789".into())], cmd_metas.clone()).unwrap();
+
+        let events = log.events().into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                NodeEvent::ProposalQueued,
+                NodeEvent::ElectionStarted { ballot: Ballot(0, 4) },
+                NodeEvent::BecameLeader { ballot: Ballot(0, 4) },
+                NodeEvent::AcceptSent { ballot: Ballot(0, 4), slots: vec![0] },
+                NodeEvent::Resolved { ballot: Ballot(0, 4), slots: vec![0] },
+                NodeEvent::ProposalDecided { slot: 0 },
+                NodeEvent::SteppedDown { ballot: Ballot(0, 4), preempted_by: Ballot(1, 0) },
+                NodeEvent::Accepted { from: 0, ballot: Ballot(1, 0), slots: vec![1] },
+                NodeEvent::AcceptRejected {
+                    from: 2,
+                    proposed: Ballot(0, 2),
+                    promised: Ballot(1, 0)
+                },
+            ],
+            events
+        );
+        assert!(log.timeline().lines().all(|line| line.contains("node 4: ")));
+    }
+
+    #[test]
+    fn node_status() {
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
+        let cmd_metas = CommandMetas::default();
+
+        let status = replica.status();
+        assert_eq!(ProposerStatus::Follower, status.proposer);
+        assert_eq!(None, status.leader);
+        assert_eq!(None, status.decided);
+        assert_eq!(0..1, status.open_range);
+        assert_eq!(vec![0, 1, 2, 3], status.peers.iter().map(|p| p.node).collect::<Vec<_>>());
+        assert!(status.peers.iter().all(|p| p.last_heard.is_none()));
+
+        replica.proposal("123".into(), cmd_metas.clone()).unwrap();
+        replica.proposal("456".into(), cmd_metas.clone()).unwrap();
+        replica.promise(0, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
+        let status = replica.status();
+        assert_eq!(
+            ProposerStatus::Candidate { ballot: Ballot(0, 4), promises: 2 },
+            status.proposer
+        );
+        assert_eq!(Some(4), status.leader);
+        assert_eq!(2, status.queued_proposals);
+        assert_eq!(3, status.received);
+        assert_eq!(Some(3), status.peers[0].last_heard);
+        assert_eq!(Some(CommandKind::Promise), status.peers[0].last_command);
+
+        replica.promise(2, Ballot(0, 4), Vec::new(), cmd_metas.clone()).unwrap();
+        replica.accepted(0, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
+        replica.accepted(1, Ballot(0, 4), vec![0], cmd_metas.clone()).unwrap();
+        let status = replica.status();
+        assert_eq!(ProposerStatus::Leader { ballot: Ballot(0, 4) }, status.proposer);
+        assert_eq!(0, status.queued_proposals);
+        assert_eq!(Some(0), status.decided);
+        assert_eq!(1..2, status.open_range);
+        assert_eq!(
+            vec![SlotStatus {
+                slot: 1,
+                acceptor: AcceptorStatus::AwaitQuorum {
+                    promised: Ballot(0, 4),
+                    accepted: Ballot(0, 4),
+                    acknowledged: 1,
+                    quorum: 3,
+                },
+            }],
+            status.slots
+        );
+        assert_eq!(Some(6), status.peers[1].last_heard);
+        assert_eq!(None, status.peers[3].last_heard);
+    }
+
+    #[test]
+    fn node_refuses_invalid_commands() {
+        let mut replica = Node::new(VecTransport::default(), CONFIG.clone());
+        let cmd_metas = CommandMetas::default();
+
+        // unknown nodes, whether named directly or through a ballot
+        assert_eq!(
+            Err(PaxosError::UnknownNode(9)),
+            replica.prepare(Ballot(0, 9), 0, cmd_metas.clone())
+        );
+        assert_eq!(
+            Err(PaxosError::UnknownNode(9)),
+            replica.catchup(9, vec![0..1], cmd_metas.clone())
+        );
+        assert_eq!(None, replica.proposer.highest_observed_ballot());
+        assert_eq!(0, replica.status().received);
+
+        // slots beyond the window are not reserved
+        let window = replica.window.capacity_range();
+        let values = vec![(0, "0".into()), (window.end, "1".into())];
+        assert_eq!(
+            Err(PaxosError::SlotOutOfWindow { slot: window.end, window: window.clone() }),
+            replica.accept(Ballot(0, 1), values, cmd_metas.clone())
+        );
+        assert_eq!(0..1, replica.window.open_range());
+        (0..4).for_each(|i| assert!(replica.transport[i].is_empty()));
+
+        // responses to a ballot of another node
+        assert!(matches!(
+            replica.promise(1, Ballot(0, 2), vec![], cmd_metas.clone()),
+            Err(PaxosError::ProtocolViolation { from: 1, .. })
+        ));
+        assert!(matches!(
+            replica.reject(1, Ballot(0, 4), Ballot(0, 4), cmd_metas.clone()),
+            Err(PaxosError::ProtocolViolation { from: 1, .. })
+        ));
+
+        // a decided slot cannot be resolved to another value
+        replica.resolution(Ballot(0, 1), vec![(0, "0".into())], cmd_metas.clone()).unwrap();
+        assert!(matches!(
+            replica.resolution(Ballot(1, 2), vec![(0, "1".into())], cmd_metas.clone()),
+            Err(PaxosError::ProtocolViolation { from: 2, .. })
+        ));
+        replica.resolution(Ballot(1, 2), vec![(0, "0".into())], cmd_metas).unwrap();
+        assert_eq!(vec![(0, "0".into())], replica.decisions().iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn node_refuses_other_clusters() {
+        let config = CONFIG.clone().with_cluster(7, 2);
+        let mut replica = Node::new(TracingTransport::default(), config);
+        let prepare = Command::Prepare { payload: (Ballot(0, 1), 0) };
+        let mut cmd_metas = CommandMetas::default();
+
+        assert_eq!(
+            Err(PaxosError::WrongCluster { cluster: 0 }),
+            replica.receive(prepare.clone(), cmd_metas.clone())
+        );
+        cmd_metas.cluster = 7;
+        cmd_metas.epoch = 1;
+        assert_eq!(
+            Err(PaxosError::WrongEpoch { epoch: 1 }),
+            replica.receive(prepare.clone(), cmd_metas.clone())
+        );
+        assert!(replica.transport.0.is_empty());
+
+        // commands sent by the node carry its identity, proposals from
+        // clients carry none
+        cmd_metas.epoch = 2;
+        replica.receive(prepare, cmd_metas).unwrap();
+        let (_, _, promise_metas) = replica.transport.0.remove(0);
+        assert_eq!((7, 2), (promise_metas.cluster, promise_metas.epoch));
+        replica.receive(Command::Proposal { payload: "foo".into() }, Default::default()).unwrap();
+    }
+
+    #[test]
+    fn node_custom_value() {
+        let transport = OpTransport::default();
+        let executed = OpStateMachine::default();
+        let mut replica =
+            Node::new(transport.clone(), CONFIG.clone()).state_machine(executed.clone());
+        let cmd_metas = CommandMetas::default();
+
+        let mut response = replica.propose(Op::Add(7), cmd_metas.clone()).unwrap();
+        // node 1 accepted an equal value proposed by another node
+        let other = Proposed { id: ProposalId { node: 1, seq: 1 }, value: Op::Add(7) };
+        replica
+            .receive(
+                Command::Promise { payload: (1, Ballot(0, 4), vec![(1, Ballot(0, 0), other)]) },
+                cmd_metas.clone(),
+            )
+            .unwrap();
+        replica
+            .receive(Command::Promise { payload: (2, Ballot(0, 4), vec![]) }, cmd_metas.clone())
+            .unwrap();
+        assert!(response.try_take().is_none());
+
+        // the hole in slot 0 is filled with a no-op
+        let accepts = transport
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|(node, cmd)| match cmd {
+                Command::Accept { payload: (_, accepts) } if *node == 0 => Some(accepts.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let values = accepts.iter().map(|(slot, p)| (*slot, p.value.clone())).collect::<Vec<_>>();
+        assert_eq!(vec![(0, Op::Noop), (1, Op::Add(7)), (2, Op::Add(7))], values);
+
+        replica
+            .receive(
+                Command::Accepted { payload: (0, Ballot(0, 4), vec![0, 1, 2]) },
+                cmd_metas.clone(),
+            )
+            .unwrap();
+        replica
+            .receive(Command::Accepted { payload: (2, Ballot(0, 4), vec![0, 1, 2]) }, cmd_metas)
+            .unwrap();
+
+        assert_eq!(accepts, replica.decisions().iter().collect::<Vec<_>>());
+        assert_eq!(vec![(1, 7), (2, 7)], *executed.0.lock().unwrap());
+        // the proposer learns the sum after its own value was added
+        assert_eq!(Some(Ok((2, 14))), response.try_take());
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    enum Op {
+        Noop,
+        Add(u64),
+    }
+
+    impl Value for Op {
+        fn noop() -> Op {
+            Op::Noop
+        }
+
+        fn is_noop(&self) -> bool {
+            matches!(self, Op::Noop)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct OpStateMachine(Arc<Mutex<Vec<(Slot, u64)>>>);
+
+    impl ReplicatedState<Op> for OpStateMachine {
+        type Response = u64;
+
+        fn execute(&mut self, slot: Slot, op: Op) -> u64 {
+            let mut executed = self.0.lock().unwrap();
+            match op {
+                Op::Noop => panic!("no-op values are not executed"),
+                Op::Add(n) => executed.push((slot, n)),
+            }
+            executed.iter().map(|(_, n)| n).sum()
+        }
+    }
+
+    #[derive(Clone, Default)]
+    #[allow(clippy::type_complexity)]
+    struct OpTransport(Arc<Mutex<Vec<(NodeId, Command<Proposed<Op>>)>>>);
+
+    impl Transport<Proposed<Op>> for OpTransport {
+        fn send(
+            &mut self,
+            node: NodeId,
+            _: &NodeMetadata,
+            cmd: Command<Proposed<Op>>,
+            _: CommandMetas,
+        ) {
+            self.0.lock().unwrap().push((node, cmd));
+        }
     }
 
     #[derive(Default)]
@@ -903,6 +2293,26 @@ mod tests {
         }
     }
 
+    /// Transport recording the metadata of every command
+    #[derive(Default)]
+    struct TracingTransport(Vec<(NodeId, Command, CommandMetas)>);
+
+    impl TracingTransport {
+        fn sent_to(&self, node: NodeId) -> Vec<(Command, CommandMetas)> {
+            self.0
+                .iter()
+                .filter(|(to, ..)| *to == node)
+                .map(|(_, cmd, cmd_metas)| (cmd.clone(), cmd_metas.clone()))
+                .collect()
+        }
+    }
+
+    impl Transport for TracingTransport {
+        fn send(&mut self, node: NodeId, _: &NodeMetadata, cmd: Command, cmd_metas: CommandMetas) {
+            self.0.push((node, cmd, cmd_metas));
+        }
+    }
+
     impl Transport for VecTransport {
         fn send(&mut self, node: NodeId, _: &NodeMetadata, cmd: Command, _cmd_metas: CommandMetas) {
             assert!(node < 4);
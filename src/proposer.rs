@@ -1,6 +1,19 @@
-use crate::{config::QuorumSet, Ballot, NodeId};
+use crate::{
+    commands::CommandMetas,
+    config::QuorumSet,
+    metrics::{Metrics, NoMetrics},
+    rng::Rng,
+    Ballot, NodeId,
+};
 use bytes::Bytes;
-use std::{cmp::max, mem};
+use std::{
+    cmp::{max, min},
+    mem,
+    sync::Arc,
+};
+
+/// Exponent of the largest backoff, in elections skipped
+const MAX_BACKOFF_EXPONENT: u32 = 6;
 
 /// The proposer is a role within paxos that acts as a coordinator for the
 /// instance in that it attempts to elect itself the proposer (leader) for the
@@ -8,7 +21,7 @@ use std::{cmp::max, mem};
 /// in which is will potentially send an ACCEPT message with a value from the
 /// acceptor with the highest accepted value already seen (key to the Paxos
 /// algorithm)
-pub struct Proposer {
+pub struct Proposer<V = Bytes> {
     /// State of the proposer state machine
     state: ProposerState,
     /// Highest seen ballot thus far from any peer
@@ -19,23 +32,48 @@ pub struct Proposer {
     quorum: usize,
 
     // TODO: bound the proposal queue
-    /// Queue of proposals while elections are happening
-    proposal_queue: Vec<Bytes>,
+    /// Queue of proposals while elections are happening, along with the
+    /// metadata of the command that proposed them
+    proposal_queue: Vec<(V, CommandMetas)>,
+    /// Receiver of election events
+    metrics: Arc<dyn Metrics>,
+
+    /// Consecutive elections preempted by a higher ballot
+    preemptions: u32,
+    /// Elections left to skip before running for leadership again
+    backoff: u32,
+    /// Randomizes the backoff so that dueling proposers back off for
+    /// different lengths
+    rng: Rng,
 }
 
-impl Proposer {
+impl<V> Proposer<V> {
     /// Creates new proposer state with the node identifier and the Phase 1
     /// quorum size
-    pub fn new(node: NodeId, quorum: usize) -> Proposer {
+    pub fn new(node: NodeId, quorum: usize) -> Proposer<V> {
         Proposer {
             state: ProposerState::Follower,
             highest: None,
             current: node,
             quorum,
             proposal_queue: Vec::new(),
+            metrics: Arc::new(NoMetrics),
+            preemptions: 0,
+            backoff: 0,
+            rng: Rng::from_clock(node),
         }
     }
 
+    /// Reports election events to the metrics
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Seeds the randomized backoff
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
     /// Returns the proposer's state as either `Follower`, `Candidate` or
     /// `Leader`
     pub fn state(&self) -> &ProposerState {
@@ -44,21 +82,62 @@ impl Proposer {
 
     /// Overrides the highest seen value, if ballot is the highest seen
     pub fn observe_ballot(&mut self, ballot: Ballot) {
+        if Some(ballot) > self.highest {
+            self.metrics.ballot_observed(ballot);
+        }
         self.highest = max(Some(ballot), self.highest);
 
         let ballot_leader = self.highest.unwrap().1 == self.current;
 
-        let lost_leadership = match self.state {
-            ProposerState::Candidate { .. } | ProposerState::Leader { .. } if !ballot_leader => {
-                true
-            }
-            _ => false,
-        };
+        let lost_leadership = matches!(
+            self.state,
+            ProposerState::Candidate { .. } | ProposerState::Leader { .. } if !ballot_leader
+        );
         if lost_leadership {
+            if self.state.is_candidate() {
+                self.preempted(ballot);
+            }
+            self.metrics.leadership_lost(ballot);
             self.state = ProposerState::Follower;
         }
     }
 
+    /// Backs off for a random number of elections, doubling the range with
+    /// each consecutive preempted election
+    fn preempted(&mut self, ballot: Ballot) {
+        self.preemptions += 1;
+        self.metrics.election_preempted(ballot, self.preemptions);
+        let range = 1u64 << min(self.preemptions, MAX_BACKOFF_EXPONENT);
+        self.backoff = self.rng.range(1..range + 1) as u32;
+        debug!("Backing off {} elections after {} preemptions", self.backoff, self.preemptions);
+    }
+
+    /// Skips an election while backing off after preempted elections
+    pub fn back_off(&mut self) -> bool {
+        if self.backoff == 0 {
+            return false;
+        }
+        self.backoff -= 1;
+        true
+    }
+
+    /// Consecutive elections preempted by a higher ballot
+    pub fn preemptions(&self) -> u32 {
+        self.preemptions
+    }
+
+    /// Gives up leadership, returning the ballot of the leader
+    pub fn step_down(&mut self) -> Option<Ballot> {
+        match self.state {
+            ProposerState::Leader { proposal } => {
+                self.metrics.leadership_lost(proposal);
+                self.state = ProposerState::Follower;
+                Some(proposal)
+            }
+            _ => None,
+        }
+    }
+
     /// Highest ballot that the proposer has seen
     pub fn highest_observed_ballot(&self) -> Option<Ballot> {
         self.highest
@@ -80,6 +159,7 @@ impl Proposer {
         self.state = ProposerState::Candidate { proposal: new_ballot, promises };
 
         debug!("Starting prepare with {:?}", new_ballot);
+        self.metrics.election_started(new_ballot);
 
         new_ballot
     }
@@ -99,6 +179,7 @@ impl Proposer {
             return;
         }
 
+        self.metrics.reject_received(proposed, promised);
         self.observe_ballot(promised);
     }
 
@@ -127,6 +208,9 @@ impl Proposer {
         };
 
         debug!("Quorum reached for Phase 1 of {:?}", proposed);
+        self.metrics.election_won(proposed);
+        self.preemptions = 0;
+        self.backoff = 0;
 
         // proposer has quorum from acceptors, upgrade to Leader and start
         // Phase 2 if we already have a value
@@ -134,13 +218,18 @@ impl Proposer {
     }
 
     /// Adds a proposal to the queue
-    pub fn push_proposal(&mut self, val: Bytes) {
-        self.proposal_queue.push(val);
+    pub fn push_proposal(&mut self, val: V, cmd_metas: CommandMetas) {
+        self.proposal_queue.push((val, cmd_metas));
     }
 
     /// Drains the proposal queue
-    pub fn take_proposals(&mut self) -> Vec<Bytes> {
-        mem::replace(&mut self.proposal_queue, Vec::new())
+    pub fn take_proposals(&mut self) -> Vec<(V, CommandMetas)> {
+        mem::take(&mut self.proposal_queue)
+    }
+
+    /// Number of queued proposals
+    pub fn queued_proposals(&self) -> usize {
+        self.proposal_queue.len()
     }
 
     /// Indicator of empty proposal queue
@@ -173,17 +262,27 @@ pub enum ProposerState {
 impl ProposerState {
     /// Proposer is the distinguished proposer
     pub fn is_leader(&self) -> bool {
-        if let ProposerState::Leader { .. } = *self { true } else { false }
+        matches!(*self, ProposerState::Leader { .. })
     }
 
     /// Proposer is a candidate for leader
     pub fn is_candidate(&self) -> bool {
-        if let ProposerState::Candidate { .. } = *self { true } else { false }
+        matches!(*self, ProposerState::Candidate { .. })
     }
 
     /// Prooser is a follower of another replica or uninitiated
     pub fn is_follower(&self) -> bool {
-        if let ProposerState::Follower { .. } = *self { true } else { false }
+        matches!(*self, ProposerState::Follower)
+    }
+
+    /// Ballot proposed by a candidate or leader
+    pub fn proposal(&self) -> Option<Ballot> {
+        match *self {
+            ProposerState::Candidate { proposal, .. } | ProposerState::Leader { proposal } => {
+                Some(proposal)
+            }
+            ProposerState::Follower => None,
+        }
     }
 }
 
@@ -193,85 +292,102 @@ mod tests {
 
     #[test]
     fn proposer_prepare() {
-        let mut proposer = Proposer::new(1, 2);
+        let mut proposer: Proposer = Proposer::new(1, 2);
         assert!(!proposer.state().is_leader());
         proposer.observe_ballot(Ballot(100, 1));
 
-        assert!(match proposer.prepare() {
-            Ballot(101, 1) => true,
-            _ => false,
-        });
+        assert!(matches!(proposer.prepare(), Ballot(101, 1)));
 
-        assert!(match proposer.state {
-            ProposerState::Candidate { proposal: Ballot(101, 1), .. } => true,
-            _ => false,
-        });
+        assert!(matches!(
+            proposer.state,
+            ProposerState::Candidate { proposal: Ballot(101, 1), .. }
+        ));
 
         assert!(!proposer.state().is_leader());
     }
 
     #[test]
     fn proposer_receive_promise() {
-        let mut proposer = Proposer::new(1, 2);
+        let mut proposer: Proposer = Proposer::new(1, 2);
         proposer.observe_ballot(Ballot(100, 1));
 
         proposer.prepare();
         assert!(!proposer.state().is_leader());
         assert_eq!(Some(Ballot(101, 1)), proposer.highest_observed_ballot());
 
-        assert!(match proposer.state {
+        assert!(matches!(
+            proposer.state,
             ProposerState::Candidate { proposal: Ballot(101, 1), ref promises, .. }
-                if promises.contains(1) =>
-                true,
-            _ => false,
-        });
+                if promises.contains(1)
+        ));
 
         proposer.receive_promise(2, Ballot(101, 1));
         assert!(proposer.state().is_leader());
         assert_eq!(Some(Ballot(101, 1)), proposer.highest_observed_ballot());
-        assert!(match proposer.state {
-            ProposerState::Leader { proposal: Ballot(101, 1) } => true,
-            _ => false,
-        });
+        assert!(matches!(proposer.state, ProposerState::Leader { proposal: Ballot(101, 1) }));
     }
 
     #[test]
     fn proposer_receive_reject() {
         // start a producer that receives rejections during Phase 1
-        let mut proposer = Proposer::new(1, 2);
+        let mut proposer: Proposer = Proposer::new(1, 2);
         // fake observing high ballot
         proposer.observe_ballot(Ballot(100, 1));
 
         proposer.prepare();
-        assert!(match proposer.state {
-            ProposerState::Candidate { proposal: Ballot(101, 1), .. } => true,
-            _ => false,
-        });
+        assert!(matches!(
+            proposer.state,
+            ProposerState::Candidate { proposal: Ballot(101, 1), .. }
+        ));
 
         // receive reject for the wrong ballot
         proposer.receive_reject(3, Ballot(5, 1), Ballot(6, 2));
         assert!(!proposer.state().is_leader());
         assert_eq!(Some(Ballot(101, 1)), proposer.highest_observed_ballot());
-        assert!(match proposer.state {
-            ProposerState::Candidate { proposal: Ballot(101, 1), .. } => true,
-            _ => false,
-        });
+        assert!(matches!(
+            proposer.state,
+            ProposerState::Candidate { proposal: Ballot(101, 1), .. }
+        ));
 
         // receive reject for incorrect ballots
         proposer.receive_reject(3, Ballot(101, 1), Ballot(100, 0));
         assert!(!proposer.state().is_leader());
         assert_eq!(Some(Ballot(101, 1)), proposer.highest_observed_ballot());
-        assert!(match proposer.state {
-            ProposerState::Candidate { proposal: Ballot(101, 1), .. } => true,
-            _ => false,
-        });
+        assert!(matches!(
+            proposer.state,
+            ProposerState::Candidate { proposal: Ballot(101, 1), .. }
+        ));
 
         proposer.receive_reject(3, Ballot(101, 1), Ballot(102, 2));
         assert!(!proposer.state().is_leader());
         assert_eq!(Some(Ballot(102, 2)), proposer.highest_observed_ballot());
-        assert!(match proposer.state {
-            ProposerState::Follower => true,
-            _ => false,
-        });
+        assert!(matches!(proposer.state, ProposerState::Follower));
+    }
+
+    #[test]
+    fn proposer_backs_off() {
+        let mut proposer: Proposer = Proposer::new(1, 2);
+        assert!(!proposer.back_off());
+
+        // each consecutive preemption doubles the range of the backoff
+        for preemptions in 1..=3 {
+            let bal = proposer.prepare();
+            proposer.observe_ballot(Ballot(bal.0 + 1, 2));
+            assert_eq!(preemptions, proposer.preemptions());
+
+            let mut skipped = 0;
+            while proposer.back_off() {
+                skipped += 1;
+            }
+            assert!(skipped >= 1 && skipped <= 1 << preemptions, "skipped {}", skipped);
+        }
+
+        // a preempted leader is not backing off from an election
+        let bal = proposer.prepare();
+        proposer.receive_promise(2, bal);
+        assert_eq!(0, proposer.preemptions());
+        proposer.observe_ballot(Ballot(bal.0 + 1, 2));
+        assert_eq!(0, proposer.preemptions());
+        assert!(!proposer.back_off());
     }
 }
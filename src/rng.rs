@@ -0,0 +1,48 @@
+//! Small random number generator (SplitMix64).
+//!
+//! Replicas seed their generator from the clock, while simulations seed
+//! it explicitly so that runs are reproducible regardless of the version of
+//! any external random number crate.
+use crate::NodeId;
+use std::{
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Debug)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    /// Seeds the generator with the node and the current time, so that
+    /// nodes and restarts draw different values
+    pub(crate) fn from_clock(node: NodeId) -> Rng {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        Rng((nanos as u64) ^ (u64::from(node) << 32))
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed value within the range
+    pub(crate) fn range(&mut self, range: Range<u64>) -> u64 {
+        assert!(range.start < range.end);
+        range.start + self.next_u64() % (range.end - range.start)
+    }
+
+    /// Returns true with the given probability
+    pub(crate) fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
@@ -0,0 +1,412 @@
+//! Simulated network for running many replicas within a single process.
+//!
+//! The `Network` owns the replicas and routes the commands they send
+//! through `SimTransport`. Delivery happens in virtual time: each message is
+//! assigned a delivery time drawn from the configured delay and may be
+//! dropped, duplicated or held back so that it is reordered with later
+//! messages. Peers can be partitioned from each other at any point.
+//!
+//! All randomness comes from a seeded generator, so a run with the same seed
+//! and the same sequence of calls delivers the same messages in the same
+//! order. Replicas draw their election backoff from the clock, unless their
+//! `Configuration` is seeded with `Configuration::with_seed`.
+pub mod harness;
+#[cfg(test)]
+mod model;
+
+use crate::{
+    commands::{Command, CommandMetas, Receiver, Transport},
+    error::{PaxosError, Result},
+    rng::Rng,
+    NodeId, NodeMetadata, Value,
+};
+use bytes::Bytes;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashSet},
+    mem,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+/// Behavior of the simulated network
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Range of virtual time units that a message spends in flight
+    pub delay: Range<u64>,
+    /// Probability that a message is held back long enough to arrive after
+    /// messages sent later
+    pub reorder_probability: f64,
+    /// Probability that a message is delivered twice
+    pub duplicate_probability: f64,
+    /// Probability that a message is never delivered
+    pub drop_probability: f64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            delay: 1..10,
+            reorder_probability: 0.0,
+            duplicate_probability: 0.0,
+            drop_probability: 0.0,
+        }
+    }
+}
+
+/// Message sent from one replica to another
+#[derive(Clone, Debug)]
+pub struct Envelope<V = Bytes> {
+    /// Node that sent the command
+    pub from: NodeId,
+    /// Destination of the command
+    pub to: NodeId,
+    /// Command sent between the replicas
+    pub command: Command<V>,
+    /// Metadata sent along with the command
+    pub cmd_metas: CommandMetas,
+}
+
+type Outbox<V = Bytes> = Arc<Mutex<Vec<Envelope<V>>>>;
+
+/// Transport that hands messages to a simulated `Network`
+pub struct SimTransport<V = Bytes> {
+    node: NodeId,
+    outbox: Outbox<V>,
+}
+
+impl<V> Transport<V> for SimTransport<V> {
+    fn send(
+        &mut self,
+        node: NodeId,
+        _: &NodeMetadata,
+        command: Command<V>,
+        cmd_metas: CommandMetas,
+    ) {
+        let envelope = Envelope { from: self.node, to: node, command, cmd_metas };
+        self.outbox.lock().unwrap().push(envelope);
+    }
+}
+
+/// Message scheduled for delivery
+struct InFlight<V> {
+    deliver_at: u64,
+    seq: u64,
+    envelope: Envelope<V>,
+}
+
+impl<V> PartialEq for InFlight<V> {
+    fn eq(&self, other: &InFlight<V>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<V> Eq for InFlight<V> {}
+
+impl<V> PartialOrd for InFlight<V> {
+    fn partial_cmp(&self, other: &InFlight<V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<V> Ord for InFlight<V> {
+    fn cmp(&self, other: &InFlight<V>) -> Ordering {
+        (self.deliver_at, self.seq).cmp(&(other.deliver_at, other.seq))
+    }
+}
+
+/// In-process network of replicas with simulated faults.
+pub struct Network<R, V = Bytes> {
+    replicas: BTreeMap<NodeId, R>,
+    outbox: Outbox<V>,
+    in_flight: BinaryHeap<Reverse<InFlight<V>>>,
+    blocked: HashSet<(NodeId, NodeId)>,
+    config: NetworkConfig,
+    rng: Rng,
+    now: u64,
+    seq: u64,
+    delivered: u64,
+    refused: Vec<(Envelope<V>, PaxosError)>,
+}
+
+impl<V: Value, R: Receiver<V>> Network<R, V> {
+    /// Creates an empty network seeded for reproducibility
+    pub fn new(seed: u64, config: NetworkConfig) -> Network<R, V> {
+        assert!(config.delay.start < config.delay.end);
+        Network {
+            replicas: BTreeMap::new(),
+            outbox: Arc::default(),
+            in_flight: BinaryHeap::new(),
+            blocked: HashSet::new(),
+            config,
+            rng: Rng::new(seed),
+            now: 0,
+            seq: 0,
+            delivered: 0,
+            refused: Vec::new(),
+        }
+    }
+
+    /// Transport for a replica that will be added to the network
+    pub fn transport(&self, node: NodeId) -> SimTransport<V> {
+        SimTransport { node, outbox: self.outbox.clone() }
+    }
+
+    /// Adds a replica built with a transport from this network
+    pub fn add_replica(&mut self, node: NodeId, replica: R) {
+        self.replicas.insert(node, replica);
+    }
+
+    /// Removes a replica from the network. Messages sent to the node are
+    /// dropped until a replica is added again.
+    pub fn remove_replica(&mut self, node: NodeId) -> Option<R> {
+        self.replicas.remove(&node)
+    }
+
+    /// Reference to a replica
+    pub fn replica(&self, node: NodeId) -> Option<&R> {
+        self.replicas.get(&node)
+    }
+
+    /// Mutable reference to a replica. Commands sent by the replica are
+    /// scheduled on the next step.
+    pub fn replica_mut(&mut self, node: NodeId) -> Option<&mut R> {
+        self.replicas.get_mut(&node)
+    }
+
+    /// Iterator over the replicas in node order
+    pub fn replicas(&self) -> impl Iterator<Item = (NodeId, &R)> {
+        self.replicas.iter().map(|(node, replica)| (*node, replica))
+    }
+
+    /// Delivers a command to a replica immediately, such as a proposal from
+    /// a client, returning the replica's error if the command was refused.
+    pub fn inject(
+        &mut self,
+        node: NodeId,
+        command: Command<V>,
+        cmd_metas: CommandMetas,
+    ) -> Result<()> {
+        let res = match self.replicas.get_mut(&node) {
+            Some(replica) => replica.receive(command, cmd_metas),
+            None => Ok(()),
+        };
+        self.schedule_outbox();
+        res
+    }
+
+    /// Blocks messages in both directions between two groups of nodes
+    pub fn partition(&mut self, left: &[NodeId], right: &[NodeId]) {
+        for l in left {
+            for r in right {
+                self.blocked.insert((*l, *r));
+                self.blocked.insert((*r, *l));
+            }
+        }
+    }
+
+    /// Blocks all messages to and from a node
+    pub fn isolate(&mut self, node: NodeId) {
+        let others = self.replicas.keys().copied().filter(|n| *n != node).collect::<Vec<_>>();
+        self.partition(&[node], &others);
+    }
+
+    /// Removes all partitions
+    pub fn heal(&mut self) {
+        self.blocked.clear();
+    }
+
+    /// Current configuration of the network
+    pub fn config_mut(&mut self) -> &mut NetworkConfig {
+        &mut self.config
+    }
+
+    /// Current virtual time
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Number of messages delivered to replicas so far
+    pub fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    /// Messages delivered between replicas that the receiver refused
+    pub fn refused(&self) -> &[(Envelope<V>, PaxosError)] {
+        &self.refused
+    }
+
+    /// Number of messages waiting to be delivered
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len() + self.outbox.lock().unwrap().len()
+    }
+
+    /// Delivers the next message, advancing virtual time. Returns `None`
+    /// once there are no messages left in flight.
+    pub fn step(&mut self) -> Option<Envelope<V>> {
+        self.schedule_outbox();
+
+        let Reverse(InFlight { deliver_at, envelope, .. }) = self.in_flight.pop()?;
+        self.now = deliver_at;
+
+        // partitions may have formed while the message was in flight
+        if !self.blocked.contains(&(envelope.from, envelope.to)) {
+            if let Some(replica) = self.replicas.get_mut(&envelope.to) {
+                self.delivered += 1;
+                let cmd_metas = envelope.cmd_metas.clone();
+                if let Err(e) = replica.receive(envelope.command.clone(), cmd_metas) {
+                    warn!("Node {} refused {:?}: {}", envelope.to, envelope.command, e);
+                    self.refused.push((envelope.clone(), e));
+                }
+            }
+        }
+
+        self.schedule_outbox();
+        Some(envelope)
+    }
+
+    /// Delivers messages until none are left in flight or `max_steps`
+    /// messages have been processed. Returns the number of steps taken.
+    pub fn run(&mut self, max_steps: usize) -> usize {
+        let mut steps = 0;
+        while steps < max_steps && self.step().is_some() {
+            steps += 1;
+        }
+        steps
+    }
+
+    fn schedule_outbox(&mut self) {
+        let mut sent = mem::take(&mut *self.outbox.lock().unwrap());
+
+        // broadcasts iterate over peers in hash order, so order the batch by
+        // destination to keep runs with the same seed identical
+        sent.sort_by_key(|envelope| envelope.to);
+
+        for envelope in sent {
+            if self.blocked.contains(&(envelope.from, envelope.to)) {
+                continue;
+            }
+            if self.rng.chance(self.config.drop_probability) {
+                trace!("Dropping {:?} from {} to {}", envelope.command, envelope.from, envelope.to);
+                continue;
+            }
+            if self.rng.chance(self.config.duplicate_probability) {
+                self.schedule(envelope.clone());
+            }
+            self.schedule(envelope);
+        }
+    }
+
+    fn schedule(&mut self, envelope: Envelope<V>) {
+        let mut delay = self.rng.range(self.config.delay.clone());
+        if self.rng.chance(self.config.reorder_probability) {
+            delay += self.rng.range(self.config.delay.clone()) * 2;
+        }
+
+        self.seq += 1;
+        let in_flight = InFlight { deliver_at: self.now + delay, seq: self.seq, envelope };
+        self.in_flight.push(Reverse(in_flight));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Configuration, Node, Replica, Slot};
+
+    fn network(seed: u64, config: NetworkConfig) -> Network<Node<SimTransport>> {
+        let mut network = Network::new(seed, config);
+        for node in 0..3 {
+            let config = Configuration::new(
+                node,
+                (0..3).filter(|n| *n != node).map(|n| (n, NodeMetadata::default())),
+            )
+            .with_seed(seed.wrapping_add(u64::from(node)));
+            let replica = Node::new(network.transport(node), config);
+            network.add_replica(node, replica);
+        }
+        network
+    }
+
+    fn decisions(network: &Network<Node<SimTransport>>, node: NodeId) -> Vec<(Slot, Bytes)> {
+        network.replica(node).unwrap().decisions().iter().collect()
+    }
+
+    fn propose(network: &mut Network<Node<SimTransport>>, node: NodeId, val: &'static str) {
+        network
+            .inject(node, Command::Proposal { payload: val.into() }, CommandMetas::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn network_decides_with_faults() {
+        let config = NetworkConfig {
+            delay: 1..20,
+            reorder_probability: 0.2,
+            duplicate_probability: 0.2,
+            drop_probability: 0.0,
+        };
+        let mut network = network(7, config);
+
+        propose(&mut network, 0, "a");
+        network.run(1000);
+        propose(&mut network, 1, "b");
+        propose(&mut network, 2, "c");
+        network.run(1000);
+
+        assert_eq!(0, network.in_flight());
+        // duplicated proposals may be decided more than once
+        let expected = decisions(&network, 0);
+        for val in &["a", "b", "c"] {
+            assert!(expected.iter().any(|(_, decided)| decided == val));
+        }
+        for node in 1..3 {
+            assert_eq!(expected, decisions(&network, node));
+        }
+    }
+
+    #[test]
+    fn network_is_reproducible() {
+        let config = NetworkConfig {
+            delay: 1..50,
+            reorder_probability: 0.3,
+            duplicate_probability: 0.3,
+            drop_probability: 0.1,
+        };
+
+        let trace = |seed| {
+            let mut network = network(seed, config.clone());
+            propose(&mut network, 0, "a");
+            propose(&mut network, 2, "b");
+            let mut trace = Vec::new();
+            while let Some(envelope) = network.step() {
+                trace.push((network.now(), envelope.from, envelope.to, envelope.command));
+            }
+            trace
+        };
+
+        assert_eq!(trace(42), trace(42));
+        assert_ne!(trace(42), trace(43));
+    }
+
+    #[test]
+    fn network_partition() {
+        let mut network = network(1, NetworkConfig::default());
+        network.isolate(2);
+
+        propose(&mut network, 0, "a");
+        network.run(1000);
+        assert_eq!(vec![(0, "a".into())], decisions(&network, 0));
+        assert_eq!(vec![(0, "a".into())], decisions(&network, 1));
+        assert!(decisions(&network, 2).is_empty());
+
+        // the isolated node catches up once it hears a new resolution
+        network.heal();
+        propose(&mut network, 0, "b");
+        network.run(1000);
+        let expected = vec![(0, "a".into()), (1, "b".into())];
+        for node in 0..3 {
+            assert_eq!(expected, decisions(&network, node));
+        }
+    }
+}
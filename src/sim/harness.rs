@@ -0,0 +1,485 @@
+//! Randomized simulation of a cluster with continuous safety checks.
+//!
+//! A `Simulation` runs `Node`s wrapped in `StateMachineReplica`s on a
+//! simulated `Network`. Each step may propose a value, time out a node into
+//! proposing leadership, pause or resume a node, or change partitions before
+//! delivering the next message. After every step the `InvariantChecker`
+//! verifies that:
+//!
+//! * no two different values are decided for the same slot,
+//! * the decided prefixes of all replicas agree,
+//! * the state machines execute decisions in slot order, and
+//! * the ballots seen and promised by each node never decrease.
+//!
+//! Paused nodes miss the messages sent to them and keep all of their state
+//! when resumed, like replicas cut off by a long partition. Crash recovery
+//! is not simulated. Refusals that honest replicas cause, such as a
+//! proposal forwarded to a node that no longer leads, are not violations.
+use super::{Network, NetworkConfig, SimTransport};
+use crate::{
+    commands::{Command, CommandMetas},
+    rng::Rng,
+    statemachine::StateMachineReplica,
+    Ballot, Configuration, Node, NodeId, NodeMetadata, PaxosError, ProposalId, Proposed, Replica,
+    ReplicatedState, Slot,
+};
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+type SimReplica =
+    StateMachineReplica<Node<SimTransport<SimValue>, SimValue>, ExecutionLog, SimValue>;
+
+/// Value decided by the simulated replicas
+type SimValue = Proposed<Bytes>;
+
+/// Parameters of a simulation run
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    /// Number of nodes in the cluster
+    pub nodes: u32,
+    /// Number of steps in a run
+    pub steps: usize,
+    /// Faults injected by the network
+    pub network: NetworkConfig,
+    /// Probability of a client proposal on each step
+    pub proposal_probability: f64,
+    /// Probability of a node proposing leadership on each step
+    pub leadership_probability: f64,
+    /// Probability of a node pausing on each step
+    pub pause_probability: f64,
+    /// Probability of a paused node resuming on each step
+    pub resume_probability: f64,
+    /// Probability of a new network partition on each step
+    pub partition_probability: f64,
+    /// Probability of all partitions healing on each step
+    pub heal_probability: f64,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> SimulationConfig {
+        SimulationConfig {
+            nodes: 3,
+            steps: 2000,
+            network: NetworkConfig {
+                delay: 1..20,
+                reorder_probability: 0.1,
+                duplicate_probability: 0.05,
+                drop_probability: 0.05,
+            },
+            proposal_probability: 0.05,
+            leadership_probability: 0.01,
+            pause_probability: 0.005,
+            resume_probability: 0.05,
+            partition_probability: 0.005,
+            heal_probability: 0.02,
+        }
+    }
+}
+
+/// Safety invariant broken during a simulation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Seed of the run that broke the invariant
+    pub seed: u64,
+    /// Step after which the invariant was broken
+    pub step: usize,
+    /// Description of the broken invariant
+    pub description: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "safety violation at step {} of seed {}: {}",
+            self.step, self.seed, self.description
+        )
+    }
+}
+
+/// Summary of a simulation run that upheld all invariants
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// Steps taken
+    pub steps: usize,
+    /// Number of slots decided by the furthest replica
+    pub decided: usize,
+    /// Messages delivered by the network
+    pub delivered: u64,
+}
+
+/// Runs the simulation for each seed in order, returning the violation of
+/// the lowest failing seed.
+pub fn check_seeds(seeds: Range<u64>, config: &SimulationConfig) -> Result<(), Violation> {
+    for seed in seeds {
+        Simulation::new(seed, config.clone()).run()?;
+    }
+    Ok(())
+}
+
+/// Panics with the lowest failing seed if any run breaks an invariant
+pub fn assert_seeds(seeds: Range<u64>, config: &SimulationConfig) {
+    if let Err(violation) = check_seeds(seeds, config) {
+        panic!("{}\nreplay with Simulation::new({}, config).run()", violation, violation.seed);
+    }
+}
+
+/// State machine that records the commands it executes
+#[derive(Clone, Default)]
+struct ExecutionLog(Arc<Mutex<Vec<(Slot, Bytes)>>>);
+
+impl ReplicatedState for ExecutionLog {
+    type Response = ();
+
+    fn execute(&mut self, slot: Slot, command: Bytes) {
+        self.0.lock().unwrap().push((slot, command));
+    }
+}
+
+/// Randomized run of a cluster on a simulated network
+pub struct Simulation {
+    seed: u64,
+    config: SimulationConfig,
+    network: Network<SimReplica, SimValue>,
+    paused: BTreeMap<NodeId, SimReplica>,
+    logs: BTreeMap<NodeId, ExecutionLog>,
+    checker: InvariantChecker,
+    rng: Rng,
+    step: usize,
+    proposals: u64,
+}
+
+impl Simulation {
+    /// Creates a simulation of the configured cluster
+    pub fn new(seed: u64, config: SimulationConfig) -> Simulation {
+        assert!(config.nodes > 0);
+        let mut network = Network::new(seed, config.network.clone());
+        let mut logs = BTreeMap::new();
+        for node in 0..config.nodes {
+            let peers = (0..config.nodes).filter(|n| *n != node);
+            let node_config = Configuration::new(node, peers.map(|n| (n, NodeMetadata::default())))
+                .with_seed(seed.wrapping_add(u64::from(node)));
+            let log = ExecutionLog::default();
+            let replica =
+                Node::new(network.transport(node), node_config).state_machine(log.clone());
+            network.add_replica(node, replica);
+            logs.insert(node, log);
+        }
+
+        Simulation {
+            seed,
+            config,
+            network,
+            paused: BTreeMap::new(),
+            logs,
+            checker: InvariantChecker::default(),
+            // separate stream from the network's generator
+            rng: Rng::new(!seed),
+            step: 0,
+            proposals: 0,
+        }
+    }
+
+    /// Runs the configured number of steps
+    pub fn run(&mut self) -> Result<Report, Violation> {
+        while self.step < self.config.steps {
+            self.step()?;
+        }
+        Ok(self.report())
+    }
+
+    /// Takes a single step of the simulation and checks the invariants
+    pub fn step(&mut self) -> Result<(), Violation> {
+        self.step += 1;
+
+        if self.rng.chance(self.config.proposal_probability) {
+            if let Some(node) = self.random_live_node() {
+                self.proposals += 1;
+                let value = Bytes::from(format!("value-{}", self.proposals));
+                let id = ProposalId { node, seq: self.proposals };
+                let proposal = Command::Proposal { payload: Proposed { id, value } };
+                if let Err(e) = self.network.inject(node, proposal, metas()) {
+                    debug!("Node {} refused proposal at step {}: {}", node, self.step, e);
+                }
+            }
+        }
+
+        if self.rng.chance(self.config.leadership_probability) {
+            if let Some(node) = self.random_live_node() {
+                if let Some(replica) = self.network.replica_mut(node) {
+                    replica.propose_leadership(metas());
+                }
+            }
+        }
+
+        if self.rng.chance(self.config.pause_probability) {
+            if let Some(node) = self.random_live_node() {
+                debug!("Pausing node {} at step {}", node, self.step);
+                if let Some(replica) = self.network.remove_replica(node) {
+                    self.paused.insert(node, replica);
+                }
+            }
+        }
+
+        if !self.paused.is_empty() && self.rng.chance(self.config.resume_probability) {
+            let i = self.rng.range(0..self.paused.len() as u64) as usize;
+            let node = *self.paused.keys().nth(i).unwrap();
+            debug!("Resuming node {} at step {}", node, self.step);
+            let replica = self.paused.remove(&node).unwrap();
+            self.network.add_replica(node, replica);
+        }
+
+        if self.rng.chance(self.config.partition_probability) {
+            let (left, right): (Vec<NodeId>, Vec<NodeId>) =
+                (0..self.config.nodes).partition(|_| self.rng.chance(0.5));
+            debug!("Partitioning {:?} from {:?} at step {}", left, right, self.step);
+            self.network.partition(&left, &right);
+        }
+
+        if self.rng.chance(self.config.heal_probability) {
+            self.network.heal();
+        }
+
+        self.network.step();
+        self.check()
+    }
+
+    fn random_live_node(&mut self) -> Option<NodeId> {
+        let live = self.network.replicas().map(|(node, _)| node).collect::<Vec<_>>();
+        if live.is_empty() {
+            return None;
+        }
+        Some(live[self.rng.range(0..live.len() as u64) as usize])
+    }
+
+    fn check(&mut self) -> Result<(), Violation> {
+        let refused = self.network.refused().iter().find(|(_, e)| !expected_refusal(e));
+        if let Some((envelope, e)) = refused {
+            let description = format!(
+                "node {} refused {:?} from node {}: {}",
+                envelope.to, envelope.command, envelope.from, e
+            );
+            return Err(Violation { seed: self.seed, step: self.step, description });
+        }
+
+        let checker = &mut self.checker;
+        let logs = &self.logs;
+        let replicas = self.network.replicas().chain(self.paused.iter().map(|(n, r)| (*n, r)));
+        for (node, replica) in replicas {
+            let inner = replica.inner();
+            let result = checker
+                .observe_ballots(node, inner.highest_observed_ballot(), inner.promised())
+                .and_then(|_| {
+                    let decisions = replica.decisions();
+                    let values = decisions.iter().map(|(slot, proposed)| (slot, proposed.value));
+                    checker.observe_decisions(node, values)
+                })
+                .and_then(|_| checker.observe_executions(node, &logs[&node].0.lock().unwrap()));
+
+            if let Err(description) = result {
+                return Err(Violation { seed: self.seed, step: self.step, description });
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self) -> Report {
+        let replicas = self.network.replicas().chain(self.paused.iter().map(|(n, r)| (*n, r)));
+        Report {
+            steps: self.step,
+            decided: replicas.map(|(_, replica)| replica.decisions().len()).max().unwrap_or(0),
+            delivered: self.network.delivered(),
+        }
+    }
+}
+
+fn metas() -> CommandMetas {
+    CommandMetas::default()
+}
+
+/// Refusals of commands that honest replicas send, such as proposals
+/// forwarded to a node that lost leadership or whose window is full
+fn expected_refusal(e: &PaxosError) -> bool {
+    matches!(
+        e,
+        PaxosError::NotLeader { .. }
+            | PaxosError::SlotOutOfWindow { .. }
+            | PaxosError::QuorumLost
+            | PaxosError::Frozen { .. }
+    )
+}
+
+/// Accumulates observations of replicas and checks them against the Paxos
+/// safety properties.
+#[derive(Default)]
+pub struct InvariantChecker {
+    /// Value decided for each slot by any replica
+    chosen: BTreeMap<Slot, (NodeId, Bytes)>,
+    /// Last observed and promised ballots of each node
+    ballots: HashMap<NodeId, (Option<Ballot>, Option<Ballot>)>,
+}
+
+impl InvariantChecker {
+    /// Checks the decided prefix of a node against the decisions of all
+    /// nodes observed so far.
+    pub fn observe_decisions<I>(&mut self, node: NodeId, decisions: I) -> Result<(), String>
+    where
+        I: Iterator<Item = (Slot, Bytes)>,
+    {
+        for (expected_slot, (slot, value)) in decisions.enumerate() {
+            if expected_slot as Slot != slot {
+                return Err(format!("node {} has a hole in its decisions at slot {}", node, slot));
+            }
+
+            match self.chosen.get(&slot) {
+                Some((chosen_by, chosen)) if *chosen != value => {
+                    return Err(format!(
+                        "slot {} decided as {:?} by node {} and {:?} by node {}",
+                        slot, chosen, chosen_by, value, node
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    self.chosen.insert(slot, (node, value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the state machine of a node executed the non-empty
+    /// decisions in increasing slot order.
+    pub fn observe_executions(
+        &mut self,
+        node: NodeId,
+        executed: &[(Slot, Bytes)],
+    ) -> Result<(), String> {
+        let mut last: Option<Slot> = None;
+        for (slot, value) in executed {
+            if last.map(|last| last >= *slot).unwrap_or(false) {
+                return Err(format!("node {} executed slot {} out of order", node, slot));
+            }
+            last = Some(*slot);
+
+            match self.chosen.get(slot) {
+                Some((_, chosen)) if chosen == value => {}
+                _ => {
+                    return Err(format!(
+                        "node {} executed {:?} for slot {} which was not decided",
+                        node, value, slot
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the ballots of a node have not decreased since the last
+    /// observation.
+    pub fn observe_ballots(
+        &mut self,
+        node: NodeId,
+        observed: Option<Ballot>,
+        promised: Option<Ballot>,
+    ) -> Result<(), String> {
+        let (last_observed, last_promised) = self.ballots.entry(node).or_insert((None, None));
+        if observed < *last_observed {
+            return Err(format!(
+                "node {} observed ballot regressed from {:?} to {:?}",
+                node, last_observed, observed
+            ));
+        }
+        if promised < *last_promised {
+            return Err(format!(
+                "node {} promised ballot regressed from {:?} to {:?}",
+                node, last_promised, promised
+            ));
+        }
+        *last_observed = observed;
+        *last_promised = promised;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulation_is_safe() {
+        assert_seeds(0..20, &SimulationConfig::default());
+    }
+
+    #[test]
+    fn simulation_makes_progress() {
+        let config = SimulationConfig {
+            pause_probability: 0.0,
+            partition_probability: 0.0,
+            ..SimulationConfig::default()
+        };
+        let report = Simulation::new(3, config).run().unwrap();
+        assert!(report.decided > 0);
+    }
+
+    #[test]
+    fn simulation_makes_progress_under_contention() {
+        // nodes frequently run for leadership and preempt each other's
+        // elections, which backing off after preemptions keeps from livelocking
+        let config = SimulationConfig {
+            network: NetworkConfig::default(),
+            proposal_probability: 0.2,
+            leadership_probability: 0.2,
+            pause_probability: 0.0,
+            partition_probability: 0.0,
+            ..SimulationConfig::default()
+        };
+        for seed in 0..10 {
+            let report = Simulation::new(seed, config.clone()).run().unwrap();
+            assert!(report.decided >= 50, "seed {} decided {} slots", seed, report.decided);
+        }
+    }
+
+    #[test]
+    fn simulation_is_reproducible() {
+        let config = SimulationConfig::default();
+        assert_eq!(Simulation::new(11, config.clone()).run(), Simulation::new(11, config).run());
+    }
+
+    #[test]
+    fn honest_refusals_are_expected() {
+        assert!(expected_refusal(&PaxosError::NotLeader { leader: Some(1) }));
+        assert!(expected_refusal(&PaxosError::SlotOutOfWindow { slot: 40, window: 0..32 }));
+        let violation = PaxosError::ProtocolViolation { from: 1, reason: "unexpected promise" };
+        assert!(!expected_refusal(&violation));
+    }
+
+    #[test]
+    fn checker_detects_conflicting_decisions() {
+        let mut checker = InvariantChecker::default();
+        let decisions = vec![(0, Bytes::from("a")), (1, Bytes::from("b"))];
+        assert!(checker.observe_decisions(0, decisions.into_iter()).is_ok());
+
+        let decisions = vec![(0, Bytes::from("a"))];
+        assert!(checker.observe_decisions(1, decisions.into_iter()).is_ok());
+
+        let decisions = vec![(0, Bytes::from("a")), (1, Bytes::from("c"))];
+        assert!(checker.observe_decisions(2, decisions.into_iter()).is_err());
+
+        let executed = vec![(1, Bytes::from("b")), (0, Bytes::from("a"))];
+        assert!(checker.observe_executions(0, &executed).is_err());
+    }
+
+    #[test]
+    fn checker_detects_ballot_regression() {
+        let mut checker = InvariantChecker::default();
+        assert!(checker.observe_ballots(0, Some(Ballot(1, 0)), None).is_ok());
+        assert!(checker.observe_ballots(0, Some(Ballot(2, 1)), Some(Ballot(2, 1))).is_ok());
+        assert!(checker.observe_ballots(0, Some(Ballot(2, 1)), Some(Ballot(1, 0))).is_err());
+        assert!(checker.observe_ballots(1, Some(Ballot(1, 0)), None).is_ok());
+    }
+}
@@ -1,63 +1,402 @@
+//! Application of decided commands to the state machine of a replica.
+pub mod parallel;
+
 use crate::{
     commands::{Command, CommandMetas, Receiver},
-    DecisionSet, Replica, Slot,
+    error::{PaxosError, Result},
+    node::MAX_FAILED_PROPOSALS,
+    DecisionSet, Identified, NodeId, ProposalId, ProposalIds, Replica, Slot, Value,
 };
 use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
 
 /// A state machine that executes sequentially applied commands.
-pub trait ReplicatedState {
+pub trait ReplicatedState<V = Bytes> {
+    /// Response of the state machine to a command, returned to the node
+    /// that proposed the command
+    type Response;
+
     /// Apply a value to the state machine.
     ///
     /// Values are applied in increasing _slot_ order. There may be holes
     /// such that there is no guarantee that _slot-1_ has been
-    /// applied before _slot_.
-    fn execute(&mut self, slot: Slot, command: Bytes);
+    /// applied before _slot_. No-op values filling holes are not applied.
+    fn execute(&mut self, slot: Slot, command: V) -> Self::Response;
+
+    /// Answers a query, expressed as a command, without changing the state.
+    /// Commands that can only be answered by executing them are refused.
+    fn query(&self, _query: &V) -> Option<Self::Response> {
+        None
+    }
+
+    /// Blocks until every executed command is applied. The replica flushes
+    /// the state before completing proposals or answering queries, so state
+    /// machines applying commands in the background wait for them here.
+    fn flush(&mut self) {}
+
+    /// Determines if a command freezes the group, such as the shard
+    /// operations of `multi::shard`. The command is not executed, and the
+    /// group stops executing commands after it.
+    fn freezes(&self, _command: &V) -> bool {
+        false
+    }
+}
+
+/// Commands reflected by the state answering a query
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// Reflects every command decided before the query was made. The query
+    /// is answered by the leader once a quorum confirms that it still leads.
+    Linearizable,
+    /// Reflects the commands of every slot before the slot, such as the
+    /// slot answered along with an earlier query, or the slot following an
+    /// earlier proposal.
+    Sequential(Slot),
+    /// Reflects the local state once it lags at most `max_lag` slots behind
+    /// the slots known to be decided.
+    Stale { max_lag: Slot },
+}
+
+/// Outcome of a proposal, shared by the replica and the proposer
+struct Shared<T> {
+    result: Option<Result<(Slot, T)>>,
+    waker: Option<Waker>,
+}
+
+/// Outcome of a proposal awaited by a `PendingResponse`
+type Outcome<T> = Arc<Mutex<Shared<T>>>;
+
+/// Completes the proposal waiting on a shared outcome
+fn complete<T>(shared: &Mutex<Shared<T>>, result: Result<(Slot, T)>) {
+    let mut shared = shared.lock().unwrap();
+    shared.result = Some(result);
+    if let Some(waker) = shared.waker.take() {
+        waker.wake();
+    }
+}
+
+/// Future resolving to the slot in which a proposal was decided, along
+/// with the response of the state machine to it.
+///
+/// Proposals fail when the replica gives up on them or when the group is
+/// frozen by a shard operation before they are executed.
+pub struct PendingResponse<T> {
+    shared: Outcome<T>,
 }
 
-/// Replica that executes commands within a state machine
-pub struct StateMachineReplica<R: Replica, S: ReplicatedState> {
+impl<T> PendingResponse<T> {
+    /// Takes the outcome of the proposal without waiting, if it is known
+    pub fn try_take(&mut self) -> Option<Result<(Slot, T)>> {
+        self.shared.lock().unwrap().result.take()
+    }
+}
+
+impl<T> Future for PendingResponse<T> {
+    type Output = Result<(Slot, T)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Query waiting on the state to reflect the slots before `after`
+struct PendingQuery<V, T> {
+    query: V,
+    after: Slot,
+    /// Read awaiting confirmation by the leader's quorum, if any
+    read: Option<u64>,
+    outcome: Outcome<T>,
+}
+
+/// Replica that executes commands within a state machine.
+///
+/// Commands are decided along with the identity of their proposal, so that
+/// proposals of equal commands are told apart when completing them.
+pub struct StateMachineReplica<R, S, V = Bytes>
+where
+    V: Identified,
+    R: Replica<V>,
+    S: ReplicatedState<V::Command>,
+{
     inner: R,
     state_machine: S,
     next_execution_slot: Slot,
+    /// Command that froze the group, and its slot
+    frozen: Option<(Slot, V::Command)>,
+    ids: ProposalIds,
+    /// Proposals made through this replica awaiting their response
+    pending: HashMap<ProposalId, Outcome<S::Response>>,
+    /// Proposals made by other means that the inner replica gave up on
+    failed: Vec<V>,
+    queries: Vec<PendingQuery<V::Command, S::Response>>,
+    /// Reads confirmed for callers of `confirm_read`
+    confirmed_reads: Vec<(u64, Result<Slot>)>,
+    value: PhantomData<fn(V)>,
 }
 
-impl<R: Replica, S: ReplicatedState> StateMachineReplica<R, S> {
-    pub(crate) fn new(replica: R, state_machine: S) -> StateMachineReplica<R, S> {
-        StateMachineReplica { inner: replica, state_machine, next_execution_slot: 0 }
+impl<V, R, S> StateMachineReplica<R, S, V>
+where
+    V: Identified,
+    R: Replica<V>,
+    S: ReplicatedState<V::Command>,
+{
+    pub(crate) fn new(replica: R, state_machine: S) -> StateMachineReplica<R, S, V> {
+        StateMachineReplica {
+            ids: ProposalIds::new(replica.node()),
+            inner: replica,
+            state_machine,
+            next_execution_slot: 0,
+            frozen: None,
+            pending: HashMap::new(),
+            failed: Vec::new(),
+            queries: Vec::new(),
+            confirmed_reads: Vec::new(),
+            value: PhantomData,
+        }
+    }
+
+    /// Replica that decides the commands applied to the state machine
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// State machine applying the decided commands
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Command that froze the group, and the slot it was decided in
+    pub fn frozen(&self) -> Option<(Slot, &V::Command)> {
+        self.frozen.as_ref().map(|(slot, command)| (*slot, command))
+    }
+
+    /// Proposes a command, resolving to the response of the state machine
+    /// once the command is executed
+    pub fn propose(
+        &mut self,
+        command: V::Command,
+        cmd_metas: CommandMetas,
+    ) -> Result<PendingResponse<S::Response>> {
+        // proposers that dropped their response no longer need one
+        self.pending.retain(|_, shared| Arc::strong_count(shared) > 1);
+
+        let id = self.ids.next();
+        let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+        self.pending.insert(id, shared.clone());
+        let proposal = Command::Proposal { payload: V::identify(id, command) };
+        if let Err(e) = self.receive(proposal, cmd_metas) {
+            self.pending.remove(&id);
+            return Err(e);
+        }
+        Ok(PendingResponse { shared })
+    }
+
+    /// Answers a query from the state machine without deciding it in a
+    /// slot. The response comes along with the slot before which every
+    /// decided command is reflected by the state. Linearizable queries are
+    /// refused by replicas that do not lead.
+    pub fn query(
+        &mut self,
+        query: V::Command,
+        consistency: Consistency,
+        cmd_metas: CommandMetas,
+    ) -> Result<PendingResponse<S::Response>> {
+        let (after, read) = match consistency {
+            Consistency::Linearizable => (0, Some(self.inner.confirm_read(cmd_metas)?)),
+            Consistency::Sequential(slot) => (slot, None),
+            Consistency::Stale { max_lag } => {
+                (self.inner.known_decided().saturating_sub(max_lag), None)
+            }
+        };
+
+        let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+        self.queries.push(PendingQuery { query, after, read, outcome: shared.clone() });
+        self.answer_queries();
+        Ok(PendingResponse { shared })
     }
 
     fn try_execute_slots(&mut self) {
-        let mut next_slot = self.next_execution_slot;
-        let decided = self.decisions().range(self.next_execution_slot..).collect::<Vec<_>>();
-        for (slot, decision) in decided {
-            if !decision.is_empty() {
-                self.state_machine.execute(slot, decision)
+        if self.frozen.is_some() {
+            return;
+        }
+        let first_slot = self.next_execution_slot;
+        let mut responses = Vec::new();
+        let decided = self.decisions().range(first_slot..).collect::<Vec<_>>();
+        for (slot, value) in decided {
+            let (id, value) = value.into_parts();
+            // advanced before applying, so a panic never applies the slot twice
+            self.next_execution_slot = slot + 1;
+            if value.is_noop() {
+                continue;
+            }
+            // commands decided after a shard operation belong to the new groups
+            if self.state_machine.freezes(&value) {
+                self.frozen = Some((slot, value));
+                for (_, shared) in self.pending.drain() {
+                    complete(&shared, Err(PaxosError::Frozen { slot }));
+                }
+                break;
+            }
+
+            let response = self.state_machine.execute(slot, value);
+            if let Some(shared) = self.pending.remove(&id) {
+                responses.push((slot, response, shared));
+            }
+        }
+
+        if self.next_execution_slot > first_slot {
+            self.state_machine.flush();
+        }
+        for (slot, response, shared) in responses {
+            complete(&shared, Ok((slot, response)));
+        }
+    }
+
+    /// Fails the proposals that the inner replica gave up on
+    fn fail_proposals(&mut self) {
+        for proposal in self.inner.take_failed_proposals() {
+            let (id, _) = proposal.clone().into_parts();
+            match self.pending.remove(&id) {
+                Some(shared) => complete(&shared, Err(PaxosError::QuorumLost)),
+                None => self.failed.push(proposal),
             }
-            next_slot = slot + 1;
         }
-        self.next_execution_slot = next_slot;
+
+        let dropped = self.failed.len().saturating_sub(MAX_FAILED_PROPOSALS);
+        if dropped > 0 {
+            warn!("Dropping {} failed proposals that were never taken", dropped);
+            self.failed.drain(..dropped);
+        }
+    }
+
+    /// Answers the queries that the state reflects the slots for
+    fn answer_queries(&mut self) {
+        for (read, res) in self.inner.take_confirmed_reads() {
+            let i = match self.queries.iter().position(|query| query.read == Some(read)) {
+                Some(i) => i,
+                None => {
+                    self.confirmed_reads.push((read, res));
+                    continue;
+                }
+            };
+            match res {
+                Ok(index) => {
+                    self.queries[i].read = None;
+                    self.queries[i].after = index;
+                }
+                Err(e) => complete(&self.queries.remove(i).outcome, Err(e)),
+            }
+        }
+
+        // commands decided after a shard operation are never executed
+        let executed = self.next_execution_slot;
+        let frozen = self.frozen.as_ref().map(|(slot, _)| *slot);
+        let answerable = |query: &PendingQuery<V::Command, S::Response>| {
+            query.read.is_none() && (query.after <= executed || frozen.is_some())
+        };
+        let (ready, waiting): (Vec<_>, Vec<_>) = mem::take(&mut self.queries)
+            .into_iter()
+            .filter(|query| Arc::strong_count(&query.outcome) > 1)
+            .partition(answerable);
+        self.queries = waiting;
+
+        for query in ready {
+            let result = match frozen {
+                Some(slot) if query.after > executed => Err(PaxosError::Frozen { slot }),
+                _ => match self.state_machine.query(&query.query) {
+                    Some(response) => Ok((executed, response)),
+                    None => Err(PaxosError::UnsupportedQuery),
+                },
+            };
+            complete(&query.outcome, result);
+        }
     }
 }
 
-impl<R: Replica, S: ReplicatedState> Receiver for StateMachineReplica<R, S> {
-    fn receive(&mut self, cmd: Command, cmd_metas: CommandMetas) {
-        self.inner.receive(cmd, cmd_metas);
+impl<V, R, S> Receiver<V> for StateMachineReplica<R, S, V>
+where
+    V: Identified,
+    R: Replica<V>,
+    S: ReplicatedState<V::Command>,
+{
+    fn receive(&mut self, cmd: Command<V>, cmd_metas: CommandMetas) -> Result<()> {
+        if let (Some((slot, _)), Command::Proposal { .. }) = (&self.frozen, &cmd) {
+            return Err(PaxosError::Frozen { slot: *slot });
+        }
+
+        // a refused command may still have decided slots before the error
+        let res = self.inner.receive(cmd, cmd_metas);
         self.try_execute_slots();
+        self.fail_proposals();
+        self.answer_queries();
+        res
     }
 }
 
-impl<R: Replica, S: ReplicatedState> Replica for StateMachineReplica<R, S> {
+impl<V, R, S> Replica<V> for StateMachineReplica<R, S, V>
+where
+    V: Identified,
+    R: Replica<V>,
+    S: ReplicatedState<V::Command>,
+{
+    fn node(&self) -> NodeId {
+        self.inner.node()
+    }
+
+    fn is_peer(&self, node: NodeId) -> bool {
+        self.inner.is_peer(node)
+    }
+
     fn propose_leadership(&mut self, cmd_metas: CommandMetas) {
         self.inner.propose_leadership(cmd_metas);
+        self.fail_proposals();
+        self.answer_queries();
     }
 
     fn is_leader(&self) -> bool {
         self.inner.is_leader()
     }
 
-    fn decisions(&self) -> DecisionSet {
+    fn decisions(&self) -> DecisionSet<'_, V> {
         self.inner.decisions()
     }
+
+    fn queued_proposals(&self) -> usize {
+        self.inner.queued_proposals()
+    }
+
+    fn take_failed_proposals(&mut self) -> Vec<V> {
+        self.fail_proposals();
+        mem::take(&mut self.failed)
+    }
+
+    fn known_decided(&self) -> Slot {
+        self.inner.known_decided()
+    }
+
+    fn confirm_read(&mut self, cmd_metas: CommandMetas) -> Result<u64> {
+        self.inner.confirm_read(cmd_metas)
+    }
+
+    fn take_confirmed_reads(&mut self) -> Vec<(u64, Result<Slot>)> {
+        self.answer_queries();
+        mem::take(&mut self.confirmed_reads)
+    }
 }
 
 #[cfg(test)]
@@ -66,17 +405,23 @@ mod tests {
     use crate::{
         commands::{Command, Receiver},
         window::{DecisionSet, SlotWindow},
-        Ballot, Slot,
+        Ballot, Configuration, Node, NodeMetadata, Slot, Transport,
+    };
+    use parallel::{ConcurrentState, ParallelExecutor};
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        thread,
+        time::Duration,
     };
 
     #[test]
     fn resolve_executes_decisions() {
-        let mut inner_replica = FakeReplica(SlotWindow::new(2));
+        let mut inner_replica = FakeReplica(SlotWindow::<Bytes>::new(2));
         {
-            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), "0".into());
+            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), proposed("0"));
         }
         {
-            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), "1".into());
+            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), proposed("1"));
         }
         {
             inner_replica
@@ -85,17 +430,21 @@ mod tests {
                 .unwrap_empty()
                 .fill()
                 .acceptor()
-                .resolve(Ballot(2, 2), "2".into());
+                .resolve(Ballot(2, 2), proposed("2"));
         }
 
         let mut replica = StateMachineReplica::new(inner_replica, VecStateMachine::default());
-        let cmd_metas = CommandMetas("".into());
-        replica.receive(Command::Resolution { payload: (Ballot(2, 2), vec![]) }, cmd_metas.clone());
+        let cmd_metas = CommandMetas::default();
+        replica
+            .receive(Command::Resolution { payload: (Ballot(2, 2), vec![]) }, cmd_metas.clone())
+            .unwrap();
         assert_eq!(vec![(0u64, Bytes::from("0")), (1, Bytes::from("1"))], replica.state_machine.0);
         replica.state_machine.0.clear();
 
         // does not happen again
-        replica.receive(Command::Resolution { payload: (Ballot(2, 2), vec![]) }, cmd_metas.clone());
+        replica
+            .receive(Command::Resolution { payload: (Ballot(2, 2), vec![]) }, cmd_metas.clone())
+            .unwrap();
         assert!(replica.state_machine.0.is_empty());
 
         // fill hole in slot 2, freeing 3
@@ -106,21 +455,23 @@ mod tests {
                 .slot_mut(2)
                 .unwrap_open()
                 .acceptor()
-                .resolve(Ballot(1, 1), Bytes::default());
+                .resolve(Ballot(1, 1), Bytes::noop());
         }
 
-        replica.receive(Command::Resolution { payload: (Ballot(2, 2), vec![]) }, cmd_metas.clone());
+        replica
+            .receive(Command::Resolution { payload: (Ballot(2, 2), vec![]) }, cmd_metas.clone())
+            .unwrap();
         assert_eq!(vec![(3u64, Bytes::from("2"))], replica.state_machine.0);
     }
 
     #[test]
     fn accepted_executes_decisions() {
-        let mut inner_replica = FakeReplica(SlotWindow::new(2));
+        let mut inner_replica = FakeReplica(SlotWindow::<Bytes>::new(2));
         {
-            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), "0".into());
+            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), proposed("0"));
         }
         {
-            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), "1".into());
+            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), proposed("1"));
         }
         {
             inner_replica
@@ -129,19 +480,21 @@ mod tests {
                 .unwrap_empty()
                 .fill()
                 .acceptor()
-                .resolve(Ballot(2, 2), "2".into());
+                .resolve(Ballot(2, 2), proposed("2"));
         }
 
         let mut replica = StateMachineReplica::new(inner_replica, VecStateMachine::default());
-        let cmd_metas = CommandMetas("".into());
+        let cmd_metas = CommandMetas::default();
         replica
-            .receive(Command::Accepted { payload: (0, Ballot(2, 2), vec![]) }, cmd_metas.clone());
+            .receive(Command::Accepted { payload: (0, Ballot(2, 2), vec![]) }, cmd_metas.clone())
+            .unwrap();
         assert_eq!(vec![(0u64, Bytes::from("0")), (1, Bytes::from("1"))], replica.state_machine.0);
         replica.state_machine.0.clear();
 
         // does not happen again
         replica
-            .receive(Command::Accepted { payload: (1, Ballot(2, 2), vec![]) }, cmd_metas.clone());
+            .receive(Command::Accepted { payload: (1, Ballot(2, 2), vec![]) }, cmd_metas.clone())
+            .unwrap();
         assert!(replica.state_machine.0.is_empty());
 
         // fill hole in slot 2, freeing 3
@@ -152,28 +505,183 @@ mod tests {
                 .slot_mut(2)
                 .unwrap_open()
                 .acceptor()
-                .resolve(Ballot(1, 1), Bytes::default());
+                .resolve(Ballot(1, 1), Bytes::noop());
         }
 
         replica
-            .receive(Command::Accepted { payload: (2, Ballot(2, 2), vec![]) }, cmd_metas.clone());
+            .receive(Command::Accepted { payload: (2, Ballot(2, 2), vec![]) }, cmd_metas.clone())
+            .unwrap();
         assert_eq!(vec![(3u64, Bytes::from("2"))], replica.state_machine.0);
     }
 
+    #[test]
+    fn query_consistency() {
+        let mut inner_replica = FakeReplica(SlotWindow::<Bytes>::new(2));
+        {
+            inner_replica.0.next_slot().acceptor().resolve(Ballot(1, 1), proposed("0"));
+        }
+        {
+            inner_replica
+                .0
+                .slot_mut(2)
+                .unwrap_empty()
+                .fill()
+                .acceptor()
+                .resolve(Ballot(1, 1), proposed("2"));
+        }
+
+        let mut replica = StateMachineReplica::new(inner_replica, VecStateMachine::default());
+        let cmd_metas = CommandMetas::default();
+        let resolution = Command::Resolution { payload: (Ballot(1, 1), vec![]) };
+        replica.receive(resolution.clone(), cmd_metas.clone()).unwrap();
+
+        let mut stale = replica
+            .query("q".into(), Consistency::Stale { max_lag: 0 }, cmd_metas.clone())
+            .unwrap();
+        assert_eq!(Some(Ok((1, 1))), stale.try_take());
+        let mut sequential =
+            replica.query("q".into(), Consistency::Sequential(3), cmd_metas.clone()).unwrap();
+        assert!(sequential.try_take().is_none());
+        assert_eq!(
+            Some(PaxosError::NotLeader { leader: None }),
+            replica.query("q".into(), Consistency::Linearizable, cmd_metas.clone()).err()
+        );
+
+        // fill hole in slot 1, executing slot 2 for the sequential query
+        {
+            replica
+                .inner
+                .0
+                .slot_mut(1)
+                .unwrap_open()
+                .acceptor()
+                .resolve(Ballot(1, 1), proposed("1"));
+        }
+        replica.receive(resolution, cmd_metas).unwrap();
+        assert_eq!(Some(Ok((3, 3))), sequential.try_take());
+    }
+
+    #[test]
+    fn forwards_confirmed_reads() {
+        let peers = (1..3).map(|node| (node, NodeMetadata::default()));
+        let config = Configuration::new(0, peers);
+        let mut replica = Node::new(NoTransport, config).state_machine(VecStateMachine::default());
+        let cmd_metas = CommandMetas::default();
+        replica.propose_leadership(cmd_metas.clone());
+        let promise = Command::Promise { payload: (1, Ballot(0, 0), vec![]) };
+        replica.receive(promise, cmd_metas.clone()).unwrap();
+
+        // reads of the replica's own queries are not handed out
+        let read = replica.confirm_read(cmd_metas.clone()).unwrap();
+        let mut query =
+            replica.query("q".into(), Consistency::Linearizable, cmd_metas.clone()).unwrap();
+        for id in read..read + 2 {
+            let confirmed = Command::Confirmed { payload: (1, Ballot(0, 0), id) };
+            replica.receive(confirmed, cmd_metas.clone()).unwrap();
+        }
+        assert_eq!(vec![(read, Ok(1))], replica.take_confirmed_reads());
+        assert!(query.try_take().is_none());
+
+        // the query is answered once the no-op filling slot 0 is decided
+        let accepted = Command::Accepted { payload: (1, Ballot(0, 0), vec![0]) };
+        replica.receive(accepted, cmd_metas).unwrap();
+        assert_eq!(Some(Ok((1, 0))), query.try_take());
+    }
+
+    #[test]
+    fn parallel_executor_applies_before_responding() {
+        let inner_replica = FakeReplica(SlotWindow::<Bytes>::new(2));
+        let executor = ParallelExecutor::new(SlowLog::default(), 2);
+        let mut replica = StateMachineReplica::new(inner_replica, executor);
+        let cmd_metas = CommandMetas::default();
+
+        let mut response = replica.propose("0".into(), cmd_metas.clone()).unwrap();
+        let mut query = replica.query("q".into(), Consistency::Sequential(1), cmd_metas).unwrap();
+        assert_eq!(Some(1), query.try_take().map(|res| res.unwrap().1.wait()));
+        let (slot, applied) = response.try_take().unwrap().unwrap();
+        assert_eq!((0, 1), (slot, applied.wait()));
+    }
+
+    #[test]
+    fn worker_panics_do_not_apply_slots_again() {
+        let inner_replica = FakeReplica(SlotWindow::<Bytes>::new(2));
+        let executor = ParallelExecutor::new(SlowLog::default(), 1);
+        let mut replica = StateMachineReplica::new(inner_replica, executor);
+        let cmd_metas = CommandMetas::default();
+
+        let refused = || replica.propose("!".into(), cmd_metas.clone());
+        assert!(panic::catch_unwind(AssertUnwindSafe(refused)).is_err());
+        let mut response = replica.propose("1".into(), cmd_metas).unwrap();
+        let (slot, applied) = response.try_take().unwrap().unwrap();
+        assert_eq!((1, 1), (slot, applied.wait()));
+    }
+
+    #[derive(Default)]
+    struct SlowLog(Mutex<Vec<Bytes>>);
+    impl ConcurrentState for SlowLog {
+        type Response = usize;
+
+        fn conflict_keys(&self, _command: &Bytes) -> Option<Vec<Bytes>> {
+            Some(Vec::new())
+        }
+
+        fn execute(&self, _slot: Slot, command: Bytes) -> usize {
+            thread::sleep(Duration::from_millis(20));
+            assert_ne!(command, "!", "refused command");
+            let mut applied = self.0.lock().unwrap();
+            applied.push(command);
+            applied.len()
+        }
+
+        fn query(&self, _query: &Bytes) -> Option<usize> {
+            Some(self.0.lock().unwrap().len())
+        }
+    }
+
     #[derive(Default)]
     struct VecStateMachine(Vec<(Slot, Bytes)>);
     impl ReplicatedState for VecStateMachine {
-        fn execute(&mut self, slot: Slot, val: Bytes) {
-            self.0.push((slot, val))
+        type Response = usize;
+
+        fn execute(&mut self, slot: Slot, val: Bytes) -> usize {
+            self.0.push((slot, val));
+            self.0.len()
+        }
+
+        fn query(&self, _query: &Bytes) -> Option<usize> {
+            Some(self.0.len())
         }
     }
 
-    struct FakeReplica(SlotWindow);
-    impl Receiver for FakeReplica {
-        fn receive(&mut self, _cmd: Command, _cmd_metas: CommandMetas) {}
+    fn proposed(value: &'static str) -> Bytes {
+        Bytes::identify(ProposalId::default(), value.into())
+    }
+
+    struct NoTransport;
+    impl Transport<Bytes> for NoTransport {
+        fn send(&mut self, _: NodeId, _: &NodeMetadata, _: Command<Bytes>, _: CommandMetas) {}
+    }
+
+    /// Replica deciding proposals in the next slot as soon as they are made
+    struct FakeReplica(SlotWindow<Bytes>);
+    impl Receiver<Bytes> for FakeReplica {
+        fn receive(&mut self, cmd: Command<Bytes>, _: CommandMetas) -> Result<()> {
+            if let Command::Proposal { payload } = cmd {
+                self.0.next_slot().acceptor().resolve(Ballot(1, 1), payload);
+            }
+            Ok(())
+        }
     }
 
-    impl Replica for FakeReplica {
+    impl Replica<Bytes> for FakeReplica {
+        fn node(&self) -> NodeId {
+            0
+        }
+
+        fn is_peer(&self, _node: NodeId) -> bool {
+            true
+        }
+
         fn propose_leadership(&mut self, _cmd_metas: CommandMetas) {
             unimplemented!();
         }
@@ -182,7 +690,7 @@ mod tests {
             unimplemented!()
         }
 
-        fn decisions(&self) -> DecisionSet {
+        fn decisions(&self) -> DecisionSet<'_, Bytes> {
             self.0.decisions()
         }
     }
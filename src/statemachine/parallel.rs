@@ -0,0 +1,356 @@
+//! Executor applying decided commands on a pool of worker threads.
+//!
+//! The application names the keys of the state that each command touches
+//! through `ConcurrentState::conflict_keys`. Commands sharing a key are
+//! applied in slot order, while commands without a key in common may be
+//! applied in parallel. Commands are handed to the workers in slot order and
+//! a worker waits for the earlier commands sharing a key with its own, so
+//! the commands waited on are always held by other workers.
+//!
+//! The replica flushes the executor before completing proposals or
+//! answering queries, so responses reflect the commands applied so far. A
+//! panic while applying a command is raised again by the flush.
+use super::ReplicatedState;
+use crate::{Slot, Value};
+use bytes::Bytes;
+use std::{
+    any::Any,
+    collections::HashMap,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+/// A state machine that applies commands concurrently
+pub trait ConcurrentState<V = Bytes>: Send + Sync + 'static {
+    /// Response of the state machine to a command
+    type Response: Send + 'static;
+
+    /// Keys of the state read or written by a command, or `None` when the
+    /// command conflicts with every other command. Commands without keys
+    /// conflict with none.
+    fn conflict_keys(&self, command: &V) -> Option<Vec<Bytes>>;
+
+    /// Apply a value to the state machine. Values sharing a key are applied
+    /// in increasing slot order.
+    fn execute(&self, slot: Slot, command: V) -> Self::Response;
+
+    /// Answers a query, expressed as a command, without changing the state
+    fn query(&self, _query: &V) -> Option<Self::Response> {
+        None
+    }
+}
+
+/// Signals that a command has been applied
+#[derive(Default)]
+struct Completion {
+    done: Mutex<bool>,
+    applied: Condvar,
+}
+
+impl Completion {
+    fn is_done(&self) -> bool {
+        *self.done.lock().unwrap()
+    }
+
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.applied.wait(done).unwrap();
+        }
+    }
+}
+
+/// Completes the command when dropped, even if applying it panicked
+struct Done(Arc<Completion>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        *self.0.done.lock().unwrap() = true;
+        self.0.applied.notify_all();
+    }
+}
+
+/// Response to a command, known once the command is applied
+pub struct Applied<T> {
+    completion: Arc<Completion>,
+    response: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> Applied<T> {
+    fn ready(response: T) -> Applied<T> {
+        let completion = Completion { done: Mutex::new(true), applied: Condvar::new() };
+        Applied { completion: Arc::new(completion), response: Arc::new(Mutex::new(Some(response))) }
+    }
+
+    /// Blocks until the command is applied, returning its response. The
+    /// replica flushes the executor first, so responses it hands out are
+    /// returned without blocking.
+    pub fn wait(self) -> T {
+        self.completion.wait();
+        let response = self.response.lock().unwrap().take();
+        response.expect("command panicked while being applied")
+    }
+}
+
+/// Panic raised by a worker, held until the executor is flushed
+type Panic = Arc<Mutex<Option<Box<dyn Any + Send>>>>;
+
+/// Command handed to the workers
+struct Task<V, T> {
+    slot: Slot,
+    command: V,
+    after: Vec<Arc<Completion>>,
+    response: Arc<Mutex<Option<T>>>,
+    done: Done,
+}
+
+/// Applies decided commands to a `ConcurrentState` on a pool of workers.
+///
+/// The executor is itself a `ReplicatedState`, to be handed to
+/// `Replica::state_machine`.
+pub struct ParallelExecutor<S: ConcurrentState<V>, V = Bytes> {
+    state: Arc<S>,
+    tasks: Option<mpsc::Sender<Task<V, S::Response>>>,
+    workers: Vec<JoinHandle<()>>,
+    /// First panic raised by the workers
+    panic: Panic,
+    /// Last command dispatched for each key
+    keys: HashMap<Bytes, Arc<Completion>>,
+    /// Last command dispatched that conflicts with every command
+    barrier: Option<Arc<Completion>>,
+    /// Commands dispatched since the barrier
+    in_flight: Vec<Arc<Completion>>,
+    value: PhantomData<fn(V)>,
+}
+
+impl<V: Value + Send + 'static, S: ConcurrentState<V>> ParallelExecutor<S, V> {
+    /// Creates an executor applying commands on a number of worker threads
+    pub fn new(state: S, workers: usize) -> ParallelExecutor<S, V> {
+        let state = Arc::new(state);
+        let (sender, receiver) = mpsc::channel::<Task<V, S::Response>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let panic = Panic::default();
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let (state, receiver) = (state.clone(), receiver.clone());
+                let panic = panic.clone();
+                thread::spawn(move || loop {
+                    // tasks are taken in slot order, one worker at a time
+                    let task = match receiver.lock().unwrap().recv() {
+                        Ok(task) => task,
+                        Err(_) => return,
+                    };
+                    let Task { slot, command, after, response, done } = task;
+                    for completion in after {
+                        completion.wait();
+                    }
+                    // the worker stays in the pool, and the flush raises the panic
+                    match panic::catch_unwind(AssertUnwindSafe(|| state.execute(slot, command))) {
+                        Ok(res) => *response.lock().unwrap() = Some(res),
+                        Err(e) => {
+                            panic.lock().unwrap().get_or_insert(e);
+                        }
+                    }
+                    drop(done);
+                })
+            })
+            .collect();
+
+        ParallelExecutor {
+            state,
+            tasks: Some(sender),
+            workers,
+            panic,
+            keys: HashMap::new(),
+            barrier: None,
+            in_flight: Vec::new(),
+            value: PhantomData,
+        }
+    }
+
+    /// State machine applying the commands. Commands may still be applied
+    /// concurrently unless the executor was waited on.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Blocks until every dispatched command has been applied, raising
+    /// again the panic of a worker that failed to apply a command
+    pub fn wait(&self) {
+        for completion in self.in_flight.iter().chain(&self.barrier) {
+            completion.wait();
+        }
+        let panic = self.panic.lock().unwrap().take();
+        if let Some(e) = panic {
+            panic::resume_unwind(e);
+        }
+    }
+
+    fn dispatch(&mut self, slot: Slot, command: V) -> Applied<S::Response> {
+        self.keys.retain(|_, completion| !completion.is_done());
+        self.in_flight.retain(|completion| !completion.is_done());
+
+        let completion = Arc::new(Completion::default());
+        let mut after = self.barrier.iter().cloned().collect::<Vec<_>>();
+        match self.state.conflict_keys(&command) {
+            Some(keys) => {
+                for key in keys {
+                    if let Some(prev) = self.keys.insert(key, completion.clone()) {
+                        after.push(prev);
+                    }
+                }
+                self.in_flight.push(completion.clone());
+            }
+            None => {
+                after.append(&mut self.in_flight);
+                self.keys.clear();
+                self.barrier = Some(completion.clone());
+            }
+        }
+
+        let response = Arc::new(Mutex::new(None));
+        let applied = Applied { completion: completion.clone(), response: response.clone() };
+        let task = Task { slot, command, after, response, done: Done(completion) };
+        if let Some(ref tasks) = self.tasks {
+            // workers only stop once the sender is dropped
+            tasks.send(task).ok();
+        }
+        applied
+    }
+}
+
+impl<V, S> ReplicatedState<V> for ParallelExecutor<S, V>
+where
+    V: Value + Send + 'static,
+    S: ConcurrentState<V>,
+{
+    /// Commands are applied after they are handed to the workers, so the
+    /// response is known once the executor is flushed
+    type Response = Applied<S::Response>;
+
+    fn execute(&mut self, slot: Slot, command: V) -> Applied<S::Response> {
+        self.dispatch(slot, command)
+    }
+
+    fn query(&self, query: &V) -> Option<Applied<S::Response>> {
+        self.state.query(query).map(Applied::ready)
+    }
+
+    fn flush(&mut self) {
+        self.wait();
+    }
+}
+
+impl<S: ConcurrentState<V>, V> Drop for ParallelExecutor<S, V> {
+    fn drop(&mut self) {
+        // workers apply the remaining commands before seeing the channel close
+        self.tasks.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+        if self.panic.lock().unwrap().is_some() {
+            error!("Executor worker panicked while applying a command");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread::sleep, time::Duration};
+
+    #[derive(Default)]
+    struct Log {
+        applied: Mutex<Vec<(Slot, Bytes)>>,
+        running: Mutex<(usize, usize)>,
+    }
+
+    impl ConcurrentState for Log {
+        /// Number of commands applied so far
+        type Response = usize;
+
+        fn conflict_keys(&self, command: &Bytes) -> Option<Vec<Bytes>> {
+            match &command[..] {
+                b"*" => None,
+                _ => Some(vec![command.slice(..1)]),
+            }
+        }
+
+        fn execute(&self, slot: Slot, command: Bytes) -> usize {
+            assert!(command != "!", "command refused");
+            {
+                let mut running = self.running.lock().unwrap();
+                running.0 += 1;
+                running.1 = running.0.max(running.1);
+            }
+            sleep(Duration::from_millis(2));
+            self.running.lock().unwrap().0 -= 1;
+            let mut applied = self.applied.lock().unwrap();
+            applied.push((slot, command));
+            applied.len()
+        }
+
+        fn query(&self, _: &Bytes) -> Option<usize> {
+            Some(self.applied.lock().unwrap().len())
+        }
+    }
+
+    #[test]
+    fn parallel_executor_orders_conflicts() {
+        let mut executor = ParallelExecutor::new(Log::default(), 4);
+        let commands = ["a1", "b1", "c1", "a2", "d1", "b2", "*", "c2", "a3", "b3", "a4"];
+        for (slot, command) in commands.iter().enumerate() {
+            executor.execute(slot as Slot, Bytes::from(*command));
+        }
+        executor.wait();
+
+        let applied = executor.state().applied.lock().unwrap().clone();
+        assert_eq!(commands.len(), applied.len());
+        let position = |cmd: &str| applied.iter().position(|(_, c)| c == cmd).unwrap();
+        for key in &["a", "b", "c"] {
+            let slots = applied
+                .iter()
+                .filter(|(_, c)| c.starts_with(key.as_bytes()))
+                .map(|(slot, _)| *slot)
+                .collect::<Vec<_>>();
+            assert!(slots.windows(2).all(|w| w[0] < w[1]), "{:?}", slots);
+        }
+        // the barrier is applied after every earlier command and before the later ones
+        assert!(applied[..position("*")].iter().all(|(slot, _)| *slot < 6));
+        assert!(applied[position("*") + 1..].iter().all(|(slot, _)| *slot > 6));
+
+        // commands of different keys ran concurrently
+        assert!(executor.state().running.lock().unwrap().1 > 1);
+    }
+
+    #[test]
+    fn parallel_executor_responds_once_flushed() {
+        let mut executor = ParallelExecutor::new(Log::default(), 4);
+        let applied = ["a1", "b1", "a2"]
+            .iter()
+            .enumerate()
+            .map(|(slot, command)| executor.execute(slot as Slot, Bytes::from(*command)))
+            .collect::<Vec<_>>();
+        executor.flush();
+
+        let mut responses = applied.into_iter().map(Applied::wait).collect::<Vec<_>>();
+        responses.sort_unstable();
+        assert_eq!(vec![1, 2, 3], responses);
+        assert_eq!(Some(3), executor.query(&"a".into()).map(Applied::wait));
+    }
+
+    #[test]
+    fn parallel_executor_raises_worker_panics() {
+        let mut executor = ParallelExecutor::new(Log::default(), 1);
+        executor.execute(0, "!".into());
+        let flushed = panic::catch_unwind(AssertUnwindSafe(|| executor.flush()));
+        assert!(flushed.is_err());
+
+        // the worker keeps applying commands
+        let applied = executor.execute(1, "a1".into());
+        executor.flush();
+        assert_eq!(1, applied.wait());
+    }
+}
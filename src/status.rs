@@ -0,0 +1,77 @@
+//! Point in time snapshot of a replica's state.
+//!
+//! `Node::status` copies the state of the proposer, the acceptors of the
+//! open window and what the node last heard from each peer into a
+//! `NodeStatus`, which can be serialized for status endpoints and
+//! debugging tools.
+use crate::{metrics::CommandKind, Ballot, NodeId, Slot};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Snapshot of a node
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    /// Identifier of the node
+    pub node: NodeId,
+    /// State of the node's proposer
+    pub proposer: ProposerStatus,
+    /// Highest ballot observed from any node
+    pub highest_ballot: Option<Ballot>,
+    /// Node holding the highest observed ballot, which may be the current
+    /// node. The node is not necessarily done with Phase 1.
+    pub leader: Option<NodeId>,
+    /// Slots in the open window. The start of the range is the lowest
+    /// undecided slot.
+    pub open_range: Range<Slot>,
+    /// Highest slot of the contiguous range of decisions
+    pub decided: Option<Slot>,
+    /// State of the acceptor of each slot in the open window
+    pub slots: Vec<SlotStatus>,
+    /// Proposals waiting for an election to complete
+    pub queued_proposals: usize,
+    /// Commands received by the node
+    pub received: u64,
+    /// Last command heard from each peer, ordered by node
+    pub peers: Vec<PeerStatus>,
+}
+
+/// State of the proposer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProposerStatus {
+    /// Not proposing a ballot
+    Follower,
+    /// Waiting for promises for the ballot
+    Candidate { ballot: Ballot, promises: usize },
+    /// Phase 1 reached quorum for the ballot
+    Leader { ballot: Ballot },
+}
+
+/// State of a slot within the open window
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotStatus {
+    pub slot: Slot,
+    pub acceptor: AcceptorStatus,
+}
+
+/// State of the acceptor for a single slot
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AcceptorStatus {
+    /// No value has been accepted
+    AwaitValue { promised: Option<Ballot> },
+    /// A value has been accepted and is waiting for a quorum of ACCEPTED
+    /// messages. Only the leader collects the messages.
+    AwaitQuorum { promised: Ballot, accepted: Ballot, acknowledged: usize, quorum: usize },
+    /// The slot is resolved
+    Resolved { ballot: Ballot },
+}
+
+/// What the node last heard from a peer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub node: NodeId,
+    /// Value of `NodeStatus::received` when the last command from the peer
+    /// was received, or `None` if the peer has not been heard from
+    pub last_heard: Option<u64>,
+    /// Type of the last command received from the peer
+    pub last_command: Option<CommandKind>,
+}
@@ -0,0 +1,576 @@
+//! TCP transport with persistent connections between replicas.
+//!
+//! Each peer gets a dedicated connection task that owns a bounded outbound
+//! queue. Frames are prefixed with a 4 byte big-endian length and commands
+//! are encoded with the versioned `codec`, for any value implementing
+//! `codec::WireValue`. When a
+//! connection is established, both sides exchange a handshake containing
+//! their `NodeId` and the cluster and epoch of their `Configuration`;
+//! connections from other clusters or epochs, or from nodes that are not
+//! peers, are dropped. Lost connections are
+//! re-established with exponential backoff.
+//!
+//! Every command is sealed by the `Authenticator` of the transport and
+//! verified by the `Authenticator` of the listener, both created from the
+//! `Configuration` unless set explicitly. Connections delivering
+//! a frame that fails verification are closed.
+//!
+//! The `NodeMetadata` of each peer is expected to hold its `host:port`
+//! address as UTF-8.
+use crate::{
+    auth::Authenticator,
+    codec::{self, DecodeError, WireValue},
+    commands::{Command, CommandMetas, Transport},
+    metrics::{Metrics, NoMetrics},
+    Configuration, NodeId, NodeMetadata,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    str,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+/// Largest frame accepted from a peer
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Largest handshake accepted, before the peer has identified itself
+const MAX_HANDSHAKE_LEN: u32 = 1024;
+
+/// Time allowed for a peer to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Identity exchanged by both ends of a connection before any commands
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Handshake {
+    node: NodeId,
+    cluster: u64,
+    epoch: u64,
+}
+
+impl Handshake {
+    fn new(config: &Configuration) -> Handshake {
+        Handshake { node: config.current(), cluster: config.cluster(), epoch: config.epoch() }
+    }
+
+    /// Checks that the remote end belongs to the same cluster and epoch
+    fn check(&self, remote: &Handshake) -> io::Result<()> {
+        if remote.cluster != self.cluster {
+            let msg = format!("node {} belongs to cluster {}", remote.node, remote.cluster);
+            return Err(invalid_data(msg));
+        }
+        if remote.epoch != self.epoch {
+            let msg = format!("node {} is at epoch {}", remote.node, remote.epoch);
+            return Err(invalid_data(msg));
+        }
+        Ok(())
+    }
+}
+
+/// Transport that sends commands to peers over persistent TCP connections.
+///
+/// Connections are opened lazily on the first message to a peer, so the
+/// transport must be used from within a tokio runtime.
+pub struct TcpTransport {
+    handshake: Handshake,
+    auth: Authenticator,
+    queue_size: usize,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    peers: HashMap<NodeId, mpsc::Sender<Bytes>>,
+}
+
+impl TcpTransport {
+    /// Creates a transport for the current node of the configuration
+    pub fn new(config: &Configuration) -> TcpTransport {
+        TcpTransport {
+            handshake: Handshake::new(config),
+            auth: config.authenticator(),
+            queue_size: 1024,
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Sets the number of frames buffered for each peer. Commands sent while
+    /// the queue is full are dropped.
+    pub fn queue_size(mut self, queue_size: usize) -> TcpTransport {
+        assert!(queue_size > 0);
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the authenticator sealing commands in place of the one created
+    /// from the configuration
+    pub fn authenticator(mut self, auth: Authenticator) -> TcpTransport {
+        self.auth = auth;
+        self
+    }
+
+    /// Sets the bounds of the exponential backoff between reconnections
+    pub fn backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> TcpTransport {
+        assert!(min_backoff <= max_backoff);
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    fn peer_queue(&mut self, node: NodeId, meta: &NodeMetadata) -> Option<&mpsc::Sender<Bytes>> {
+        let closed = self.peers.get(&node).map(|queue| queue.is_closed()).unwrap_or(true);
+        if closed {
+            let addr = match str::from_utf8(&meta.0) {
+                Ok(addr) => addr.to_string(),
+                Err(_) => {
+                    error!("Metadata for node {} is not a valid address", node);
+                    return None;
+                }
+            };
+
+            let (sender, receiver) = mpsc::channel(self.queue_size);
+            let peer = Peer {
+                node,
+                addr,
+                handshake: self.handshake.clone(),
+                min_backoff: self.min_backoff,
+                max_backoff: self.max_backoff,
+            };
+            tokio::spawn(peer.run(receiver));
+            self.peers.insert(node, sender);
+        }
+        self.peers.get(&node)
+    }
+}
+
+impl<V: WireValue> Transport<V> for TcpTransport {
+    fn send(
+        &mut self,
+        node: NodeId,
+        meta: &NodeMetadata,
+        cmd: Command<V>,
+        cmd_metas: CommandMetas,
+    ) {
+        let frame = codec::encode_sealed(&cmd, &cmd_metas, &self.auth);
+        if let Some(queue) = self.peer_queue(node, meta) {
+            if queue.try_send(frame).is_err() {
+                warn!("Outbound queue for node {} is full, dropping command", node);
+            }
+        }
+    }
+}
+
+/// Connection task for a single peer
+struct Peer {
+    node: NodeId,
+    addr: String,
+    handshake: Handshake,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Peer {
+    async fn run(self, mut queue: mpsc::Receiver<Bytes>) {
+        let mut backoff = self.min_backoff;
+        loop {
+            match self.connect().await {
+                Ok(mut stream) => {
+                    debug!("Connected to node {} at {}", self.node, self.addr);
+                    backoff = self.min_backoff;
+                    loop {
+                        let frame = match queue.recv().await {
+                            Some(frame) => frame,
+                            // transport has been dropped
+                            None => return,
+                        };
+                        if let Err(e) = write_frame(&mut stream, &frame).await {
+                            warn!("Lost connection to node {}: {}", self.node, e);
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    debug!("Unable to connect to node {} at {}: {}", self.node, self.addr, e);
+                }
+            }
+
+            sleep(backoff).await;
+            backoff = min(backoff * 2, self.max_backoff);
+        }
+    }
+
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+
+        let remote = timeout(HANDSHAKE_TIMEOUT, async {
+            write_handshake(&mut stream, &self.handshake).await?;
+            read_handshake(&mut stream).await
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+
+        self.handshake.check(&remote)?;
+        if remote.node != self.node {
+            return Err(invalid_data(format!(
+                "expected node {}, found {}",
+                self.node, remote.node
+            )));
+        }
+        Ok(stream)
+    }
+}
+
+/// Accepts connections from peers and forwards their commands to a channel.
+pub struct Listener {
+    listener: TcpListener,
+    handshake: Handshake,
+    peers: Arc<HashSet<NodeId>>,
+    auth: Authenticator,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Listener {
+    /// Binds a listener for the current node of the configuration. Only the
+    /// peers of the configuration may connect.
+    pub async fn bind<A: ToSocketAddrs>(addr: A, config: &Configuration) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Listener {
+            listener,
+            handshake: Handshake::new(config),
+            peers: Arc::new(config.peer_node_ids().collect()),
+            auth: config.authenticator(),
+            metrics: Arc::new(NoMetrics),
+        })
+    }
+
+    /// Sets the authenticator verifying commands in place of the one created
+    /// from the configuration
+    pub fn authenticator(mut self, auth: Authenticator) -> Listener {
+        self.auth = auth;
+        self
+    }
+
+    /// Reports frames that fail verification to the metrics
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Listener {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the receiving end of the channel is dropped
+    pub async fn run<V>(self, commands: mpsc::Sender<(Command<V>, CommandMetas)>)
+    where
+        V: WireValue + Send + 'static,
+    {
+        loop {
+            let stream = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Error accepting connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = commands.closed() => return,
+            };
+
+            let conn = Connection {
+                handshake: self.handshake.clone(),
+                peers: self.peers.clone(),
+                auth: self.auth.clone(),
+                metrics: self.metrics.clone(),
+            };
+            let commands = commands.clone();
+            tokio::spawn(async move {
+                if let Err(e) = conn.serve(stream, commands).await {
+                    debug!("Closing inbound connection: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// State shared by the inbound connections of a listener
+struct Connection {
+    handshake: Handshake,
+    peers: Arc<HashSet<NodeId>>,
+    auth: Authenticator,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Connection {
+    /// Reads commands from an inbound connection
+    async fn serve<V: WireValue>(
+        self,
+        mut stream: TcpStream,
+        commands: mpsc::Sender<(Command<V>, CommandMetas)>,
+    ) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        let remote = timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+        self.handshake.check(&remote)?;
+        if !self.peers.contains(&remote.node) {
+            return Err(invalid_data(format!("node {} is not a peer", remote.node)));
+        }
+        write_handshake(&mut stream, &self.handshake).await?;
+        debug!("Accepted connection from node {}", remote.node);
+
+        loop {
+            let frame = read_frame(&mut stream, MAX_FRAME_LEN).await?;
+            let (cmd, cmd_metas, seal) = match codec::decode_sealed(frame) {
+                Ok(decoded) => decoded,
+                // sent by a newer version of the protocol during an upgrade
+                Err(DecodeError::UnknownMessage(kind)) => {
+                    debug!("Dropping message of unknown type {} from node {}", kind, remote.node);
+                    continue;
+                }
+                Err(e) => return Err(invalid_data(e)),
+            };
+            if let Err(e) = self.auth.verify(&seal) {
+                warn!("Refusing command from node {}: {}", remote.node, e);
+                self.metrics.message_refused(&e);
+                return Err(invalid_data(e));
+            }
+            if commands.send((cmd, cmd_metas)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn write_handshake<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    handshake: &Handshake,
+) -> io::Result<()> {
+    let frame = bincode::serialize(handshake).map_err(invalid_data)?;
+    write_frame(w, &frame).await
+}
+
+async fn read_handshake<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Handshake> {
+    let frame = read_frame(r, MAX_HANDSHAKE_LEN).await?;
+    bincode::deserialize(&frame).map_err(invalid_data)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, frame: &[u8]) -> io::Result<()> {
+    if frame.len() > MAX_FRAME_LEN as usize {
+        return Err(invalid_data("frame exceeds maximum length"));
+    }
+    w.write_u32(frame.len() as u32).await?;
+    w.write_all(frame).await?;
+    w.flush().await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R, max_len: u32) -> io::Result<Bytes> {
+    let len = r.read_u32().await?;
+    if len > max_len {
+        return Err(invalid_data("frame exceeds maximum length"));
+    }
+    let mut frame = vec![0u8; len as usize];
+    r.read_exact(&mut frame).await?;
+    Ok(frame.into())
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{metrics::Counters, Ballot};
+
+    #[tokio::test]
+    async fn tcp_send_receive() {
+        let listener = Listener::bind("127.0.0.1:0", &config(1)).await.unwrap();
+        let meta = NodeMetadata(listener.local_addr().unwrap().to_string().into());
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(listener.run(sender));
+
+        let mut transport = TcpTransport::new(&config(0));
+        transport.send(1, &meta, prepare(0), metas("a"));
+        transport.send(1, &meta, Command::Proposal { payload: Bytes::from("foo") }, metas("b"));
+
+        let (cmd, cmd_metas) = recv(&mut receiver).await;
+        assert_eq!(Command::Prepare { payload: (Ballot(1, 0), 0) }, cmd);
+        assert_eq!("a", cmd_metas.baggage);
+        let (cmd, cmd_metas) = recv(&mut receiver).await;
+        assert_eq!(Command::Proposal { payload: "foo".into() }, cmd);
+        assert_eq!("b", cmd_metas.baggage);
+    }
+
+    #[tokio::test]
+    async fn tcp_reconnects() {
+        // reserve an address, then free it so the first connection fails
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let meta = NodeMetadata(addr.to_string().into());
+
+        let mut transport = TcpTransport::new(&config(0))
+            .backoff(Duration::from_millis(5), Duration::from_millis(20));
+        transport.send(1, &meta, prepare(0), metas(""));
+        sleep(Duration::from_millis(30)).await;
+
+        let listener = Listener::bind(addr, &config(1)).await.unwrap();
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(listener.run(sender));
+
+        let (cmd, _) = recv(&mut receiver).await;
+        assert_eq!(Command::Prepare { payload: (Ballot(1, 0), 0) }, cmd);
+    }
+
+    #[tokio::test]
+    async fn tcp_rejects_other_clusters() {
+        let listener = Listener::bind("127.0.0.1:0", &config(1).with_cluster(7, 2)).await.unwrap();
+        let meta = NodeMetadata(listener.local_addr().unwrap().to_string().into());
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(listener.run(sender));
+
+        for (cluster, epoch) in [(8, 2), (7, 1)].iter() {
+            let mut transport = TcpTransport::new(&config(0).with_cluster(*cluster, *epoch));
+            transport.send(1, &meta, prepare(0), metas(""));
+            assert!(timeout(Duration::from_millis(100), receiver.recv()).await.is_err());
+        }
+
+        let mut transport = TcpTransport::new(&config(0).with_cluster(7, 2));
+        transport.send(1, &meta, prepare(0), metas(""));
+        let (_, cmd_metas) = recv(&mut receiver).await;
+        assert_eq!((7, 2), (cmd_metas.cluster, cmd_metas.epoch));
+    }
+
+    #[tokio::test]
+    async fn tcp_rejects_unknown_peers() {
+        let listener = Listener::bind("127.0.0.1:0", &config(1)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(listener.run::<Bytes>(sender));
+
+        let mut transport = TcpTransport::new(&config(5));
+        let meta = NodeMetadata(addr.to_string().into());
+        transport.send(1, &meta, prepare(5), metas(""));
+        assert!(timeout(Duration::from_millis(100), receiver.recv()).await.is_err());
+
+        // handshakes are read with a small limit, before the peer is known
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_u32(MAX_HANDSHAKE_LEN + 1).await.unwrap();
+        let closed = timeout(Duration::from_secs(5), stream.read_u8()).await.unwrap();
+        assert!(closed.is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_refuses_unauthenticated_commands() {
+        let keyed = config(1).with_cluster(7, 2).with_key("key");
+        let metrics = Arc::new(Counters::default());
+        let listener =
+            Listener::bind("127.0.0.1:0", &keyed).await.unwrap().with_metrics(metrics.clone());
+        let meta = NodeMetadata(listener.local_addr().unwrap().to_string().into());
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(listener.run(sender));
+
+        let mut transport = TcpTransport::new(&config(0).with_cluster(7, 2).with_key("other"));
+        transport.send(1, &meta, prepare(0), metas(""));
+        assert!(timeout(Duration::from_millis(100), receiver.recv()).await.is_err());
+        assert_eq!(1, metrics.snapshot().refused);
+
+        let mut transport = TcpTransport::new(&config(0).with_cluster(7, 2).with_key("key"));
+        transport.send(1, &meta, prepare(0), metas(""));
+        let (cmd, _) = recv(&mut receiver).await;
+        assert_eq!(Command::Prepare { payload: (Ballot(1, 0), 0) }, cmd);
+    }
+
+    #[tokio::test]
+    async fn tcp_skips_unknown_messages() {
+        let listener = Listener::bind("127.0.0.1:0", &config(1)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(listener.run(sender));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = Handshake::new(&config(0));
+        write_handshake(&mut stream, &handshake).await.unwrap();
+        read_handshake(&mut stream).await.unwrap();
+
+        // message type added by a later version of the protocol
+        write_frame(&mut stream, &[codec::PROTOCOL_VERSION + 1, 200, 8, 1]).await.unwrap();
+        let cmd = Command::Prepare { payload: (Ballot(1, 0), 0) };
+        write_frame(&mut stream, &codec::encode(&cmd, &metas(""))).await.unwrap();
+
+        let (received, _) = recv(&mut receiver).await;
+        assert_eq!(cmd, received);
+    }
+
+    #[cfg(feature = "driver")]
+    #[tokio::test]
+    async fn tcp_drives_replicas() {
+        use crate::{driver::Driver, Node, Proposed};
+
+        let mut listeners = Vec::new();
+        for node in 0..3 {
+            listeners.push(Listener::bind("127.0.0.1:0", &config(node)).await.unwrap());
+        }
+        let addrs = listeners
+            .iter()
+            .map(|listener| NodeMetadata(listener.local_addr().unwrap().to_string().into()))
+            .collect::<Vec<_>>();
+
+        let mut handles = Vec::new();
+        let mut decisions = Vec::new();
+        for (node, listener) in (0..).zip(listeners) {
+            let peers = (0..3).filter(|n| *n != node).map(|n| (n, addrs[n as usize].clone()));
+            let config = Configuration::new(node, peers);
+            let (sender, receiver) = mpsc::channel::<(Command<Proposed<Bytes>>, _)>(64);
+            tokio::spawn(listener.run(sender));
+            let replica = Node::new(TcpTransport::new(&config), config);
+            let (driver, handle, decision_stream) = Driver::new(replica, receiver);
+            tokio::spawn(driver.tick_interval(Duration::from_millis(10)).run());
+            handles.push(handle);
+            decisions.push(decision_stream);
+        }
+
+        let slot = handles[1].propose("foo".into(), CommandMetas::default());
+        assert_eq!(0, timeout(Duration::from_secs(5), slot).await.unwrap().unwrap());
+        let slot = handles[2].propose("foo".into(), CommandMetas::default());
+        assert_eq!(1, timeout(Duration::from_secs(5), slot).await.unwrap().unwrap());
+        for decision_stream in decisions.iter_mut() {
+            assert_eq!(Some((0, "foo".into())), decision_stream.next().await);
+            assert_eq!(Some((1, "foo".into())), decision_stream.next().await);
+        }
+    }
+
+    async fn recv(
+        receiver: &mut mpsc::Receiver<(Command, CommandMetas)>,
+    ) -> (Command, CommandMetas) {
+        timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap()
+    }
+
+    fn config(node: NodeId) -> Configuration {
+        let peers = (0..3).filter(|n| *n != node).map(|n| (n, NodeMetadata::default()));
+        Configuration::new(node, peers)
+    }
+
+    fn prepare(node: NodeId) -> Command {
+        Command::Prepare { payload: (Ballot(1, node), 0) }
+    }
+
+    fn metas(val: &'static str) -> CommandMetas {
+        CommandMetas::default().with_baggage(val)
+    }
+}
@@ -1,43 +1,66 @@
 use super::Slot;
-use crate::{acceptor::Acceptor, Ballot};
+use crate::{
+    acceptor::Acceptor,
+    metrics::{Metrics, NoMetrics},
+    Ballot, Value,
+};
 use bytes::Bytes;
 use std::{
     cmp::{max, min},
     iter::ExactSizeIterator,
     ops::{Bound, Range, RangeBounds},
+    sync::Arc,
 };
 
-struct ResolvedSlot(Ballot, Bytes);
+/// Maximum number of slots held by the open window. Slots beyond the
+/// window are refused rather than growing it without bound.
+const MAX_OPEN_SLOTS: Slot = 1 << 16;
+
+struct ResolvedSlot<V>(Ballot, V);
 
 /// Tracking for open and decided slots for a paxos replica
-pub struct SlotWindow {
+pub struct SlotWindow<V = Bytes> {
     /// Slots that are indexed >= open_min_slot.
     ///
     /// Some slots may be decided, but the open_min_slot is quaranteed
     /// to be still undecided.
-    open: Vec<Acceptor>,
+    open: Vec<Acceptor<V>>,
     open_min_slot: Slot,
     max_promised: Option<Ballot>,
 
     /// Slots that have been decided.
-    decided: Vec<ResolvedSlot>,
+    decided: Vec<ResolvedSlot<V>>,
 
     /// Size of the phase 2 quorum
     quorum: usize,
+
+    /// Receiver of decision and window size events
+    metrics: Arc<dyn Metrics>,
 }
 
-impl SlotWindow {
+impl<V: Value> SlotWindow<V> {
     /// New tracker for slots
-    pub fn new(quorum: usize) -> SlotWindow {
-        let mut open = Vec::new();
+    pub fn new(quorum: usize) -> SlotWindow<V> {
         // add the first slot
-        open.push(Acceptor::new(None, quorum));
+        let open = vec![Acceptor::new(None, quorum)];
+
+        SlotWindow {
+            open,
+            open_min_slot: 0,
+            max_promised: None,
+            decided: Vec::new(),
+            quorum,
+            metrics: Arc::new(NoMetrics),
+        }
+    }
 
-        SlotWindow { open, open_min_slot: 0, max_promised: None, decided: Vec::new(), quorum }
+    /// Reports decisions and changes to the window to the metrics
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
     }
 
     /// Mutable reference to a slot
-    pub fn slot_mut(&mut self, slot: Slot) -> SlotMutRef {
+    pub fn slot_mut(&mut self, slot: Slot) -> SlotMutRef<'_, V> {
         assert!(self.open_min_slot as usize >= self.decided.len());
         let min_slot = self.open_min_slot - self.decided.len() as Slot;
 
@@ -70,7 +93,7 @@ impl SlotWindow {
     }
 
     /// Opens the next slot
-    pub fn next_slot(&mut self) -> OpenSlotMutRef {
+    pub fn next_slot(&mut self) -> OpenSlotMutRef<'_, V> {
         if self.open.last().is_some() && self.open.last().unwrap().highest_value().is_none() {
             return OpenSlotMutRef { i: self.open.len() - 1, window: self };
         }
@@ -88,11 +111,34 @@ impl SlotWindow {
         Range { start: self.open_min_slot, end: self.open_min_slot + self.open.len() as Slot }
     }
 
+    /// Slots that can be reserved without exceeding the maximum size of the
+    /// open window. Slots before the range have been decided.
+    pub fn capacity_range(&self) -> Range<Slot> {
+        Range { start: self.open_min_slot, end: self.open_min_slot + MAX_OPEN_SLOTS }
+    }
+
+    /// Acceptors of the open window along with their slots
+    pub fn open_slots(&self) -> impl Iterator<Item = (Slot, &Acceptor<V>)> {
+        let start = self.open_min_slot;
+        self.open.iter().enumerate().map(move |(i, acceptor)| (start + i as Slot, acceptor))
+    }
+
+    /// Highest ballot promised by any acceptor within the window
+    pub fn max_promised(&self) -> Option<Ballot> {
+        self.max_promised
+    }
+
     /// Iterator for resolved slots and the decided value
-    pub fn decisions(&self) -> DecisionSet {
+    pub fn decisions(&self) -> DecisionSet<'_, V> {
         DecisionSet { window: self }
     }
 
+    /// Drops the decided slots as if they had been executed
+    #[cfg(test)]
+    pub(crate) fn truncate_decisions(&mut self) {
+        self.decided.clear();
+    }
+
     fn fill_decisions(&mut self) {
         // find the range of resolved slots
         let last_resolved = self
@@ -105,6 +151,7 @@ impl SlotWindow {
 
         // move resolved slots into the decided vector
         if let Some(i) = last_resolved {
+            let first_decided = self.open_min_slot;
             self.open_min_slot += (i as u64) + 1;
             let resolutions = self.open.drain(0..=i).map(|open_slot| {
                 let (bal, val) = open_slot.resolution().unwrap();
@@ -112,7 +159,9 @@ impl SlotWindow {
             });
             self.decided.extend(resolutions);
             self.fill_open_slots(self.open_min_slot);
+            self.metrics.slots_decided(first_decided..self.open_min_slot);
         }
+        self.metrics.window_size(self.open.len());
     }
 
     fn fill_open_slots(&mut self, max_slot: Slot) {
@@ -130,22 +179,22 @@ impl SlotWindow {
 }
 
 /// Mutable reference to an open slot
-pub struct OpenSlotMutRef<'a> {
+pub struct OpenSlotMutRef<'a, V: Value> {
     i: usize,
-    window: &'a mut SlotWindow,
+    window: &'a mut SlotWindow<V>,
 }
 
-impl<'a> OpenSlotMutRef<'a> {
+impl<'a, V: Value> OpenSlotMutRef<'a, V> {
     pub fn slot(&self) -> Slot {
         self.i as Slot + self.window.open_min_slot
     }
 
-    pub fn acceptor(&mut self) -> &mut Acceptor {
+    pub fn acceptor(&mut self) -> &mut Acceptor<V> {
         &mut self.window.open[self.i]
     }
 }
 
-impl<'a> Drop for OpenSlotMutRef<'a> {
+impl<'a, V: Value> Drop for OpenSlotMutRef<'a, V> {
     fn drop(&mut self) {
         let acceptor_promised = self.acceptor().promised();
         self.window.max_promised = max(self.window.max_promised, acceptor_promised);
@@ -154,27 +203,27 @@ impl<'a> Drop for OpenSlotMutRef<'a> {
 }
 
 /// Mutable reference to a slot
-pub enum SlotMutRef<'a> {
+pub enum SlotMutRef<'a, V: Value> {
     /// Slot is unresolved
-    Open(OpenSlotMutRef<'a>),
+    Open(OpenSlotMutRef<'a, V>),
     /// Slot has not been reserved
-    Empty(EmptySlotRef<'a>),
+    Empty(EmptySlotRef<'a, V>),
     /// Slot is resolved with a value
-    Resolved(Ballot, Bytes),
+    Resolved(Ballot, V),
     /// Slot is resolved and the command value has already
     /// been executed.
     ResolutionTruncated,
 }
 
 /// Reference to an empty slot that can be filled on demand
-pub struct EmptySlotRef<'a> {
+pub struct EmptySlotRef<'a, V> {
     slot: Slot,
-    window: &'a mut SlotWindow,
+    window: &'a mut SlotWindow<V>,
 }
 
-impl<'a> EmptySlotRef<'a> {
+impl<'a, V: Value> EmptySlotRef<'a, V> {
     /// Filts the slot as open
-    pub fn fill(self) -> OpenSlotMutRef<'a> {
+    pub fn fill(self) -> OpenSlotMutRef<'a, V> {
         self.window.fill_open_slots(self.slot);
         let i = self.slot - self.window.open_min_slot;
         OpenSlotMutRef { i: i as usize, window: self.window }
@@ -182,16 +231,16 @@ impl<'a> EmptySlotRef<'a> {
 }
 
 /// Set of slot decisions that are replicated and stable.
-pub struct DecisionSet<'a> {
-    window: &'a SlotWindow,
+pub struct DecisionSet<'a, V = Bytes> {
+    window: &'a SlotWindow<V>,
 }
 
-impl<'a> DecisionSet<'a> {
-    pub fn iter(&self) -> DecisionIterator {
-        DecisionIterator { window: &self.window, i: 0, end: self.window.decided.len() }
+impl<'a, V: Value> DecisionSet<'a, V> {
+    pub fn iter(&self) -> DecisionIterator<'_, V> {
+        DecisionIterator { window: self.window, i: 0, end: self.window.decided.len() }
     }
 
-    pub fn range<R>(&self, range: R) -> DecisionIterator
+    pub fn range<R>(&self, range: R) -> DecisionIterator<'_, V>
     where
         R: RangeBounds<Slot>,
     {
@@ -208,7 +257,7 @@ impl<'a> DecisionSet<'a> {
             Bound::Unbounded => len,
         };
 
-        DecisionIterator { window: &self.window, i: start, end }
+        DecisionIterator { window: self.window, i: start, end }
     }
 
     pub fn len(&self) -> usize {
@@ -221,16 +270,16 @@ impl<'a> DecisionSet<'a> {
 }
 
 /// Iterator over the window's decisions
-pub struct DecisionIterator<'a> {
-    window: &'a SlotWindow,
+pub struct DecisionIterator<'a, V> {
+    window: &'a SlotWindow<V>,
     i: usize,
     end: usize,
 }
 
-impl<'a> Iterator for DecisionIterator<'a> {
-    type Item = (Slot, Bytes);
+impl<'a, V: Value> Iterator for DecisionIterator<'a, V> {
+    type Item = (Slot, V);
 
-    fn next(&mut self) -> Option<(Slot, Bytes)> {
+    fn next(&mut self) -> Option<(Slot, V)> {
         if self.i >= self.end {
             return None;
         }
@@ -242,15 +291,15 @@ impl<'a> Iterator for DecisionIterator<'a> {
     }
 }
 
-impl<'a> ExactSizeIterator for DecisionIterator<'a> {
+impl<'a, V: Value> ExactSizeIterator for DecisionIterator<'a, V> {
     fn len(&self) -> usize {
         self.end - self.i
     }
 }
 
-impl<'a> SlotMutRef<'a> {
+impl<'a, V: Value> SlotMutRef<'a, V> {
     #[cfg(test)]
-    pub fn unwrap_open(self) -> OpenSlotMutRef<'a> {
+    pub fn unwrap_open(self) -> OpenSlotMutRef<'a, V> {
         match self {
             SlotMutRef::Open(open_slot_ref) => open_slot_ref,
             _ => panic!("Slot was resolved when open expected"),
@@ -258,7 +307,7 @@ impl<'a> SlotMutRef<'a> {
     }
 
     #[cfg(test)]
-    pub fn unwrap_empty(self) -> EmptySlotRef<'a> {
+    pub fn unwrap_empty(self) -> EmptySlotRef<'a, V> {
         match self {
             SlotMutRef::Empty(empty) => empty,
             _ => panic!("Slot was resolved when empty expected"),
@@ -266,7 +315,7 @@ impl<'a> SlotMutRef<'a> {
     }
 
     #[cfg(test)]
-    pub fn unwrap_resolved(self) -> (Ballot, Bytes) {
+    pub fn unwrap_resolved(self) -> (Ballot, V) {
         match self {
             SlotMutRef::Resolved(bal, value) => (bal, value),
             _ => panic!("Slot was resolved when open expected"),
@@ -280,7 +329,7 @@ mod tests {
 
     #[test]
     fn fill_open_slots() {
-        let mut window = SlotWindow::new(3);
+        let mut window: SlotWindow = SlotWindow::new(3);
         assert_eq!(0, window.open_min_slot);
 
         window.fill_open_slots(5);
@@ -304,11 +353,8 @@ mod tests {
 
     #[test]
     fn windows() {
-        let mut window = SlotWindow::new(3);
-        assert!(match window.slot_mut(0) {
-            SlotMutRef::Open(_) => true,
-            _ => false,
-        });
+        let mut window: SlotWindow = SlotWindow::new(3);
+        assert!(matches!(window.slot_mut(0), SlotMutRef::Open(_)));
 
         {
             window.slot_mut(2).unwrap_empty().fill().acceptor().resolve(Ballot(0, 0), "123".into());
@@ -355,7 +401,7 @@ mod tests {
 
     #[test]
     fn open_one() {
-        let mut window = SlotWindow::new(2);
+        let mut window: SlotWindow = SlotWindow::new(2);
         {
             window.slot_mut(1).unwrap_empty().fill();
         }
@@ -363,7 +409,7 @@ mod tests {
 
         {
             assert!(match window.slot_mut(0) {
-                SlotMutRef::Open(ref mut slot) => !slot.acceptor().highest_value().is_some(),
+                SlotMutRef::Open(ref mut slot) => slot.acceptor().highest_value().is_none(),
                 _ => false,
             });
         }
@@ -371,7 +417,7 @@ mod tests {
 
     #[test]
     fn decisions() {
-        let mut window = SlotWindow::new(2);
+        let mut window: SlotWindow = SlotWindow::new(2);
         {
             window.slot_mut(1).unwrap_empty().fill().acceptor().resolve(Ballot(0, 5), "1".into())
         }
@@ -395,10 +441,7 @@ mod tests {
         }
 
         for i in 0..3 {
-            assert!(match window.slot_mut(i) {
-                SlotMutRef::Resolved(..) => true,
-                _ => false,
-            })
+            assert!(matches!(window.slot_mut(i), SlotMutRef::Resolved(..)))
         }
 
         {
@@ -408,7 +451,7 @@ mod tests {
 
     #[test]
     fn next_slot() {
-        let mut window = SlotWindow::new(2);
+        let mut window: SlotWindow = SlotWindow::new(2);
 
         // first slot is considered next since it is not filled with a value
         {